@@ -0,0 +1,661 @@
+use arrow_array::builder::{BinaryBuilder, BooleanBuilder, Float64Builder, Int64Builder, StringBuilder};
+use arrow_array::cast::AsArray;
+use arrow_array::{
+    Array, ArrayRef, BinaryArray, LargeBinaryArray, LargeStringArray, RecordBatch, RecordBatchReader, StringArray,
+};
+use arrow_cast::display::{ArrayFormatter, FormatOptions};
+use arrow_ipc::reader::{FileReader, StreamReader};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::expr::parse_number;
+use crate::processor::RowBuffer;
+
+/// Default number of rows per Parquet row group
+pub const DEFAULT_ROW_GROUP_SIZE: usize = 1024 * 1024;
+/// Rows per record batch decoded from Parquet input
+const READ_BATCH_SIZE: usize = 64 * 1024;
+
+/// Record batches decoded from a columnar input.
+pub type Batches = Box<dyn RecordBatchReader + Send>;
+
+/// Columnar input formats, recognized by their magic bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnarFormat {
+    Parquet,
+    /// Arrow IPC file (Feather v2), which needs a seekable input
+    ArrowFile,
+    /// Arrow IPC stream, which can be read from a pipe
+    ArrowStream,
+}
+
+impl ColumnarFormat {
+    pub fn from_magic(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"PAR1") {
+            Some(ColumnarFormat::Parquet)
+        } else if data.starts_with(b"ARROW1") {
+            Some(ColumnarFormat::ArrowFile)
+        } else if data.starts_with(&[0xff, 0xff, 0xff, 0xff]) {
+            // Every stream message starts with the continuation marker
+            Some(ColumnarFormat::ArrowStream)
+        } else {
+            None
+        }
+    }
+
+    /// Opens a regular file in this format.
+    pub fn open_file(self, file: File) -> Result<Batches, Error> {
+        Ok(match self {
+            ColumnarFormat::Parquet => Box::new(
+                ParquetRecordBatchReaderBuilder::try_new(file)?
+                    .with_batch_size(READ_BATCH_SIZE)
+                    .build()?,
+            ),
+            ColumnarFormat::ArrowFile => Box::new(FileReader::try_new_buffered(file, None)?),
+            ColumnarFormat::ArrowStream => Box::new(StreamReader::try_new_buffered(file, None)?),
+        })
+    }
+
+    /// Opens a sequential reader in this format, which only works for
+    /// Arrow IPC streams.
+    pub fn open_stream(self, reader: Box<dyn Read + Send>) -> Result<Batches, Error> {
+        match self {
+            ColumnarFormat::ArrowStream => Ok(Box::new(StreamReader::try_new_buffered(reader, None)?)),
+            ColumnarFormat::Parquet | ColumnarFormat::ArrowFile => Err(Error::Config(format!(
+                "{:?} input needs a regular file, not a pipe or compressed stream",
+                self
+            ))),
+        }
+    }
+}
+
+/// Column names of a columnar input, used as its header.
+pub fn column_names(batches: &Batches) -> Vec<Vec<u8>> {
+    batches
+        .schema()
+        .fields()
+        .iter()
+        .map(|field| field.name().as_bytes().to_vec())
+        .collect()
+}
+
+/// Reads the values of a record batch as text fields, so columnar rows go
+/// through the same selection and filters as parsed CSV records. Nulls are
+/// empty fields.
+pub struct BatchFields<'a> {
+    batch: &'a RecordBatch,
+    columns: Vec<ColumnValues<'a>>,
+    scratch: String,
+}
+
+enum ColumnValues<'a> {
+    Utf8(&'a StringArray),
+    LargeUtf8(&'a LargeStringArray),
+    Binary(&'a BinaryArray),
+    LargeBinary(&'a LargeBinaryArray),
+    /// Any other type, formatted as text
+    Formatted(ArrayFormatter<'a>),
+}
+
+impl<'a> BatchFields<'a> {
+    pub fn new(batch: &'a RecordBatch) -> Result<Self, ArrowError> {
+        let options = FormatOptions::default();
+        let columns = batch
+            .columns()
+            .iter()
+            .map(|array| {
+                Ok(match array.data_type() {
+                    DataType::Utf8 => ColumnValues::Utf8(array.as_string()),
+                    DataType::LargeUtf8 => ColumnValues::LargeUtf8(array.as_string()),
+                    DataType::Binary => ColumnValues::Binary(array.as_binary()),
+                    DataType::LargeBinary => ColumnValues::LargeBinary(array.as_binary()),
+                    _ => ColumnValues::Formatted(ArrayFormatter::try_new(array.as_ref(), &options)?),
+                })
+            })
+            .collect::<Result<_, ArrowError>>()?;
+        Ok(Self {
+            batch,
+            columns,
+            scratch: String::new(),
+        })
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.batch.num_rows()
+    }
+
+    /// Replaces `fields` with the values of row `row`.
+    pub fn read_row(&mut self, row: usize, fields: &mut Vec<Cow<'a, [u8]>>) {
+        fields.clear();
+        for (array, column) in self.batch.columns().iter().zip(&self.columns) {
+            if array.is_null(row) {
+                fields.push(Cow::Borrowed(&[]));
+                continue;
+            }
+            fields.push(match column {
+                ColumnValues::Utf8(values) => Cow::Borrowed(values.value(row).as_bytes()),
+                ColumnValues::LargeUtf8(values) => Cow::Borrowed(values.value(row).as_bytes()),
+                ColumnValues::Binary(values) => Cow::Borrowed(values.value(row)),
+                ColumnValues::LargeBinary(values) => Cow::Borrowed(values.value(row)),
+                ColumnValues::Formatted(formatter) => {
+                    self.scratch.clear();
+                    write!(self.scratch, "{}", formatter.value(row)).ok();
+                    Cow::Owned(self.scratch.as_bytes().to_vec())
+                }
+            });
+        }
+    }
+}
+
+/// Type of an output column in columnar formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    String,
+    Int64,
+    Float64,
+    Boolean,
+    Binary,
+}
+
+impl FromStr for ColumnType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "string" | "utf8" => Ok(ColumnType::String),
+            "int64" | "int" | "integer" => Ok(ColumnType::Int64),
+            "float64" | "float" | "double" => Ok(ColumnType::Float64),
+            "boolean" | "bool" => Ok(ColumnType::Boolean),
+            "binary" | "bytes" => Ok(ColumnType::Binary),
+            _ => Err(format!(
+                "unknown column type '{}' (expected string, int64, float64, boolean or binary)",
+                s
+            )),
+        }
+    }
+}
+
+impl ColumnType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ColumnType::String => "string",
+            ColumnType::Int64 => "int64",
+            ColumnType::Float64 => "float64",
+            ColumnType::Boolean => "boolean",
+            ColumnType::Binary => "binary",
+        }
+    }
+
+    fn data_type(self) -> DataType {
+        match self {
+            ColumnType::String => DataType::Utf8,
+            ColumnType::Int64 => DataType::Int64,
+            ColumnType::Float64 => DataType::Float64,
+            ColumnType::Boolean => DataType::Boolean,
+            ColumnType::Binary => DataType::Binary,
+        }
+    }
+
+    /// Narrowest type that fits both the values seen so far (`current`, or
+    /// `None` if they were all empty) and `value`.
+    pub(crate) fn widen(current: Option<ColumnType>, value: &[u8]) -> ColumnType {
+        use ColumnType::*;
+        match current {
+            None | Some(Boolean) if parse_bool(value).is_some() => Boolean,
+            None | Some(Int64) if parse_int(value).is_some() => Int64,
+            None | Some(Int64) | Some(Float64) if parse_number(value).is_some() => Float64,
+            _ => String,
+        }
+    }
+
+    /// Narrowest type that fits the values of two samples.
+    pub(crate) fn join(a: Option<ColumnType>, b: Option<ColumnType>) -> Option<ColumnType> {
+        use ColumnType::*;
+        match (a, b) {
+            (None, ty) | (ty, None) => ty,
+            (Some(a), Some(b)) if a == b => Some(a),
+            (Some(Int64 | Float64), Some(Int64 | Float64)) => Some(Float64),
+            _ => Some(String),
+        }
+    }
+}
+
+fn parse_bool(value: &[u8]) -> Option<bool> {
+    match value.to_ascii_lowercase().as_slice() {
+        b"true" => Some(true),
+        b"false" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_int(value: &[u8]) -> Option<i64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+fn parse_float(value: &[u8]) -> Option<f64> {
+    parse_number(value)
+}
+
+/// Compression codec for Parquet column chunks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ParquetCompression {
+    None,
+    #[default]
+    Snappy,
+    Zstd,
+}
+
+impl FromStr for ParquetCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" | "uncompressed" => Ok(ParquetCompression::None),
+            "snappy" => Ok(ParquetCompression::Snappy),
+            "zstd" => Ok(ParquetCompression::Zstd),
+            _ => Err(format!(
+                "unknown parquet compression '{}' (expected snappy, zstd or none)",
+                s
+            )),
+        }
+    }
+}
+
+/// Parquet writer settings.
+#[derive(Clone, Debug)]
+pub struct ParquetOptions {
+    pub row_group_size: usize,
+    pub compression: ParquetCompression,
+    /// Explicit column types by output column name; other columns are
+    /// inferred from the first chunk
+    pub schema: Vec<(String, ColumnType)>,
+}
+
+impl Default for ParquetOptions {
+    fn default() -> Self {
+        Self {
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+            compression: ParquetCompression::default(),
+            schema: Vec::new(),
+        }
+    }
+}
+
+impl ParquetOptions {
+    /// Parses a `name:type,...` schema spec.
+    pub fn parse_schema(spec: &str) -> Result<Vec<(String, ColumnType)>, String> {
+        spec.split(',')
+            .map(|entry| {
+                let (name, ty) = entry
+                    .rsplit_once(':')
+                    .ok_or_else(|| format!("invalid schema entry '{}' (expected name:type)", entry))?;
+                Ok((name.trim().to_string(), ty.trim().parse()?))
+            })
+            .collect()
+    }
+
+    pub fn writer_properties(&self, level: Option<u32>) -> Result<WriterProperties, Error> {
+        let compression = match self.compression {
+            ParquetCompression::None => Compression::UNCOMPRESSED,
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd => {
+                let level = match level {
+                    Some(level) => ZstdLevel::try_new(level as i32)?,
+                    None => ZstdLevel::default(),
+                };
+                Compression::ZSTD(level)
+            }
+        };
+        Ok(WriterProperties::builder()
+            .set_max_row_group_size(self.row_group_size.max(1))
+            .set_compression(compression)
+            .build())
+    }
+}
+
+/// The resolved output schema of one run.
+pub struct ColumnSchema {
+    arrow: SchemaRef,
+    types: Vec<ColumnType>,
+    /// Whether each column's type was inferred rather than given
+    inferred: Vec<bool>,
+}
+
+impl ColumnSchema {
+    /// Combines the explicit `overrides` with the types `sampled` from the
+    /// first rows. Columns that were empty in the sample become strings.
+    pub fn new(
+        names: &[Vec<u8>],
+        sampled: Vec<Option<ColumnType>>,
+        overrides: &[(String, ColumnType)],
+    ) -> Result<Self, Error> {
+        let names: Vec<String> = names.iter().map(|n| String::from_utf8_lossy(n).into_owned()).collect();
+        let mut types: Vec<_> = sampled.iter().map(|t| t.unwrap_or(ColumnType::String)).collect();
+        let mut inferred = vec![true; names.len()];
+
+        for (name, ty) in overrides {
+            let index = names
+                .iter()
+                .position(|n| n == name)
+                .ok_or_else(|| Error::Config(format!("--schema names unknown output column '{}'", name)))?;
+            types[index] = *ty;
+            inferred[index] = false;
+        }
+
+        let fields: Vec<Field> = names
+            .iter()
+            .zip(&types)
+            .map(|(name, ty)| Field::new(name.as_str(), ty.data_type(), true))
+            .collect();
+        Ok(Self {
+            arrow: Arc::new(Schema::new(fields)),
+            types,
+            inferred,
+        })
+    }
+
+    pub fn arrow(&self) -> SchemaRef {
+        self.arrow.clone()
+    }
+}
+
+/// Values of the row currently being added, staged until the row is
+/// known to be kept.
+#[derive(Default)]
+pub struct StagedRow {
+    data: Vec<u8>,
+    /// Output column and end offset in `data` of each value
+    values: Vec<(usize, usize)>,
+}
+
+impl StagedRow {
+    pub fn push(&mut self, column: usize, value: &[u8]) {
+        self.data.extend_from_slice(value);
+        self.values.push((column, self.data.len()));
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.values.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        let mut start = 0;
+        self.values.iter().map(move |&(column, end)| {
+            let value = &self.data[start..end];
+            start = end;
+            (column, value)
+        })
+    }
+}
+
+/// Infers column types from the rows of a sample chunk.
+pub struct TypeSampler {
+    types: Vec<Option<ColumnType>>,
+    row: StagedRow,
+}
+
+impl TypeSampler {
+    pub fn new(columns: usize) -> Self {
+        Self {
+            types: vec![None; columns],
+            row: StagedRow::default(),
+        }
+    }
+
+    pub fn finish(self) -> Vec<Option<ColumnType>> {
+        self.types
+    }
+}
+
+impl RowBuffer for TypeSampler {
+    fn start_row(&mut self, _first: bool) {
+        self.row.clear();
+    }
+
+    fn write_value(&mut self, column: usize, _position: usize, value: &[u8]) {
+        self.row.push(column, value);
+    }
+
+    fn end_row(&mut self, _written: usize) {
+        for (column, value) in self.row.iter() {
+            if !value.is_empty() {
+                self.types[column] = Some(ColumnType::widen(self.types[column], value));
+            }
+        }
+    }
+
+    fn discard_row(&mut self) {}
+}
+
+enum ColumnBuilder {
+    String(StringBuilder),
+    Int64(Int64Builder),
+    Float64(Float64Builder),
+    Boolean(BooleanBuilder),
+    Binary(BinaryBuilder),
+}
+
+/// Builds an Arrow record batch from the rows kept in one chunk. Empty
+/// values become nulls, except in string and binary columns. A value that
+/// does not fit its column type fails the batch: the file can not hold it,
+/// and the row is valid input, so it is not dropped as malformed either.
+pub struct BatchBuilder<'a> {
+    schema: &'a ColumnSchema,
+    columns: Vec<ColumnBuilder>,
+    row: StagedRow,
+    /// First value that did not fit its column type
+    error: Option<String>,
+}
+
+impl<'a> BatchBuilder<'a> {
+    pub fn new(schema: &'a ColumnSchema) -> Self {
+        let columns = schema
+            .types
+            .iter()
+            .map(|ty| match ty {
+                ColumnType::String => ColumnBuilder::String(StringBuilder::new()),
+                ColumnType::Int64 => ColumnBuilder::Int64(Int64Builder::new()),
+                ColumnType::Float64 => ColumnBuilder::Float64(Float64Builder::new()),
+                ColumnType::Boolean => ColumnBuilder::Boolean(BooleanBuilder::new()),
+                ColumnType::Binary => ColumnBuilder::Binary(BinaryBuilder::new()),
+            })
+            .collect();
+        Self {
+            schema,
+            columns,
+            row: StagedRow::default(),
+            error: None,
+        }
+    }
+
+    pub fn finish(self) -> Result<RecordBatch, Error> {
+        if let Some(error) = self.error {
+            return Err(Error::InvalidValue(error));
+        }
+
+        let arrays: Vec<ArrayRef> = self
+            .columns
+            .into_iter()
+            .map(|column| -> ArrayRef {
+                match column {
+                    ColumnBuilder::String(mut b) => Arc::new(b.finish()),
+                    ColumnBuilder::Int64(mut b) => Arc::new(b.finish()),
+                    ColumnBuilder::Float64(mut b) => Arc::new(b.finish()),
+                    ColumnBuilder::Boolean(mut b) => Arc::new(b.finish()),
+                    ColumnBuilder::Binary(mut b) => Arc::new(b.finish()),
+                }
+            })
+            .collect();
+        Ok(RecordBatch::try_new(self.schema.arrow(), arrays)?)
+    }
+
+    fn append(&mut self, column: usize, value: &[u8]) -> Result<(), ColumnType> {
+        let ty = self.schema.types[column];
+        match &mut self.columns[column] {
+            ColumnBuilder::String(b) => b.append_value(String::from_utf8_lossy(value)),
+            ColumnBuilder::Binary(b) => b.append_value(value),
+            ColumnBuilder::Int64(b) if value.is_empty() => b.append_null(),
+            ColumnBuilder::Float64(b) if value.is_empty() => b.append_null(),
+            ColumnBuilder::Boolean(b) if value.is_empty() => b.append_null(),
+            ColumnBuilder::Int64(b) => b.append_value(parse_int(value).ok_or(ty)?),
+            ColumnBuilder::Float64(b) => b.append_value(parse_float(value).ok_or(ty)?),
+            ColumnBuilder::Boolean(b) => b.append_value(parse_bool(value).ok_or(ty)?),
+        }
+        Ok(())
+    }
+
+    fn mismatch(&self, column: usize, ty: ColumnType, value: &[u8]) -> String {
+        let name = self.schema.arrow.field(column).name();
+        let mut message = format!(
+            "value '{}' in column '{}' is not a valid {}",
+            String::from_utf8_lossy(value),
+            name,
+            ty.name()
+        );
+        if self.schema.inferred[column] {
+            message.push_str(&format!(
+                " (the type was inferred from the first chunk; set it with --schema '{}:string')",
+                name
+            ));
+        }
+        message
+    }
+}
+
+impl RowBuffer for BatchBuilder<'_> {
+    fn start_row(&mut self, _first: bool) {
+        self.row.clear();
+    }
+
+    fn write_value(&mut self, column: usize, _position: usize, value: &[u8]) {
+        self.row.push(column, value);
+    }
+
+    fn end_row(&mut self, _written: usize) {
+        let row = std::mem::take(&mut self.row);
+        for (column, value) in row.iter() {
+            if let Err(ty) = self.append(column, value) {
+                if self.error.is_none() {
+                    self.error = Some(self.mismatch(column, ty, value));
+                }
+                // Keep the columns the same length
+                self.append(column, b"").ok();
+            }
+        }
+        self.row = row;
+    }
+
+    fn discard_row(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::CsvProcessor;
+    use crate::writer::{Format, OutputFormat};
+    use std::path::{Path, PathBuf};
+
+    /// Integers in the first chunk, then values that are not.
+    const MIXED: &str = "id,score\n1,10\n2,20\n3,30\n4,40\n5,zz\n6,3.5\n7,70\n";
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pulsecsv-columnar-{}-{}", std::process::id(), name))
+    }
+
+    /// Converts `csv` to Parquet at `output` in chunks of 16 bytes.
+    fn convert(csv: &str, output: &Path, schema: &str) -> Result<(), Error> {
+        let input = output.with_extension("csv");
+        std::fs::write(&input, csv)?;
+        let parquet = ParquetOptions {
+            schema: match schema {
+                "" => Vec::new(),
+                spec => ParquetOptions::parse_schema(spec).unwrap(),
+            },
+            ..ParquetOptions::default()
+        };
+        let result = CsvProcessor::builder()
+            .delimiter(b',')
+            .chunk_size(16)
+            .output_format(OutputFormat {
+                format: Format::Parquet,
+                ..OutputFormat::default()
+            })
+            .parquet(parquet)
+            .sink(output)
+            .build()?
+            .process(input.as_path());
+        std::fs::remove_file(&input)?;
+        result.map(drop)
+    }
+
+    /// Reads the values of a Parquet file back as text, row by row.
+    fn read_back(path: &Path) -> Vec<Vec<String>> {
+        let batches = ColumnarFormat::Parquet.open_file(File::open(path).unwrap()).unwrap();
+        let mut rows = Vec::new();
+        for batch in batches {
+            let batch = batch.unwrap();
+            let mut fields = BatchFields::new(&batch).unwrap();
+            let mut row = Vec::new();
+            for i in 0..fields.num_rows() {
+                fields.read_row(i, &mut row);
+                rows.push(row.iter().map(|value| String::from_utf8_lossy(value).into_owned()).collect());
+            }
+        }
+        rows
+    }
+
+    #[test]
+    fn fails_on_values_that_do_not_fit_the_inferred_type() {
+        let output = test_path("inferred.parquet");
+        let error = convert(MIXED, &output, "").unwrap_err();
+        assert!(matches!(error, Error::InvalidValue(_)), "{}", error);
+        assert_eq!(
+            error.to_string(),
+            "value 'zz' in column 'score' is not a valid int64 (the type was inferred from the first chunk; \
+             set it with --schema 'score:string')"
+        );
+        // No rows are dropped into a file that looks complete
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn keeps_every_row_with_an_explicit_schema() {
+        let output = test_path("schema.parquet");
+        convert(MIXED, &output, "score:string").unwrap();
+        let rows = read_back(&output);
+        std::fs::remove_file(&output).unwrap();
+        let scores: Vec<_> = rows.iter().map(|row| row[1].as_str()).collect();
+        assert_eq!(scores, ["10", "20", "30", "40", "zz", "3.5", "70"]);
+        assert_eq!(rows[6][0], "7");
+
+        let error = convert(MIXED, &output, "score:float64").unwrap_err();
+        assert_eq!(error.to_string(), "value 'zz' in column 'score' is not a valid float64");
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn infers_types_from_the_first_chunk() {
+        let mut sampler = TypeSampler::new(4);
+        for row in [["1", "1.5", "true", ""], ["-2", "2", "FALSE", ""], ["3", "x", "", ""]] {
+            sampler.start_row(true);
+            for (column, value) in row.iter().enumerate() {
+                sampler.write_value(column, column, value.as_bytes());
+            }
+            sampler.end_row(row.len());
+        }
+        let types = sampler.finish();
+        assert_eq!(types, [Some(ColumnType::Int64), Some(ColumnType::String), Some(ColumnType::Boolean), None]);
+        assert_eq!(ColumnType::join(Some(ColumnType::Int64), Some(ColumnType::Float64)), Some(ColumnType::Float64));
+        assert_eq!(ColumnType::join(None, Some(ColumnType::Boolean)), Some(ColumnType::Boolean));
+    }
+}
@@ -0,0 +1,113 @@
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+/// Compression format of an input or output stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    /// Detects the format from the leading magic bytes of a stream.
+    pub fn from_magic(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if bytes.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+
+    /// Guesses the format from the file extension.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz" | "gzip") => Compression::Gzip,
+            Some("zst" | "zstd") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            Some("xz") => Compression::Xz,
+            _ => Compression::None,
+        }
+    }
+
+    /// Wraps `reader` in a decoder for this format. Concatenated members and
+    /// frames are decoded as one stream.
+    pub fn decoder(self, reader: Box<dyn Read + Send>) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Compression::None => reader,
+            Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Compression::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+        })
+    }
+
+    /// Levels the encoder of this format accepts.
+    pub fn levels(self) -> RangeInclusive<u32> {
+        match self {
+            Compression::None => 0..=0,
+            Compression::Gzip | Compression::Xz => 0..=9,
+            Compression::Zstd => 1..=22,
+            Compression::Bzip2 => 1..=9,
+        }
+    }
+
+    /// Compresses `data` into a self-contained member or frame. Every format
+    /// supported here decodes concatenated members as a single stream, which
+    /// lets chunks be compressed independently on the worker threads.
+    pub fn compress(self, data: Vec<u8>, level: Option<u32>) -> io::Result<Vec<u8>> {
+        let out = Vec::with_capacity(data.len() / 2);
+        match self {
+            Compression::None => Ok(data),
+            Compression::Gzip => {
+                let level = flate2::Compression::new(level.unwrap_or(6));
+                let mut encoder = GzEncoder::new(out, level);
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::bulk::compress(&data, level.unwrap_or(3) as i32),
+            Compression::Bzip2 => {
+                let level = bzip2::Compression::new(level.unwrap_or(6));
+                let mut encoder = BzEncoder::new(out, level);
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+            Compression::Xz => {
+                let mut encoder = XzEncoder::new(out, level.unwrap_or(6));
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            "bzip2" | "bz2" => Ok(Compression::Bzip2),
+            "xz" => Ok(Compression::Xz),
+            _ => Err(format!("unknown compression '{}' (expected none, gzip, zstd, bzip2 or xz)", s)),
+        }
+    }
+}
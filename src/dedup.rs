@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::spill::{self, SpillDir, SpillWriter};
+
+/// Approximate bookkeeping cost of a remembered row besides its bytes
+const ENTRY_OVERHEAD: usize = 48;
+
+/// Which of several rows with the same key is kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Keep {
+    #[default]
+    First,
+    Last,
+}
+
+impl FromStr for Keep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Keep::First),
+            "last" => Ok(Keep::Last),
+            _ => Err(format!("unknown occurrence '{}' (expected first or last)", s)),
+        }
+    }
+}
+
+/// Removes rows with repeated keys in the writer stage, where rows arrive
+/// in input order. Keys are remembered in memory until they exceed the
+/// budget; after that every row is spilled to disk, partitioned by the
+/// hash of its key, and the partitions are deduplicated one at a time and
+/// merged back into input order at the end.
+pub struct Deduplicator<'a> {
+    keep: Keep,
+    budget: usize,
+    used: usize,
+    /// Output position of the next row
+    seq: u64,
+    duplicates: usize,
+    /// Kept row of each key with its position. With `Keep::First` rows are
+    /// emitted as they arrive and only the keys are held.
+    seen: HashMap<Vec<u8>, (u64, Vec<u8>)>,
+    temp_dir: &'a Path,
+    partitions: usize,
+    spill: Option<(SpillDir, Vec<SpillWriter>)>,
+}
+
+/// Spilled entry flag of a row that was already written
+const EMITTED: u8 = 1;
+
+impl<'a> Deduplicator<'a> {
+    /// `input_bytes` sizes the spill partitions.
+    pub fn new(keep: Keep, budget: usize, temp_dir: &'a Path, input_bytes: Option<u64>) -> Self {
+        Self {
+            keep,
+            budget,
+            used: 0,
+            seq: 0,
+            duplicates: 0,
+            seen: HashMap::new(),
+            temp_dir,
+            partitions: spill::partition_count(input_bytes, budget),
+            spill: None,
+        }
+    }
+
+    /// Adds the next output row; `emit` writes a row to the output.
+    pub fn push(
+        &mut self,
+        key: &[u8],
+        row: &[u8],
+        emit: &mut impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let seq = self.seq;
+        self.seq += 1;
+        if let Some((_, partitions)) = &mut self.spill {
+            let partition = (spill::hash_key(key) % partitions.len() as u64) as usize;
+            partitions[partition].write(&[&meta(seq, 0), key, row])?;
+            return Ok(());
+        }
+
+        match (self.seen.get_mut(key), self.keep) {
+            (Some(_), Keep::First) => self.duplicates += 1,
+            (Some(kept), Keep::Last) => {
+                self.used = self.used + row.len() - kept.1.len();
+                *kept = (seq, row.to_vec());
+                self.duplicates += 1;
+            }
+            (None, Keep::First) => {
+                emit(row)?;
+                self.used += key.len() + ENTRY_OVERHEAD;
+                self.seen.insert(key.to_vec(), (seq, Vec::new()));
+            }
+            (None, Keep::Last) => {
+                self.used += key.len() + row.len() + ENTRY_OVERHEAD;
+                self.seen.insert(key.to_vec(), (seq, row.to_vec()));
+            }
+        }
+
+        if self.used > self.budget {
+            self.start_spill()?;
+        }
+        Ok(())
+    }
+
+    /// Moves the remembered rows to the spill partitions. Rows that were
+    /// already written are spilled as markers, so later repeats still
+    /// find them.
+    fn start_spill(&mut self) -> Result<(), Error> {
+        let dir = SpillDir::new(self.temp_dir)?;
+        let mut partitions = (0..self.partitions).map(|_| dir.create()).collect::<Result<Vec<_>, _>>()?;
+
+        // Spill in input order, so every partition stays sorted by position
+        let mut seen: Vec<_> = std::mem::take(&mut self.seen).into_iter().collect();
+        seen.sort_unstable_by_key(|(_, (seq, _))| *seq);
+        let flag = match self.keep {
+            Keep::First => EMITTED,
+            Keep::Last => 0,
+        };
+        for (key, (seq, row)) in seen {
+            let partition = (spill::hash_key(&key) % partitions.len() as u64) as usize;
+            partitions[partition].write(&[&meta(seq, flag), &key, &row])?;
+        }
+
+        self.used = 0;
+        self.spill = Some((dir, partitions));
+        Ok(())
+    }
+
+    /// Writes the remaining kept rows in input order, returning the number
+    /// of rows dropped as duplicates.
+    pub fn finish(mut self, emit: &mut impl FnMut(&[u8]) -> Result<(), Error>) -> Result<usize, Error> {
+        let Some((dir, partitions)) = self.spill.take() else {
+            if self.keep == Keep::Last {
+                let mut rows: Vec<_> = self.seen.into_values().collect();
+                rows.sort_unstable_by_key(|(seq, _)| *seq);
+                for (_, row) in rows {
+                    emit(&row)?;
+                }
+            }
+            return Ok(self.duplicates);
+        };
+
+        // Deduplicate each partition in memory and write its survivors,
+        // which are still in input order, to a run
+        let mut runs = Vec::new();
+        let mut fields = [Vec::new(), Vec::new(), Vec::new()];
+        for partition in partitions {
+            let mut reader = partition.finish()?;
+            let mut kept: HashMap<Vec<u8>, (u64, u8, Vec<u8>)> = HashMap::new();
+            while reader.read(&mut fields)? {
+                let (seq, flag) = parse_meta(&fields[0]);
+                let entry = (seq, flag, std::mem::take(&mut fields[2]));
+                match kept.get_mut(&fields[1]) {
+                    Some(existing) => {
+                        self.duplicates += 1;
+                        if self.keep == Keep::Last {
+                            *existing = entry;
+                        }
+                    }
+                    None => {
+                        kept.insert(std::mem::take(&mut fields[1]), entry);
+                    }
+                }
+            }
+
+            let mut survivors: Vec<_> = kept.into_values().filter(|(_, flag, _)| *flag != EMITTED).collect();
+            survivors.sort_unstable_by_key(|(seq, _, _)| *seq);
+            let mut run = dir.create()?;
+            for (seq, _, row) in survivors {
+                run.write(&[&seq.to_le_bytes(), &row])?;
+            }
+            runs.push(run.finish()?);
+        }
+
+        spill::merge_by_position(runs, emit)?;
+        Ok(self.duplicates)
+    }
+}
+
+/// Encodes the position and flag of a spilled row.
+fn meta(seq: u64, flag: u8) -> [u8; 9] {
+    let mut meta = [0; 9];
+    meta[..8].copy_from_slice(&seq.to_le_bytes());
+    meta[8] = flag;
+    meta
+}
+
+/// Decodes `meta`.
+fn parse_meta(meta: &[u8]) -> (u64, u8) {
+    (spill::position(meta), meta[8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rows `key:index` over a few keys, repeating in an irregular order.
+    fn rows() -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..500)
+            .map(|i| {
+                let key = format!("k{}", (i * 7 + i / 13) % 37);
+                let row = format!("{}:{}", key, i);
+                (key.into_bytes(), row.into_bytes())
+            })
+            .collect()
+    }
+
+    /// The kept rows in output order, as a plain in-memory pass finds them.
+    fn expected(rows: &[(Vec<u8>, Vec<u8>)], keep: Keep) -> Vec<Vec<u8>> {
+        let mut kept: Vec<(usize, &Vec<u8>)> = Vec::new();
+        let mut index: HashMap<&[u8], usize> = HashMap::new();
+        for (seq, (key, row)) in rows.iter().enumerate() {
+            match (index.get(&key[..]), keep) {
+                (Some(_), Keep::First) => {}
+                (Some(&i), Keep::Last) => kept[i] = (seq, row),
+                (None, _) => {
+                    index.insert(key, kept.len());
+                    kept.push((seq, row));
+                }
+            }
+        }
+        kept.sort_by_key(|(seq, _)| *seq);
+        kept.into_iter().map(|(_, row)| row.clone()).collect()
+    }
+
+    fn dedup(rows: &[(Vec<u8>, Vec<u8>)], keep: Keep, budget: usize) -> (Vec<Vec<u8>>, usize) {
+        let temp_dir = std::env::temp_dir();
+        let mut dedup = Deduplicator::new(keep, budget, &temp_dir, Some(4096));
+        let mut out = Vec::new();
+        let mut emit = |row: &[u8]| {
+            out.push(row.to_vec());
+            Ok(())
+        };
+        for (key, row) in rows {
+            dedup.push(key, row, &mut emit).unwrap();
+        }
+        let duplicates = dedup.finish(&mut emit).unwrap();
+        (out, duplicates)
+    }
+
+    #[test]
+    fn keeps_the_first_row_of_each_key() {
+        let rows = rows();
+        let (out, duplicates) = dedup(&rows, Keep::First, usize::MAX);
+        assert_eq!(out, expected(&rows, Keep::First));
+        assert_eq!(duplicates, rows.len() - 37);
+    }
+
+    #[test]
+    fn keeps_the_last_row_of_each_key_in_its_position() {
+        let rows = vec![
+            (b"a".to_vec(), b"a1".to_vec()),
+            (b"b".to_vec(), b"b1".to_vec()),
+            (b"a".to_vec(), b"a2".to_vec()),
+            (b"c".to_vec(), b"c1".to_vec()),
+            (b"b".to_vec(), b"b2".to_vec()),
+        ];
+        let (out, duplicates) = dedup(&rows, Keep::Last, usize::MAX);
+        assert_eq!(out, [&b"a2"[..], b"c1", b"b2"]);
+        assert_eq!(duplicates, 2);
+
+        let rows = self::rows();
+        assert_eq!(dedup(&rows, Keep::Last, usize::MAX).0, expected(&rows, Keep::Last));
+    }
+
+    #[test]
+    fn spills_with_a_tiny_budget() {
+        let rows = rows();
+        for keep in [Keep::First, Keep::Last] {
+            // From the first row, after a few keys, and after most of them
+            for budget in [0, 200, 1500] {
+                let (out, duplicates) = dedup(&rows, keep, budget);
+                assert_eq!(out, expected(&rows, keep), "{:?} with budget {}", keep, budget);
+                assert_eq!(duplicates, rows.len() - 37, "{:?} with budget {}", keep, budget);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_keep() {
+        assert_eq!("first".parse::<Keep>(), Ok(Keep::First));
+        assert_eq!("last".parse::<Keep>(), Ok(Keep::Last));
+        assert!("middle".parse::<Keep>().is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use arrow_schema::ArrowError;
+use parquet::errors::ParquetError;
+
+/// Errors returned by the processing engine.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading the input or writing the output failed
+    Io(io::Error),
+    /// A column name that is not in the header
+    UnknownColumn(String),
+    /// A column given by name for an input without a header
+    NoHeader(String),
+    /// A `--where` expression that does not parse
+    Expression(String),
+    /// An invalid regular expression
+    Regex(regex::Error),
+    /// Invalid or conflicting options
+    Config(String),
+    /// A value that does not fit the type of its output column
+    InvalidValue(String),
+    /// A malformed input row, with `OnError::Fail`
+    Parse(ParseError),
+    /// Reading or writing Arrow data failed
+    Arrow(ArrowError),
+    /// Reading or writing Parquet failed
+    Parquet(ParquetError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::UnknownColumn(name) => write!(f, "unknown column '{}'", name),
+            Error::NoHeader(name) => write!(f, "column '{}' is not an index and the input has no header", name),
+            Error::Expression(message) => write!(f, "invalid expression: {}", message),
+            Error::Regex(e) => write!(f, "{}", e),
+            Error::Config(message) | Error::InvalidValue(message) => write!(f, "{}", message),
+            Error::Parse(e) => write!(f, "{}", e),
+            Error::Arrow(e) => write!(f, "{}", e),
+            Error::Parquet(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Regex(e) => Some(e),
+            Error::Arrow(e) => Some(e),
+            Error::Parquet(e) => Some(e),
+            Error::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Self {
+        Error::Regex(e)
+    }
+}
+
+impl From<ArrowError> for Error {
+    fn from(e: ArrowError) -> Self {
+        Error::Arrow(e)
+    }
+}
+
+impl From<ParquetError> for Error {
+    fn from(e: ParquetError) -> Self {
+        Error::Parquet(e)
+    }
+}
+
+/// A malformed input row.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-based line the row starts on (the row number for columnar input)
+    pub line: u64,
+    /// Byte offset of the row in the (decompressed) input
+    pub byte_offset: u64,
+    /// 0-based field the problem was found in, if it is specific to one
+    pub column: Option<usize>,
+    pub kind: ParseErrorKind,
+}
+
+/// What is wrong with a malformed row.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// A quoted field runs to the end of the input without a closing quote
+    UnterminatedQuote,
+    /// A closing quote is followed by more text before the delimiter
+    TextAfterQuote,
+    /// The row has fewer fields than the selection needs
+    TooFewFields { expected: usize, found: usize },
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnterminatedQuote => write!(f, "unterminated quoted field"),
+            ParseErrorKind::TextAfterQuote => write!(f, "text after closing quote"),
+            ParseErrorKind::TooFewFields { expected, found } => {
+                write!(f, "expected at least {} fields but found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} (byte {})", self.line, self.byte_offset)?;
+        if let Some(column) = self.column {
+            write!(f, ", column {}", column)?;
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// What to do with malformed rows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Drop the row
+    #[default]
+    Skip,
+    /// Stop with `Error::Parse` at the first malformed row
+    Fail,
+    /// Drop the row and report it on stderr
+    Log,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnError::Skip),
+            "fail" => Ok(OnError::Fail),
+            "log" => Ok(OnError::Log),
+            _ => Err(format!("unknown error policy '{}' (expected skip, fail or log)", s)),
+        }
+    }
+}
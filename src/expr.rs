@@ -0,0 +1,647 @@
+use regex::bytes::Regex;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::io::Write;
+
+use crate::header::{resolve_column, Header};
+
+/// A compiled `--where` predicate.
+///
+/// Expressions are parsed and their columns resolved once, then evaluated
+/// per row directly on the borrowed field slices. Supported syntax:
+///
+/// - columns by header name (`email`), quoted name (`"first name"`) or
+///   0-based index (`#1`)
+/// - string literals in single quotes (`'a''b'` escapes a quote) and numbers
+/// - comparisons `= == != <> < <= > >=`, numeric when both sides are numbers
+///   and byte-wise otherwise
+/// - `AND`, `OR`, `NOT` and parentheses
+/// - `x IS [NOT] EMPTY`, `x [NOT] IN (a, b, ...)`, `x [NOT] LIKE 'a%_'`
+/// - regex matches with `x MATCHES 're'`, `x ~ 're'` and `x !~ 're'`
+/// - functions `len(x)`, `lower(x)`, `upper(x)` and `trim(x)`
+#[derive(Debug)]
+pub struct Expr {
+    root: Node,
+}
+
+#[derive(Debug)]
+enum Node {
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Compare(Operand, CmpOp, Operand),
+    IsEmpty(Operand),
+    In(Operand, Vec<Operand>),
+    Like(Operand, Vec<u8>),
+    Matches(Operand, Regex),
+}
+
+#[derive(Debug)]
+enum Operand {
+    Column(usize),
+    Literal(Vec<u8>),
+    Call(Func, Box<Operand>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Func {
+    Len,
+    Lower,
+    Upper,
+    Trim,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Case mapping applied lazily by `lower()` and `upper()`, so string
+/// functions never have to copy the field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Case {
+    Keep,
+    Lower,
+    Upper,
+}
+
+impl Case {
+    fn apply(self, b: u8) -> u8 {
+        match self {
+            Case::Keep => b,
+            Case::Lower => b.to_ascii_lowercase(),
+            Case::Upper => b.to_ascii_uppercase(),
+        }
+    }
+}
+
+/// A value produced while evaluating an operand for one row.
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    Str(&'a [u8], Case),
+    Num(f64),
+}
+
+impl Value<'_> {
+    fn as_num(&self) -> Option<f64> {
+        match *self {
+            Value::Num(n) => Some(n),
+            Value::Str(bytes, _) => parse_number(bytes),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(self, Value::Str(bytes, _) if bytes.is_empty())
+    }
+
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        if let (Some(a), Some(b)) = (self.as_num(), other.as_num()) {
+            return a.partial_cmp(&b);
+        }
+        match (*self, *other) {
+            (Value::Str(a, ca), Value::Str(b, cb)) => {
+                Some(a.iter().map(|&x| ca.apply(x)).cmp(b.iter().map(|&x| cb.apply(x))))
+            }
+            _ => None,
+        }
+    }
+
+    /// Calls `f` with the value as a contiguous byte string, mapping case or
+    /// formatting numbers into a reused per-thread buffer when needed.
+    fn with_bytes<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        thread_local! {
+            static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        }
+
+        match *self {
+            Value::Str(bytes, Case::Keep) => f(bytes),
+            value => SCRATCH.with(|scratch| {
+                let mut scratch = scratch.borrow_mut();
+                scratch.clear();
+                match value {
+                    Value::Str(bytes, case) => scratch.extend(bytes.iter().map(|&b| case.apply(b))),
+                    Value::Num(n) => {
+                        let _ = write!(scratch, "{}", n);
+                    }
+                }
+                f(&scratch)
+            }),
+        }
+    }
+}
+
+/// Parses a field as a number, ignoring surrounding whitespace.
+pub fn parse_number(bytes: &[u8]) -> Option<f64> {
+    // Rust also accepts "inf" and "nan", which should stay strings here
+    if !bytes.iter().any(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(bytes).ok()?.trim().parse().ok()
+}
+
+impl Expr {
+    /// Parses `source`, resolving column names against `header`.
+    pub fn compile(source: &str, header: Option<&Header>) -> Result<Self, crate::error::Error> {
+        Self::parse(source, header).map_err(|e| crate::error::Error::Expression(e.to_string()))
+    }
+
+    fn parse(source: &str, header: Option<&Header>) -> Result<Self, Box<dyn Error>> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            header,
+        };
+        let root = parser.parse_or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {} in expression", token.describe()).into());
+        }
+        Ok(Self { root })
+    }
+
+    /// Evaluates the expression against the fields of one row.
+    pub fn matches<F: AsRef<[u8]>>(&self, fields: &[F]) -> bool {
+        eval(&self.root, fields)
+    }
+}
+
+fn eval<F: AsRef<[u8]>>(node: &Node, fields: &[F]) -> bool {
+    match node {
+        Node::And(a, b) => eval(a, fields) && eval(b, fields),
+        Node::Or(a, b) => eval(a, fields) || eval(b, fields),
+        Node::Not(a) => !eval(a, fields),
+        Node::Compare(a, op, b) => {
+            let ordering = value(a, fields).compare(&value(b, fields));
+            match op {
+                CmpOp::Eq => ordering == Some(Ordering::Equal),
+                CmpOp::Ne => ordering != Some(Ordering::Equal),
+                CmpOp::Lt => ordering == Some(Ordering::Less),
+                CmpOp::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+                CmpOp::Gt => ordering == Some(Ordering::Greater),
+                CmpOp::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            }
+        }
+        Node::IsEmpty(a) => value(a, fields).is_empty(),
+        Node::In(a, list) => {
+            let needle = value(a, fields);
+            list.iter()
+                .any(|item| needle.compare(&value(item, fields)) == Some(Ordering::Equal))
+        }
+        Node::Like(a, pattern) => match value(a, fields) {
+            Value::Str(bytes, case) => like(bytes, case, pattern),
+            num => num.with_bytes(|bytes| like(bytes, Case::Keep, pattern)),
+        },
+        Node::Matches(a, regex) => value(a, fields).with_bytes(|bytes| regex.is_match(bytes)),
+    }
+}
+
+fn value<'a, F: AsRef<[u8]>>(operand: &'a Operand, fields: &'a [F]) -> Value<'a> {
+    match operand {
+        // Missing columns behave like empty fields
+        Operand::Column(index) => Value::Str(fields.get(*index).map_or(&[][..], |f| f.as_ref()), Case::Keep),
+        Operand::Literal(bytes) => Value::Str(bytes, Case::Keep),
+        Operand::Call(func, arg) => match (func, value(arg, fields)) {
+            (Func::Len, Value::Str(bytes, _)) => {
+                // Count UTF-8 characters by skipping continuation bytes
+                Value::Num(bytes.iter().filter(|&&b| b & 0xC0 != 0x80).count() as f64)
+            }
+            (Func::Len, num) => num.with_bytes(|bytes| Value::Num(bytes.len() as f64)),
+            (Func::Lower, Value::Str(bytes, _)) => Value::Str(bytes, Case::Lower),
+            (Func::Upper, Value::Str(bytes, _)) => Value::Str(bytes, Case::Upper),
+            (Func::Trim, Value::Str(bytes, case)) => Value::Str(trim(bytes), case),
+            (_, num) => num,
+        },
+    }
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(start, |i| i + 1);
+    &bytes[start..end]
+}
+
+/// SQL `LIKE` matching where `%` matches any run of characters and `_`
+/// matches exactly one character.
+fn like(text: &[u8], case: Case, pattern: &[u8]) -> bool {
+    let (mut t, mut p) = (0, 0);
+    // Position of the last `%` seen and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'%') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'_') => {
+                t += utf8_len(text[t]);
+                p += 1;
+                continue;
+            }
+            Some(&c) if c == case.apply(text[t]) => {
+                t += 1;
+                p += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        match backtrack {
+            Some((star, star_t)) => {
+                let next = star_t + utf8_len(text[star_t]);
+                backtrack = Some((star, next));
+                p = star + 1;
+                t = next;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p.min(pattern.len())..].iter().all(|&c| c == b'%')
+}
+
+fn utf8_len(lead: u8) -> usize {
+    match lead {
+        0xF0..=0xFF => 4,
+        0xE0..=0xEF => 3,
+        0xC0..=0xDF => 2,
+        _ => 1,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    QuotedIdent(String),
+    Index(usize),
+    Str(String),
+    Number(String),
+    Symbol(&'static str),
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Ident(s) => format!("'{}'", s),
+            Token::QuotedIdent(s) => format!("\"{}\"", s),
+            Token::Index(i) => format!("#{}", i),
+            Token::Str(s) => format!("string '{}'", s),
+            Token::Number(s) => format!("number {}", s),
+            Token::Symbol(s) => format!("'{}'", s),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Ident(s) if s.eq_ignore_ascii_case(keyword))
+    }
+}
+
+const SYMBOLS: [&str; 13] = ["==", "!=", "<>", "<=", ">=", "!~", "=", "<", ">", "~", "(", ")", ","];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            // Doubling the quote inside the literal escapes it
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    Some(&q) if q == c && chars.get(i + 1) == Some(&c) => {
+                        text.push(c);
+                        i += 2;
+                    }
+                    Some(&q) if q == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(&other) => {
+                        text.push(other);
+                        i += 1;
+                    }
+                    None => return Err(format!("unterminated {} in expression", c).into()),
+                }
+            }
+            tokens.push(if c == '\'' { Token::Str(text) } else { Token::QuotedIdent(text) });
+        } else if c == '#' {
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let digits: String = chars[start..i].iter().collect();
+            let index = digits
+                .parse()
+                .map_err(|_| "expected a column index after '#' in expression")?;
+            tokens.push(Token::Index(index));
+        } else if c.is_ascii_digit() || (matches!(c, '-' | '.') && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit())) {
+            let start = i;
+            i += 1;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric()
+                    || chars[i] == '.'
+                    || ((chars[i] == '-' || chars[i] == '+') && matches!(chars[i - 1], 'e' | 'E')))
+            {
+                i += 1;
+            }
+            tokens.push(Token::Number(chars[start..i].iter().collect()));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(*s))
+                .ok_or_else(|| format!("unexpected character '{}' in expression", c))?;
+            tokens.push(Token::Symbol(symbol));
+            i += symbol.len();
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'h> {
+    tokens: Vec<Token>,
+    pos: usize,
+    header: Option<&'h Header>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, Box<dyn Error>> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("unexpected end of expression")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek().is_some_and(|t| t.is_keyword(keyword)) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), Box<dyn Error>> {
+        if self.eat_symbol(symbol) {
+            return Ok(());
+        }
+        match self.peek() {
+            Some(token) => Err(format!("expected '{}' but found {}", symbol, token.describe()).into()),
+            None => Err(format!("expected '{}' at end of expression", symbol).into()),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Node, Box<dyn Error>> {
+        let mut node = self.parse_and()?;
+        while self.eat_keyword("or") {
+            node = Node::Or(Box::new(node), Box::new(self.parse_and()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, Box<dyn Error>> {
+        let mut node = self.parse_not()?;
+        while self.eat_keyword("and") {
+            node = Node::And(Box::new(node), Box::new(self.parse_not()?));
+        }
+        Ok(node)
+    }
+
+    fn parse_not(&mut self) -> Result<Node, Box<dyn Error>> {
+        if self.eat_keyword("not") {
+            return Ok(Node::Not(Box::new(self.parse_not()?)));
+        }
+        if self.eat_symbol("(") {
+            let node = self.parse_or()?;
+            self.expect_symbol(")")?;
+            return Ok(node);
+        }
+        self.parse_predicate()
+    }
+
+    fn parse_predicate(&mut self) -> Result<Node, Box<dyn Error>> {
+        let left = self.parse_operand()?;
+
+        if self.eat_keyword("is") {
+            let negated = self.eat_keyword("not");
+            if !self.eat_keyword("empty") {
+                return Err("expected EMPTY after IS".into());
+            }
+            return Ok(negate(Node::IsEmpty(left), negated));
+        }
+
+        let negated = self.eat_keyword("not");
+        if self.eat_keyword("in") {
+            self.expect_symbol("(")?;
+            let mut list = vec![self.parse_operand()?];
+            while self.eat_symbol(",") {
+                list.push(self.parse_operand()?);
+            }
+            self.expect_symbol(")")?;
+            return Ok(negate(Node::In(left, list), negated));
+        }
+        if self.eat_keyword("like") {
+            let pattern = self.parse_string("LIKE")?;
+            return Ok(negate(Node::Like(left, pattern.into_bytes()), negated));
+        }
+        if self.eat_keyword("matches") {
+            return Ok(negate(Node::Matches(left, self.parse_regex()?), negated));
+        }
+        if negated {
+            return Err("expected IN, LIKE or MATCHES after NOT".into());
+        }
+
+        let op = match self.next()? {
+            Token::Symbol("=" | "==") => CmpOp::Eq,
+            Token::Symbol("!=" | "<>") => CmpOp::Ne,
+            Token::Symbol("<") => CmpOp::Lt,
+            Token::Symbol("<=") => CmpOp::Le,
+            Token::Symbol(">") => CmpOp::Gt,
+            Token::Symbol(">=") => CmpOp::Ge,
+            Token::Symbol("~") => return Ok(Node::Matches(left, self.parse_regex()?)),
+            Token::Symbol("!~") => return Ok(Node::Not(Box::new(Node::Matches(left, self.parse_regex()?)))),
+            token => return Err(format!("expected a comparison but found {}", token.describe()).into()),
+        };
+        Ok(Node::Compare(left, op, self.parse_operand()?))
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, Box<dyn Error>> {
+        match self.next()? {
+            Token::Str(s) => Ok(Operand::Literal(s.into_bytes())),
+            Token::Number(n) => Ok(Operand::Literal(n.into_bytes())),
+            Token::Index(i) => Ok(Operand::Column(i)),
+            Token::QuotedIdent(name) => Ok(Operand::Column(self.resolve(&name)?)),
+            Token::Ident(name) if self.eat_symbol("(") => {
+                let func = match name.to_ascii_lowercase().as_str() {
+                    "len" | "length" => Func::Len,
+                    "lower" => Func::Lower,
+                    "upper" => Func::Upper,
+                    "trim" => Func::Trim,
+                    _ => return Err(format!("unknown function '{}'", name).into()),
+                };
+                let arg = self.parse_operand()?;
+                self.expect_symbol(")")?;
+                Ok(Operand::Call(func, Box::new(arg)))
+            }
+            Token::Ident(name) => Ok(Operand::Column(self.resolve(&name)?)),
+            token => Err(format!("expected a column or value but found {}", token.describe()).into()),
+        }
+    }
+
+    fn parse_string(&mut self, context: &str) -> Result<String, Box<dyn Error>> {
+        match self.next()? {
+            Token::Str(s) => Ok(s),
+            token => Err(format!("expected a string after {} but found {}", context, token.describe()).into()),
+        }
+    }
+
+    fn parse_regex(&mut self) -> Result<Regex, Box<dyn Error>> {
+        let pattern = self.parse_string("MATCHES")?;
+        Ok(Regex::new(&pattern)?)
+    }
+
+    fn resolve(&self, name: &str) -> Result<usize, Box<dyn Error>> {
+        match self.header {
+            Some(header) => header
+                .index_of(name)
+                .ok_or_else(|| format!("unknown column '{}' in expression", name).into()),
+            None => Ok(resolve_column(name, None)?),
+        }
+    }
+}
+
+fn negate(node: Node, negated: bool) -> Node {
+    if negated {
+        Node::Not(Box::new(node))
+    } else {
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header::from_names(vec![b"id".to_vec(), b"name".to_vec(), b"score".to_vec(), b"first name".to_vec()])
+    }
+
+    fn eval(source: &str, row: &[&str]) -> bool {
+        let fields: Vec<&[u8]> = row.iter().map(|f| f.as_bytes()).collect();
+        Expr::compile(source, Some(&header())).unwrap().matches(&fields)
+    }
+
+    #[test]
+    fn compares_numbers_numerically() {
+        let row = ["10", "bob", " 9.5 ", ""];
+        assert!(eval("id > 9", &row));
+        assert!(eval("id > score", &row));
+        assert!(eval("score < 10", &row));
+        assert!(eval("id = 10.0", &row));
+        assert!(eval("id = '1e1'", &row));
+        assert!(eval("#2 >= 9.5 AND #2 <= 9.5", &row));
+        assert!(!eval("id != 10", &row));
+    }
+
+    #[test]
+    fn compares_strings_bytewise() {
+        let row = ["10", "bob", "abc", "Ann"];
+        // Only one side is a number, so both are compared as strings
+        assert!(eval("id < '9x'", &row));
+        assert!(eval("name > 'Bob'", &row));
+        assert!(eval("name = 'bob'", &row));
+        assert!(!eval("name = 'Bob'", &row));
+        assert!(eval("upper(name) = 'BOB'", &row));
+        assert!(eval("lower(\"first name\") == 'ann'", &row));
+        assert!(eval("name <> 'alice'", &row));
+    }
+
+    #[test]
+    fn inf_and_nan_are_strings() {
+        let row = ["inf", "nan", "", ""];
+        // Compared byte-wise, 'i' sorts after '1'
+        assert!(eval("id > 1000", &row));
+        assert!(eval("id = 'inf'", &row));
+        // As a number NaN would not equal itself
+        assert!(eval("name = name", &row));
+    }
+
+    #[test]
+    fn evaluates_in_lists() {
+        let row = ["7", "carol", "", ""];
+        assert!(eval("id IN (1, 7, 9)", &row));
+        assert!(eval("id IN ('07.0')", &row));
+        assert!(eval("name IN ('bob', 'carol')", &row));
+        assert!(!eval("name IN ('Carol')", &row));
+        assert!(eval("name NOT IN ('bob', 'dave')", &row));
+        assert!(eval("upper(name) IN ('CAROL')", &row));
+        assert!(eval("id IN (name, 7)", &row));
+    }
+
+    #[test]
+    fn evaluates_like_patterns() {
+        let row = ["12", "jane.doe@example.com", "", "Zoë"];
+        assert!(eval("name LIKE '%@example.com'", &row));
+        assert!(eval("name LIKE 'jane%'", &row));
+        assert!(eval("name LIKE '%doe%'", &row));
+        assert!(eval("name LIKE 'j_ne.%'", &row));
+        assert!(!eval("name LIKE 'JANE%'", &row));
+        assert!(eval("upper(name) LIKE 'JANE%'", &row));
+        assert!(eval("name NOT LIKE '%@test.com'", &row));
+        assert!(eval("id LIKE '1_'", &row));
+        assert!(eval("len(id) LIKE '2'", &row));
+        assert!(eval("\"first name\" LIKE 'Zo_'", &row));
+        assert!(eval("#2 LIKE '%'", &row));
+        assert!(!eval("#2 LIKE '_%'", &row));
+        assert!(eval("name LIKE '%a%e%e%'", &row));
+    }
+
+    #[test]
+    fn combines_conditions() {
+        let row = ["3", "", "  x ", ""];
+        assert!(eval("name IS EMPTY AND score IS NOT EMPTY", &row));
+        assert!(eval("NOT (id = 4 OR id = 5)", &row));
+        assert!(eval("id = 1 OR id = 2 OR id = 3", &row));
+        assert!(eval("trim(score) = 'x' AND len(score) = 4", &row));
+        assert!(eval("score ~ '^ +x' AND score !~ 'y'", &row));
+        // Missing columns behave like empty fields
+        assert!(eval("#9 IS EMPTY", &row));
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for source in ["id >", "id IN (1, 2", "name LIKE 5", "nope = 1", "id = 1 extra", "name NOT = 'a'"] {
+            assert!(Expr::compile(source, Some(&header())).is_err(), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use regex::bytes::Regex;
+
+use crate::error::Error;
+use crate::expr::Expr;
+use crate::keys::KeySet;
+
+/// Row filters applied before field extraction. A row is kept only if it
+/// passes every configured filter.
+#[derive(Debug, Default)]
+pub struct RowFilter {
+    /// Drop rows where these two columns are equal
+    pub filter_equal: Option<(usize, usize)>,
+    /// Keep rows matching this `--where` expression
+    pub predicate: Option<Expr>,
+    /// Keep rows whose column matches (or with `negate`, does not match) a regex
+    pub matches: Vec<FieldMatch>,
+    /// Keep rows whose column is (or with `exclude`, is not) in a key list
+    pub key_lists: Vec<KeyMatch>,
+}
+
+/// A `--match` or `--not-match` condition on a single column.
+#[derive(Debug)]
+pub struct FieldMatch {
+    column: usize,
+    regex: Regex,
+    negate: bool,
+}
+
+impl FieldMatch {
+    /// Matches `pattern` against input column `column`. The regex is
+    /// byte-oriented, so rows that are not valid UTF-8 can still be matched.
+    pub fn new(column: usize, pattern: &str, negate: bool) -> Result<Self, Error> {
+        Ok(Self {
+            column,
+            regex: Regex::new(pattern)?,
+            negate,
+        })
+    }
+}
+
+/// An `--include-keys` or `--exclude-keys` condition on a single column.
+#[derive(Debug)]
+pub struct KeyMatch {
+    column: usize,
+    keys: KeySet,
+    exclude: bool,
+}
+
+impl KeyMatch {
+    pub fn new(column: usize, keys: KeySet, exclude: bool) -> Self {
+        Self { column, keys, exclude }
+    }
+}
+
+impl RowFilter {
+    /// Returns the index of the first filter that drops the row, or `None`
+    /// if the row is kept. Filters are numbered in the order they run:
+    /// `filter_equal`, then each of `matches`, each of `key_lists`, then
+    /// `predicate`.
+    pub fn rejecting_filter<F: AsRef<[u8]>>(&self, fields: &[F]) -> Option<usize> {
+        let mut index = 0;
+        if let Some((col1, col2)) = self.filter_equal {
+            if col1 < fields.len() && col2 < fields.len() && fields[col1].as_ref() == fields[col2].as_ref() {
+                return Some(index);
+            }
+            index += 1;
+        }
+
+        for m in &self.matches {
+            let field = fields.get(m.column).map_or(&[][..], |f| f.as_ref());
+            if m.regex.is_match(field) == m.negate {
+                return Some(index);
+            }
+            index += 1;
+        }
+
+        for k in &self.key_lists {
+            let field = fields.get(k.column).map_or(&[][..], |f| f.as_ref());
+            if k.keys.contains(field) == k.exclude {
+                return Some(index);
+            }
+            index += 1;
+        }
+
+        if let Some(predicate) = &self.predicate {
+            if !predicate.matches(fields) {
+                return Some(index);
+            }
+        }
+
+        None
+    }
+}
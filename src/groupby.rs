@@ -0,0 +1,751 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::columnar::StagedRow;
+use crate::error::Error;
+use crate::expr::parse_number;
+use crate::header::Column;
+use crate::processor::RowBuffer;
+use crate::spill::{self, push_value, SpillDir, SpillWriter, Values};
+use crate::writer::RowWriter;
+
+/// Approximate bookkeeping cost of a group or a distinct value besides its
+/// bytes
+const ENTRY_OVERHEAD: usize = 48;
+
+/// What an aggregate computes over the rows of a group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Function {
+    /// Rows, or with a column, non-empty values
+    Count,
+    CountDistinct,
+    /// Sum of the values that are numbers
+    Sum,
+    /// Smallest value; numbers compare as numbers and sort before text
+    Min,
+    Max,
+    /// Mean of the values that are numbers
+    Mean,
+    First,
+    Last,
+    /// Values joined with a separator, in input order
+    Concat,
+}
+
+impl Function {
+    pub fn name(self) -> &'static str {
+        match self {
+            Function::Count => "count",
+            Function::CountDistinct => "count_distinct",
+            Function::Sum => "sum",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Mean => "mean",
+            Function::First => "first",
+            Function::Last => "last",
+            Function::Concat => "concat",
+        }
+    }
+}
+
+/// An aggregate column of `group_by`. Empty values are ignored by every
+/// function except a `count` without a column.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Aggregate {
+    pub function: Function,
+    /// Output column aggregated; only `count` works without one
+    pub column: Option<Column>,
+    /// Separator of `concat`
+    pub separator: String,
+}
+
+impl Aggregate {
+    /// Counts the rows of each group.
+    pub fn count() -> Self {
+        Self {
+            function: Function::Count,
+            column: None,
+            separator: ";".to_string(),
+        }
+    }
+
+    pub fn new(function: Function, column: impl Into<Column>) -> Self {
+        Self {
+            function,
+            column: Some(column.into()),
+            ..Self::count()
+        }
+    }
+
+    /// Sets the separator of `concat` (`;` by default).
+    pub fn separator(mut self, separator: impl Into<String>) -> Self {
+        self.separator = separator.into();
+        self
+    }
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    /// Parses `count` or `FUNC:COL`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, column) = match s.split_once(':') {
+            Some((name, column)) => (name, Some(column)),
+            None => (s, None),
+        };
+        let function = match name {
+            "count" => Function::Count,
+            "count-distinct" | "distinct" => Function::CountDistinct,
+            "sum" => Function::Sum,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "mean" | "avg" => Function::Mean,
+            "first" => Function::First,
+            "last" => Function::Last,
+            "concat" => Function::Concat,
+            _ => return Err(format!("unknown aggregate '{}'", name)),
+        };
+        match column {
+            Some(column) if !column.is_empty() => Ok(Aggregate::new(function, column)),
+            None if function == Function::Count => Ok(Aggregate::count()),
+            _ => Err(format!("expected {}:COL but got '{}'", name, s)),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.column {
+            Some(column) => write!(f, "{}:{}", self.function.name(), column),
+            None => f.write_str(self.function.name()),
+        }
+    }
+}
+
+/// Group key and aggregates resolved against the output columns.
+pub struct Grouping {
+    /// Output columns making up the key, in order
+    by: Vec<usize>,
+    /// Function, column and separator of each aggregate
+    aggregates: Vec<(Function, Option<usize>, Vec<u8>)>,
+    /// Number of output columns before grouping
+    width: usize,
+    /// Names of the columns written: the key, then one per aggregate
+    pub names: Vec<Vec<u8>>,
+}
+
+impl Grouping {
+    /// `names` are the output columns; `aggregates` pairs each aggregate
+    /// with the index of its column.
+    pub fn new(names: &[Vec<u8>], by: Vec<usize>, aggregates: &[(Option<usize>, &Aggregate)]) -> Self {
+        let mut columns: Vec<Vec<u8>> = by.iter().map(|&column| names[column].clone()).collect();
+        for &(column, aggregate) in aggregates {
+            let mut name = aggregate.function.name().as_bytes().to_vec();
+            if let Some(column) = column {
+                name.push(b'_');
+                name.extend_from_slice(&names[column]);
+            }
+            columns.push(name);
+        }
+        Self {
+            by,
+            aggregates: aggregates
+                .iter()
+                .map(|&(column, aggregate)| (aggregate.function, column, aggregate.separator.as_bytes().to_vec()))
+                .collect(),
+            width: names.len(),
+            names: columns,
+        }
+    }
+
+    fn new_group(&self, first: u64) -> Group {
+        let states = self
+            .aggregates
+            .iter()
+            .map(|(function, _, _)| match function {
+                Function::Count => State::Count(0),
+                Function::CountDistinct => State::Distinct(HashSet::new(), 0),
+                Function::Sum | Function::Mean => State::Sum(Sum::default()),
+                Function::Min => State::Min(None),
+                Function::Max => State::Max(None),
+                Function::First => State::First(None),
+                Function::Last => State::Last(None),
+                Function::Concat => State::Concat(None),
+            })
+            .collect();
+        Group { first, states }
+    }
+
+    /// Serializes a group as an output row: its key values, then its
+    /// aggregates.
+    fn write_row(&self, key: &[u8], group: &Group, rows: &RowWriter, out: &mut Vec<u8>) {
+        rows.start_row(out, true);
+        let start = out.len();
+        let mut position = 0;
+        for value in Values(key) {
+            rows.write_value(out, position, position, value);
+            position += 1;
+        }
+        for (state, (function, _, _)) in group.states.iter().zip(&self.aggregates) {
+            let value = state.result(*function);
+            rows.write_value(out, position, position, &value);
+            position += 1;
+        }
+        rows.end_row(out, start, position);
+    }
+}
+
+/// Aggregates of one group, with the position of its first row.
+struct Group {
+    first: u64,
+    states: Vec<State>,
+}
+
+impl Group {
+    fn add(&mut self, grouping: &Grouping, values: &[&[u8]]) {
+        for (state, (_, column, separator)) in self.states.iter_mut().zip(&grouping.aggregates) {
+            let Some(column) = column else {
+                // A count of rows
+                if let State::Count(count) = state {
+                    *count += 1;
+                }
+                continue;
+            };
+            let value = values[*column];
+            if !value.is_empty() {
+                state.add(value, separator);
+            }
+        }
+    }
+
+    /// Folds in the aggregates of rows that come after this group's rows.
+    fn merge(&mut self, other: Group, grouping: &Grouping) {
+        self.first = self.first.min(other.first);
+        for ((state, other), (_, _, separator)) in self.states.iter_mut().zip(other.states).zip(&grouping.aggregates) {
+            state.merge(other, separator);
+        }
+    }
+
+    /// Approximate memory held by the group.
+    fn size(&self) -> usize {
+        ENTRY_OVERHEAD + self.states.iter().map(State::size).sum::<usize>()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = self.first.to_le_bytes().to_vec();
+        let mut state = Vec::new();
+        for s in &self.states {
+            state.clear();
+            s.encode(&mut state);
+            push_value(&mut out, &state);
+        }
+        out
+    }
+
+    fn decode(grouping: &Grouping, data: &[u8]) -> Self {
+        let mut group = grouping.new_group(spill::position(data));
+        for (state, data) in group.states.iter_mut().zip(Values(&data[8..])) {
+            state.decode(data);
+        }
+        group
+    }
+}
+
+/// Running sum of the values that are numbers. Integers are also summed
+/// exactly while they fit.
+#[derive(Default)]
+struct Sum {
+    float: f64,
+    int: Option<i64>,
+    count: u64,
+    /// Whether a value was not an integer, or the integer sum overflowed
+    inexact: bool,
+}
+
+impl Sum {
+    fn add(&mut self, number: f64, int: Option<i64>) {
+        self.float += number;
+        self.count += 1;
+        self.int = match (self.inexact, int) {
+            (false, Some(int)) => self.int.unwrap_or(0).checked_add(int),
+            _ => None,
+        };
+        self.inexact = self.int.is_none();
+    }
+
+    fn merge(&mut self, other: Sum) {
+        self.float += other.float;
+        self.count += other.count;
+        self.inexact |= other.inexact;
+        self.int = match (self.inexact, self.int, other.int) {
+            (false, a, b) => a.unwrap_or(0).checked_add(b.unwrap_or(0)),
+            _ => None,
+        };
+        self.inexact = self.int.is_none() && self.count > 0;
+    }
+}
+
+enum State {
+    Count(u64),
+    /// Distinct values and their total length
+    Distinct(HashSet<Vec<u8>>, usize),
+    Sum(Sum),
+    Min(Option<Vec<u8>>),
+    Max(Option<Vec<u8>>),
+    First(Option<Vec<u8>>),
+    Last(Option<Vec<u8>>),
+    Concat(Option<Vec<u8>>),
+}
+
+impl State {
+    /// Adds a non-empty value.
+    fn add(&mut self, value: &[u8], separator: &[u8]) {
+        match self {
+            State::Count(count) => *count += 1,
+            State::Distinct(values, bytes) => {
+                if !values.contains(value) {
+                    values.insert(value.to_vec());
+                    *bytes += value.len();
+                }
+            }
+            State::Sum(sum) => {
+                if let Some(number) = parse_number(value) {
+                    let int = std::str::from_utf8(value).ok().and_then(|s| s.trim().parse().ok());
+                    sum.add(number, int);
+                }
+            }
+            State::Min(min) => replace_if(min, value, Ordering::Less),
+            State::Max(max) => replace_if(max, value, Ordering::Greater),
+            State::First(first) => {
+                if first.is_none() {
+                    *first = Some(value.to_vec());
+                }
+            }
+            State::Last(last) => *last = Some(value.to_vec()),
+            State::Concat(joined) => match joined {
+                Some(joined) => {
+                    joined.extend_from_slice(separator);
+                    joined.extend_from_slice(value);
+                }
+                None => *joined = Some(value.to_vec()),
+            },
+        }
+    }
+
+    fn merge(&mut self, other: State, separator: &[u8]) {
+        match (self, other) {
+            (State::Count(count), State::Count(other)) => *count += other,
+            (State::Distinct(values, bytes), State::Distinct(other, _)) => {
+                for value in other {
+                    if !values.contains(&value) {
+                        *bytes += value.len();
+                        values.insert(value);
+                    }
+                }
+            }
+            (State::Sum(sum), State::Sum(other)) => sum.merge(other),
+            (State::Min(min), State::Min(Some(other))) => replace_if(min, &other, Ordering::Less),
+            (State::Max(max), State::Max(Some(other))) => replace_if(max, &other, Ordering::Greater),
+            (State::First(first @ None), State::First(other)) => *first = other,
+            (State::Last(last), State::Last(Some(other))) => *last = Some(other),
+            (State::Concat(joined), State::Concat(Some(other))) => match joined {
+                Some(joined) => {
+                    joined.extend_from_slice(separator);
+                    joined.extend_from_slice(&other);
+                }
+                None => *joined = Some(other),
+            },
+            _ => {}
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            State::Distinct(values, bytes) => bytes + values.len() * ENTRY_OVERHEAD,
+            State::Min(value) | State::Max(value) | State::First(value) | State::Last(value) | State::Concat(value) => {
+                value.as_ref().map_or(0, Vec::len)
+            }
+            State::Count(_) | State::Sum(_) => 0,
+        }
+    }
+
+    fn result(&self, function: Function) -> Vec<u8> {
+        let number = |n: f64| n.to_string().into_bytes();
+        match self {
+            State::Count(count) => count.to_string().into_bytes(),
+            State::Distinct(values, _) => values.len().to_string().into_bytes(),
+            State::Sum(sum) if sum.count == 0 => Vec::new(),
+            State::Sum(sum) => match (function, sum.int) {
+                (Function::Mean, _) => number(sum.float / sum.count as f64),
+                (_, Some(int)) => int.to_string().into_bytes(),
+                (_, None) => number(sum.float),
+            },
+            State::Min(value) | State::Max(value) | State::First(value) | State::Last(value) | State::Concat(value) => {
+                value.clone().unwrap_or_default()
+            }
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            State::Count(count) => out.extend_from_slice(&count.to_le_bytes()),
+            State::Distinct(values, _) => {
+                for value in values {
+                    push_value(out, value);
+                }
+            }
+            State::Sum(sum) => {
+                out.extend_from_slice(&sum.float.to_le_bytes());
+                out.extend_from_slice(&sum.int.unwrap_or(0).to_le_bytes());
+                out.extend_from_slice(&sum.count.to_le_bytes());
+                out.push(sum.inexact as u8);
+            }
+            State::Min(value) | State::Max(value) | State::First(value) | State::Last(value) | State::Concat(value) => {
+                if let Some(value) = value {
+                    out.push(1);
+                    out.extend_from_slice(value);
+                }
+            }
+        }
+    }
+
+    /// Reads back an encoded state of the same kind.
+    fn decode(&mut self, data: &[u8]) {
+        let u64_at = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+        match self {
+            State::Count(count) => *count = u64_at(0),
+            State::Distinct(values, bytes) => {
+                for value in Values(data) {
+                    *bytes += value.len();
+                    values.insert(value.to_vec());
+                }
+            }
+            State::Sum(sum) => {
+                sum.float = f64::from_bits(u64_at(0));
+                sum.count = u64_at(16);
+                sum.inexact = data[24] == 1;
+                sum.int = (!sum.inexact && sum.count > 0).then_some(u64_at(8) as i64);
+            }
+            State::Min(value) | State::Max(value) | State::First(value) | State::Last(value) | State::Concat(value) => {
+                *value = data.split_first().map(|(_, rest)| rest.to_vec());
+            }
+        }
+    }
+}
+
+/// Sets `current` to `value` if it is unset or `value` compares as `wanted`
+/// to it.
+fn replace_if(current: &mut Option<Vec<u8>>, value: &[u8], wanted: Ordering) {
+    let replace = match current {
+        None => true,
+        Some(current) => compare_values(value, current) == wanted,
+    };
+    if replace {
+        *current = Some(value.to_vec());
+    }
+}
+
+/// Numbers compare as numbers and before text, text byte by byte.
+fn compare_values(a: &[u8], b: &[u8]) -> Ordering {
+    match (parse_number(a), parse_number(b)) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+/// Groups of the rows of one chunk, aggregated on its worker thread.
+pub struct PartialGroups<'a> {
+    grouping: &'a Grouping,
+    row: StagedRow,
+    groups: HashMap<Vec<u8>, Group>,
+    /// Rows added, which positions the first row of each group
+    rows: u64,
+}
+
+impl<'a> PartialGroups<'a> {
+    pub fn new(grouping: &'a Grouping) -> Self {
+        Self {
+            grouping,
+            row: StagedRow::default(),
+            groups: HashMap::new(),
+            rows: 0,
+        }
+    }
+}
+
+impl RowBuffer for PartialGroups<'_> {
+    fn start_row(&mut self, _first: bool) {
+        self.row.clear();
+    }
+
+    fn write_value(&mut self, column: usize, _position: usize, value: &[u8]) {
+        self.row.push(column, value);
+    }
+
+    fn end_row(&mut self, _written: usize) {
+        let mut values = vec![&[][..]; self.grouping.width];
+        for (column, value) in self.row.iter() {
+            values[column] = value;
+        }
+        let mut key = Vec::new();
+        for &column in &self.grouping.by {
+            push_value(&mut key, values[column]);
+        }
+
+        let grouping = self.grouping;
+        let first = self.rows;
+        let group = self.groups.entry(key).or_insert_with(|| grouping.new_group(first));
+        group.add(grouping, &values);
+        self.rows += 1;
+    }
+
+    fn discard_row(&mut self) {
+        self.row.clear();
+    }
+}
+
+/// Merges the partial groups of each chunk in the writer stage, where
+/// they arrive in input order. Groups are held in memory until they exceed
+/// the budget; after that the groups and all later partial groups are
+/// spilled to disk, partitioned by the hash of their key, and each
+/// partition is merged on its own at the end. Groups are written in the
+/// order of their first row.
+pub struct Aggregator<'a> {
+    grouping: &'a Grouping,
+    budget: usize,
+    used: usize,
+    /// Rows merged so far
+    rows: u64,
+    groups: HashMap<Vec<u8>, Group>,
+    temp_dir: &'a Path,
+    partitions: usize,
+    spill: Option<(SpillDir, Vec<SpillWriter>)>,
+}
+
+impl<'a> Aggregator<'a> {
+    /// `input_bytes` sizes the spill partitions.
+    pub fn new(grouping: &'a Grouping, budget: usize, temp_dir: &'a Path, input_bytes: Option<u64>) -> Self {
+        Self {
+            grouping,
+            budget,
+            used: 0,
+            rows: 0,
+            groups: HashMap::new(),
+            temp_dir,
+            partitions: spill::partition_count(input_bytes, budget),
+            spill: None,
+        }
+    }
+
+    /// Adds the groups of the next chunk.
+    pub fn push(&mut self, partial: PartialGroups) -> Result<(), Error> {
+        let offset = self.rows;
+        self.rows += partial.rows;
+        for (key, mut group) in partial.groups {
+            group.first += offset;
+            if let Some((_, partitions)) = &mut self.spill {
+                let partition = (spill::hash_key(&key) % partitions.len() as u64) as usize;
+                partitions[partition].write(&[&key, &group.encode()])?;
+                continue;
+            }
+            match self.groups.get_mut(&key) {
+                Some(existing) => {
+                    let size = existing.size();
+                    existing.merge(group, self.grouping);
+                    self.used = self.used + existing.size() - size;
+                }
+                None => {
+                    self.used += key.len() + group.size();
+                    self.groups.insert(key, group);
+                }
+            }
+        }
+
+        if self.spill.is_none() && self.used > self.budget {
+            let dir = SpillDir::new(self.temp_dir)?;
+            let mut partitions = (0..self.partitions).map(|_| dir.create()).collect::<Result<Vec<_>, _>>()?;
+            for (key, group) in self.groups.drain() {
+                let partition = (spill::hash_key(&key) % partitions.len() as u64) as usize;
+                partitions[partition].write(&[&key, &group.encode()])?;
+            }
+            self.used = 0;
+            self.spill = Some((dir, partitions));
+        }
+        Ok(())
+    }
+
+    /// Writes one row per group and returns the number of groups.
+    pub fn finish(self, rows: &RowWriter, emit: &mut impl FnMut(&[u8]) -> Result<(), Error>) -> Result<usize, Error> {
+        let grouping = self.grouping;
+        let mut out = Vec::new();
+        let Some((dir, partitions)) = self.spill else {
+            let mut groups: Vec<_> = self.groups.into_iter().collect();
+            groups.sort_unstable_by_key(|(_, group)| group.first);
+            for (key, group) in &groups {
+                out.clear();
+                grouping.write_row(key, group, rows, &mut out);
+                emit(&out)?;
+            }
+            return Ok(groups.len());
+        };
+
+        // Merge each partition in memory and write its groups, in order of
+        // their first row, to a run
+        let mut count = 0;
+        let mut runs = Vec::new();
+        let mut fields = [Vec::new(), Vec::new()];
+        for partition in partitions {
+            let mut reader = partition.finish()?;
+            let mut groups: HashMap<Vec<u8>, Group> = HashMap::new();
+            while reader.read(&mut fields)? {
+                let group = Group::decode(grouping, &fields[1]);
+                match groups.get_mut(&fields[0]) {
+                    Some(existing) => existing.merge(group, grouping),
+                    None => {
+                        groups.insert(std::mem::take(&mut fields[0]), group);
+                    }
+                }
+            }
+
+            let mut groups: Vec<_> = groups.into_iter().collect();
+            groups.sort_unstable_by_key(|(_, group)| group.first);
+            let mut run = dir.create()?;
+            for (key, group) in &groups {
+                out.clear();
+                grouping.write_row(key, group, rows, &mut out);
+                run.write(&[&group.first.to_le_bytes(), &out])?;
+            }
+            count += groups.len();
+            runs.push(run.finish()?);
+        }
+
+        spill::merge_by_position(runs, emit)?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::OutputFormat;
+
+    const AGGREGATES: [&str; 9] = [
+        "count",
+        "count-distinct:value",
+        "sum:value",
+        "mean:value",
+        "min:value",
+        "max:value",
+        "first:value",
+        "last:value",
+        "concat:value",
+    ];
+
+    fn grouping() -> Grouping {
+        let names = [b"key".to_vec(), b"value".to_vec()];
+        let aggregates: Vec<Aggregate> = AGGREGATES.iter().map(|a| a.parse().unwrap()).collect();
+        let columns: Vec<_> = aggregates.iter().map(|a| (a.column.as_ref().map(|_| 1), a)).collect();
+        Grouping::new(&names, vec![0], &columns)
+    }
+
+    /// Aggregates `chunks` of `key,value` rows, each chunk on its own like
+    /// a worker does, and returns the output rows as CSV and whether the
+    /// groups spilled.
+    fn aggregate(chunks: &[Vec<(String, String)>], budget: usize) -> (String, bool) {
+        let grouping = grouping();
+        let temp_dir = std::env::temp_dir();
+        let mut aggregator = Aggregator::new(&grouping, budget, &temp_dir, Some(4096));
+        for rows in chunks {
+            let mut partial = PartialGroups::new(&grouping);
+            for (key, value) in rows {
+                partial.start_row(false);
+                partial.write_value(0, 0, key.as_bytes());
+                partial.write_value(1, 1, value.as_bytes());
+                partial.end_row(2);
+            }
+            aggregator.push(partial).unwrap();
+        }
+
+        let spilled = aggregator.spill.is_some();
+        let rows = RowWriter::new(OutputFormat::default(), grouping.names.clone(), false);
+        let mut out = Vec::new();
+        aggregator
+            .finish(&rows, &mut |row| {
+                out.extend_from_slice(row);
+                Ok(())
+            })
+            .unwrap();
+        (String::from_utf8(out).unwrap(), spilled)
+    }
+
+    fn rows(rows: &[(&str, &str)]) -> Vec<(String, String)> {
+        rows.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn aggregates_groups_in_order_of_their_first_row() {
+        let chunks = [
+            rows(&[("b", "3"), ("a", "x"), ("b", "")]),
+            rows(&[("a", "10"), ("c", "2.5"), ("b", "3")]),
+            rows(&[("a", "-1"), ("b", "1")]),
+        ];
+        let expected = "b,4,2,7,2.3333333333333335,1,3,3,1,3;3;1\n\
+                        a,3,3,9,4.5,-1,x,x,-1,x;10;-1\n\
+                        c,1,1,2.5,2.5,2.5,2.5,2.5,2.5,2.5\n";
+        assert_eq!(aggregate(&chunks, usize::MAX), (expected.to_string(), false));
+        assert_eq!(aggregate(&chunks, 0), (expected.to_string(), true));
+    }
+
+    /// Many groups over many chunks, with every value of a group distinct
+    /// so `concat` shows the order the rows were merged in.
+    fn chunks() -> Vec<Vec<(String, String)>> {
+        let rows: Vec<_> = (0..600).map(|i| (format!("k{}", (i * 11 + i / 17) % 43), i.to_string())).collect();
+        rows.chunks(7).map(<[_]>::to_vec).collect()
+    }
+
+    #[test]
+    fn concatenates_in_input_order() {
+        let chunks = chunks();
+        for budget in [usize::MAX, 0] {
+            let (out, _) = aggregate(&chunks, budget);
+            for line in out.lines() {
+                let concat = line.rsplit(',').next().unwrap();
+                let values: Vec<u64> = concat.split(';').map(|v| v.parse().unwrap()).collect();
+                assert!(values.windows(2).all(|pair| pair[0] < pair[1]), "budget {}: {}", budget, line);
+            }
+        }
+    }
+
+    #[test]
+    fn spills_with_a_tiny_budget() {
+        let chunks = chunks();
+        let (expected, _) = aggregate(&chunks, usize::MAX);
+        assert_eq!(expected.lines().count(), 43);
+        // From the first chunk, and after most of the groups
+        for budget in [0, 4000] {
+            assert_eq!(aggregate(&chunks, budget), (expected.clone(), true), "budget {}", budget);
+        }
+    }
+
+    #[test]
+    fn sums_integers_exactly() {
+        let big = i64::MAX / 2;
+        let chunks = [rows(&[("a", &big.to_string()), ("a", "1")]), rows(&[("a", &big.to_string())])];
+        let (out, _) = aggregate(&chunks, usize::MAX);
+        assert_eq!(out.split(',').nth(3), Some(&*(big * 2 + 1).to_string()));
+    }
+
+    #[test]
+    fn parses_aggregates() {
+        assert_eq!("count".parse::<Aggregate>(), Ok(Aggregate::count()));
+        assert_eq!("avg:price".parse::<Aggregate>(), Ok(Aggregate::new(Function::Mean, "price")));
+        assert_eq!(Aggregate::new(Function::CountDistinct, "id").to_string(), "count_distinct:id");
+        assert!("sum".parse::<Aggregate>().is_err());
+        assert!("median:price".parse::<Aggregate>().is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::tokenizer::Tokenizer;
+
+/// Column names parsed from the first record of the input.
+#[derive(Clone, Debug, Default)]
+pub struct Header {
+    names: Vec<Vec<u8>>,
+}
+
+impl Header {
+    pub fn parse(tokenizer: &Tokenizer, record: &[u8]) -> Self {
+        // Exports from spreadsheet tools often start with a UTF-8 BOM
+        let record = record.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(record);
+
+        let mut fields = Vec::new();
+        tokenizer.split_fields(record, &mut fields);
+        Self {
+            names: fields.into_iter().map(|f| f.into_owned()).collect(),
+        }
+    }
+
+    pub fn from_names(names: Vec<Vec<u8>>) -> Self {
+        Self { names }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn name(&self, index: usize) -> Option<&[u8]> {
+        self.names.get(index).map(|n| n.as_slice())
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name.as_bytes())
+    }
+}
+
+/// An input column, by 0-based index or by header name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+impl Column {
+    /// Resolves the column to an index, looking names up in `header`.
+    pub fn resolve(&self, header: Option<&Header>) -> Result<usize, Error> {
+        match (self, header) {
+            (Column::Index(index), _) => Ok(*index),
+            (Column::Name(name), Some(header)) => {
+                header.index_of(name).ok_or_else(|| Error::UnknownColumn(name.clone()))
+            }
+            (Column::Name(name), None) => Err(Error::NoHeader(name.clone())),
+        }
+    }
+}
+
+/// Entries that parse as integers are indices, anything else is a name.
+impl FromStr for Column {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        Ok(match s.parse::<usize>() {
+            Ok(index) => Column::Index(index),
+            Err(_) => Column::Name(s.to_string()),
+        })
+    }
+}
+
+impl From<usize> for Column {
+    fn from(index: usize) -> Self {
+        Column::Index(index)
+    }
+}
+
+impl From<&str> for Column {
+    fn from(s: &str) -> Self {
+        let Ok(column) = s.parse();
+        column
+    }
+}
+
+impl From<String> for Column {
+    fn from(s: String) -> Self {
+        Column::from(s.as_str())
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Column::Index(index) => write!(f, "{}", index),
+            Column::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+pub fn resolve_column(column: &str, header: Option<&Header>) -> Result<usize, Error> {
+    Column::from(column).resolve(header)
+}
@@ -0,0 +1,279 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::columnar::{self, Batches, ColumnarFormat};
+use crate::compression::Compression;
+use crate::error::Error;
+use crate::header::Header;
+use crate::tokenizer::{count_lines, Tokenizer};
+
+/// Where rows are read from.
+pub enum Source {
+    /// A file, or stdin if the path is `-`
+    Path(PathBuf),
+    /// Any reader; compressed and Arrow IPC stream data is detected
+    Reader(Box<dyn Read + Send>),
+}
+
+impl Source {
+    pub fn reader(reader: impl Read + Send + 'static) -> Self {
+        Source::Reader(Box::new(reader))
+    }
+}
+
+impl From<PathBuf> for Source {
+    fn from(path: PathBuf) -> Self {
+        Source::Path(path)
+    }
+}
+
+impl From<&Path> for Source {
+    fn from(path: &Path) -> Self {
+        Source::Path(path.to_path_buf())
+    }
+}
+
+impl From<&str> for Source {
+    fn from(path: &str) -> Self {
+        Source::Path(PathBuf::from(path))
+    }
+}
+
+/// An opened input, with its header already parsed if header mode is on.
+pub enum Input {
+    /// A regular file, memory-mapped and split into chunks in place
+    Mapped {
+        mmap: Mmap,
+        header: Option<Header>,
+        data_start: usize,
+    },
+    /// A pipe or stdin, read sequentially in record-aligned blocks
+    Stream {
+        reader: BlockReader,
+        header: Option<Header>,
+        /// Byte offset and line count of the consumed header
+        data_position: (u64, u64),
+    },
+    /// Parquet or Arrow IPC, read as record batches. The header always comes
+    /// from the schema.
+    Columnar {
+        batches: Batches,
+        header: Option<Header>,
+        size: Option<u64>,
+    },
+}
+
+impl Input {
+    /// Opens `path`, or stdin if it is `-`. Uncompressed regular files are
+    /// memory-mapped; compressed files and anything else (pipes, FIFOs,
+    /// process substitution) are decompressed if needed and streamed.
+    /// Parquet and Arrow IPC inputs are recognized by their magic bytes.
+    pub fn open(path: &Path, tokenizer: Tokenizer, has_header: bool, block_size: usize) -> Result<Self, Error> {
+        if path.as_os_str() == "-" {
+            return Self::from_reader(Box::new(io::stdin()), tokenizer, has_header, block_size);
+        }
+
+        let file = File::open(path)?;
+        let hint = Compression::from_path(path);
+        if !file.metadata()?.is_file() {
+            let reader = decompress(Box::new(file), hint)?;
+            return Self::stream(reader, tokenizer, has_header, block_size);
+        }
+
+        let mmap = unsafe { Mmap::map(&file)? };
+        if let Some(format) = ColumnarFormat::from_magic(&mmap) {
+            let size = Some(mmap.len() as u64);
+            drop(mmap);
+            let batches = format.open_file(file)?;
+            return Ok(Self::columnar(batches, size));
+        }
+        let compression = Compression::from_magic(&mmap).unwrap_or(hint);
+        if compression != Compression::None {
+            drop(mmap);
+            let reader = compression.decoder(Box::new(file))?;
+            return Self::stream(reader, tokenizer, has_header, block_size);
+        }
+
+        let (header, data_start) = match has_header {
+            true => {
+                let end = tokenizer.record_end(&mmap, 0);
+                let header = tokenizer
+                    .records(&mmap[..end])
+                    .next()
+                    .map(|record| Header::parse(&tokenizer, record));
+                (header, end)
+            }
+            false => (None, 0),
+        };
+
+        Ok(Input::Mapped {
+            mmap,
+            header,
+            data_start,
+        })
+    }
+
+    /// Reads from any reader, decompressing it if its magic bytes say so.
+    pub fn from_reader(
+        reader: Box<dyn Read + Send>,
+        tokenizer: Tokenizer,
+        has_header: bool,
+        block_size: usize,
+    ) -> Result<Self, Error> {
+        let reader = decompress(reader, Compression::None)?;
+        Self::stream(reader, tokenizer, has_header, block_size)
+    }
+
+    /// Wraps a sequential reader, consuming the header from it if requested.
+    pub fn stream(
+        reader: Box<dyn Read + Send>,
+        tokenizer: Tokenizer,
+        has_header: bool,
+        block_size: usize,
+    ) -> Result<Self, Error> {
+        let mut reader = BufReader::with_capacity(64 * 1024, reader);
+        if let Some(format) = ColumnarFormat::from_magic(reader.fill_buf()?) {
+            return Ok(Self::columnar(format.open_stream(Box::new(reader))?, None));
+        }
+
+        let mut reader = BlockReader::new(Box::new(reader), tokenizer, block_size);
+        let mut data_position = (0, 0);
+        let header = match has_header {
+            true => reader.next_record()?.and_then(|record| {
+                data_position = (record.len() as u64, count_lines(&record));
+                tokenizer
+                    .records(&record)
+                    .next()
+                    .map(|record| Header::parse(&tokenizer, record))
+            }),
+            false => None,
+        };
+        Ok(Input::Stream {
+            reader,
+            header,
+            data_position,
+        })
+    }
+
+    fn columnar(batches: Batches, size: Option<u64>) -> Self {
+        let header = Header::from_names(columnar::column_names(&batches));
+        Input::Columnar {
+            batches,
+            header: Some(header),
+            size,
+        }
+    }
+
+    pub fn header(&self) -> Option<&Header> {
+        match self {
+            Input::Mapped { header, .. } | Input::Stream { header, .. } | Input::Columnar { header, .. } => {
+                header.as_ref()
+            }
+        }
+    }
+
+    /// Byte offset and number of lines before the first data row.
+    pub fn data_position(&self) -> (u64, u64) {
+        match self {
+            Input::Mapped { mmap, data_start, .. } => (*data_start as u64, count_lines(&mmap[..*data_start])),
+            Input::Stream { data_position, .. } => *data_position,
+            Input::Columnar { .. } => (0, 0),
+        }
+    }
+
+    /// Size of the input in bytes, if known up front.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Input::Mapped { mmap, .. } => Some(mmap.len() as u64),
+            Input::Stream { .. } => None,
+            Input::Columnar { size, .. } => *size,
+        }
+    }
+}
+
+/// Peeks at the start of `reader` and wraps it in a decoder if the magic
+/// bytes (or failing that, `hint`) say it is compressed.
+pub fn decompress(reader: Box<dyn Read + Send>, hint: Compression) -> io::Result<Box<dyn Read + Send>> {
+    let mut reader = BufReader::with_capacity(64 * 1024, reader);
+    let compression = Compression::from_magic(reader.fill_buf()?).unwrap_or(hint);
+    compression.decoder(Box::new(reader))
+}
+
+/// Reads a stream in blocks of roughly `block_size` bytes that always end on
+/// a record boundary, so blocks can be processed independently in parallel.
+pub struct BlockReader {
+    reader: Box<dyn Read + Send>,
+    tokenizer: Tokenizer,
+    block_size: usize,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl BlockReader {
+    pub fn new(reader: Box<dyn Read + Send>, tokenizer: Tokenizer, block_size: usize) -> Self {
+        Self {
+            reader,
+            tokenizer,
+            block_size: block_size.max(1),
+            buf: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Reads the next full record including its terminator.
+    fn next_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.next_cut(|tokenizer, buf| tokenizer.record_end(buf, 0))
+    }
+
+    /// Reads the next record-aligned block.
+    pub fn next_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let block_size = self.block_size;
+        self.next_cut(|tokenizer, buf| tokenizer.find_boundary(buf, 0, block_size.min(buf.len())))
+    }
+
+    /// Splits off the front of the buffer up to the position returned by
+    /// `cut`, reading more data while the cut runs into the end of the buffer.
+    fn next_cut<F>(&mut self, cut: F) -> io::Result<Option<Vec<u8>>>
+    where
+        F: Fn(&Tokenizer, &[u8]) -> usize,
+    {
+        let mut want = self.block_size;
+        loop {
+            self.fill(want)?;
+            if self.buf.is_empty() {
+                return Ok(None);
+            }
+
+            let end = cut(&self.tokenizer, &self.buf);
+            if end < self.buf.len() || self.eof {
+                let rest = self.buf.split_off(end);
+                return Ok(Some(std::mem::replace(&mut self.buf, rest)));
+            }
+
+            // The last record continues past what has been read so far
+            want = self.buf.len() + self.block_size;
+        }
+    }
+
+    /// Reads until the buffer holds at least `len` bytes or the stream ends.
+    fn fill(&mut self, len: usize) -> io::Result<()> {
+        while !self.eof && self.buf.len() < len {
+            let want = (len - self.buf.len()) as u64;
+            let read = (&mut self.reader).take(want).read_to_end(&mut self.buf)?;
+            if (read as u64) < want {
+                self.eof = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for BlockReader {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_block().transpose()
+    }
+}
@@ -0,0 +1,970 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use arrow_array::RecordBatch;
+
+use crate::columnar::{BatchFields, StagedRow};
+use crate::error::Error;
+use crate::header::Column;
+use crate::input::{Input, Source};
+use crate::pipeline;
+use crate::rejects::{ChunkReport, Rejects};
+use crate::sort::{SortedChunk, Sorter};
+use crate::spill::{self, push_value, SpillDir, SpillReader, SpillWriter, Values};
+use crate::tokenizer::Tokenizer;
+use crate::writer::RowWriter;
+
+/// Approximate bookkeeping cost of a right row besides its bytes
+const ENTRY_OVERHEAD: usize = 48;
+/// Names the right side in the reasons of its malformed rows
+const RIGHT_INPUT: &str = "join input";
+
+/// Which rows a join outputs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JoinType {
+    /// Each input row once for every matching right row, with its columns
+    #[default]
+    Inner,
+    /// Like `Inner`, plus rows without a match with empty right columns
+    Left,
+    /// Input rows that have a match, once, without right columns
+    Semi,
+    /// Input rows that have no match
+    Anti,
+}
+
+impl FromStr for JoinType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inner" => Ok(JoinType::Inner),
+            "left" => Ok(JoinType::Left),
+            "semi" => Ok(JoinType::Semi),
+            "anti" => Ok(JoinType::Anti),
+            _ => Err(format!("unknown join type '{}' (expected inner, left, semi or anti)", s)),
+        }
+    }
+}
+
+impl fmt::Display for JoinType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JoinType::Inner => "inner",
+            JoinType::Left => "left",
+            JoinType::Semi => "semi",
+            JoinType::Anti => "anti",
+        })
+    }
+}
+
+/// A join of the output rows with a second input, the right side.
+pub struct Join {
+    pub source: Source,
+    /// Key columns among the output columns
+    pub on: Vec<Column>,
+    /// Key columns of the right side, pairwise with `on`. Empty means the
+    /// same as `on`.
+    pub right_on: Vec<Column>,
+    pub kind: JoinType,
+}
+
+impl Join {
+    /// An inner join with `source` on the output `columns`, which have the
+    /// same names or indices on the right side.
+    pub fn new<I>(source: impl Into<Source>, on: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Column>,
+    {
+        Self {
+            source: source.into(),
+            on: on.into_iter().map(Into::into).collect(),
+            right_on: Vec::new(),
+            kind: JoinType::default(),
+        }
+    }
+
+    /// Sets the key columns of the right side.
+    pub fn right_on<I>(mut self, columns: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Column>,
+    {
+        self.right_on = columns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn kind(mut self, kind: JoinType) -> Self {
+        self.kind = kind;
+        self
+    }
+}
+
+/// Hash table of the right side, keyed on the encoded key values. When the
+/// right side does not fit in the memory budget it is spilled to disk in
+/// hash partitions instead, and the output rows are partitioned the same
+/// way and joined one partition at a time (a grace hash join).
+///
+/// Inner and semi joins can build the table from the output rows instead,
+/// when they are the smaller side; the right side is then left unread
+/// until all output rows are collected, and streamed past them. Matching
+/// right rows beyond the budget are then joined right away and the joined
+/// rows sorted back into input order on disk.
+pub struct JoinTable {
+    kind: JoinType,
+    /// Output column and right column of each key
+    keys: Vec<(usize, usize)>,
+    /// Number of output columns before the join
+    left_width: usize,
+    /// Right columns added to the output
+    right_columns: Vec<usize>,
+    /// Names of the right columns added to the output
+    pub names: Vec<Vec<u8>>,
+    /// Non-key values of the right rows with each key
+    rows: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+    spill: Option<SpillDir>,
+    /// Hash partitions of the right side, taken by the spilled probe
+    partitions: Mutex<Vec<SpillReader>>,
+    /// The unread right side, when the table is built from the output rows
+    deferred: Mutex<Option<(Input, Tokenizer)>>,
+    build_left: bool,
+    budget: usize,
+    temp_dir: PathBuf,
+}
+
+impl JoinTable {
+    /// Reads the right side from `input`. `keys` pairs the output column
+    /// and the right column of each key; `left_width` is the number of
+    /// output columns. With `build_left` the right side is only read by
+    /// `LeftBuild::finish`, which needs its width from the header unless
+    /// the join is a semi join. Malformed right rows go to `rejects`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn build(
+        input: Input,
+        tokenizer: &Tokenizer,
+        kind: JoinType,
+        keys: Vec<(usize, usize)>,
+        left_width: usize,
+        budget: usize,
+        temp_dir: &Path,
+        build_left: bool,
+        rejects: &mut Rejects,
+    ) -> Result<Self, Error> {
+        let header = input.header().cloned();
+        let partition_count = spill::partition_count(input.size(), budget);
+        let is_key = |column: usize| keys.iter().any(|&(_, right)| right == column);
+        let with_values = matches!(kind, JoinType::Inner | JoinType::Left);
+
+        let mut rows: HashMap<Vec<u8>, Vec<Vec<u8>>> = HashMap::new();
+        let mut used = 0;
+        let mut spill: Option<(SpillDir, Vec<SpillWriter>)> = None;
+        let mut width = header.as_ref().map_or(0, |header| header.len());
+        let mut key = Vec::new();
+        let mut deferred = Some((input, *tokenizer));
+        if !build_left {
+            let (input, _) = deferred.take().unwrap();
+            for_each_row(input, tokenizer, rejects, |fields| {
+                width = width.max(fields.len());
+                let mut values = Vec::new();
+                encode_right(fields, &keys, with_values, &mut key, &mut values);
+
+                if let Some((_, partitions)) = &mut spill {
+                    let partition = (spill::hash_key(&key) % partitions.len() as u64) as usize;
+                    partitions[partition].write(&[&key, &values])?;
+                    return Ok(());
+                }
+                match rows.get_mut(&key) {
+                    // Semi and anti joins only need to know a key exists
+                    Some(_) if !with_values => {}
+                    Some(matches) => {
+                        used += values.len() + ENTRY_OVERHEAD;
+                        matches.push(values);
+                    }
+                    None => {
+                        used += key.len() + values.len() + ENTRY_OVERHEAD;
+                        rows.insert(key.clone(), vec![values]);
+                    }
+                }
+
+                // Move the table to disk once it outgrows the budget
+                if used > budget {
+                    let dir = SpillDir::new(temp_dir)?;
+                    let mut partitions = (0..partition_count).map(|_| dir.create()).collect::<Result<Vec<_>, _>>()?;
+                    for (key, matches) in rows.drain() {
+                        let partition = (spill::hash_key(&key) % partitions.len() as u64) as usize;
+                        for values in matches {
+                            partitions[partition].write(&[&key, &values])?;
+                        }
+                    }
+                    spill = Some((dir, partitions));
+                }
+                Ok(())
+            })?;
+        }
+
+        let right_columns: Vec<usize> = match with_values {
+            true => (0..width).filter(|&i| !is_key(i)).collect(),
+            false => Vec::new(),
+        };
+        let names = right_columns
+            .iter()
+            .enumerate()
+            .map(|(i, &column)| match header.as_ref().and_then(|h| h.name(column)) {
+                Some(name) => name.to_vec(),
+                None => format!("col{}", left_width + i).into_bytes(),
+            })
+            .collect();
+        let (spill, partitions) = match spill {
+            Some((dir, partitions)) => {
+                let readers = partitions.into_iter().map(SpillWriter::finish).collect::<Result<_, _>>()?;
+                (Some(dir), readers)
+            }
+            None => (None, Vec::new()),
+        };
+
+        Ok(Self {
+            kind,
+            keys,
+            left_width,
+            right_columns,
+            names,
+            rows,
+            spill,
+            partitions: Mutex::new(partitions),
+            deferred: Mutex::new(deferred),
+            build_left,
+            budget,
+            temp_dir: temp_dir.to_path_buf(),
+        })
+    }
+
+    pub fn kind(&self) -> JoinType {
+        self.kind
+    }
+
+    /// Whether the right side was spilled to disk.
+    pub fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    /// Whether the table is built from the output rows.
+    pub fn is_built_from_left(&self) -> bool {
+        self.build_left
+    }
+
+    /// Whether output rows are collected with their keys to be joined at
+    /// the end, rather than joined as they are processed.
+    pub fn probes_later(&self) -> bool {
+        self.is_spilled() || self.build_left
+    }
+
+    /// Encodes the key of an output row.
+    pub fn left_key(&self, row: &StagedRow, key: &mut Vec<u8>) {
+        let mut values = vec![&[][..]; self.left_width];
+        for (column, value) in row.iter() {
+            values[column] = value;
+        }
+        for &(left, _) in &self.keys {
+            push_value(key, values[left]);
+        }
+    }
+
+    /// Right rows matching `key`, if the table is in memory.
+    pub fn matches(&self, key: &[u8]) -> Option<&[Vec<u8>]> {
+        self.rows.get(key).map(Vec::as_slice)
+    }
+
+    /// Writes the joined rows of output row `left`, given its matching
+    /// right rows, and returns how many were written.
+    pub fn write_joined(
+        &self,
+        matches: Option<&[Vec<u8>]>,
+        left: &StagedRow,
+        rows: &RowWriter,
+        out: &mut Vec<u8>,
+        first: bool,
+    ) -> usize {
+        let matches: Vec<Option<&[u8]>> = match (self.kind, matches) {
+            (JoinType::Inner | JoinType::Left, Some(matches)) => matches.iter().map(|m| Some(&m[..])).collect(),
+            (JoinType::Left, None) | (JoinType::Semi, Some(_)) | (JoinType::Anti, None) => vec![None],
+            _ => Vec::new(),
+        };
+
+        for (i, right) in matches.iter().enumerate() {
+            rows.start_row(out, first && i == 0);
+            let start = out.len();
+            let mut position = 0;
+            for (column, value) in left.iter() {
+                rows.write_value(out, column, position, value);
+                position += 1;
+            }
+            let mut values = Values(right.unwrap_or_default());
+            for i in 0..self.right_columns.len() {
+                let value = values.next().unwrap_or_default();
+                rows.write_value(out, self.left_width + i, position, value);
+                position += 1;
+            }
+            rows.end_row(out, start, position);
+        }
+        matches.len()
+    }
+
+    /// Starts collecting the output rows to build the table from. The
+    /// right side is later read in chunks of `chunk_size` bytes, at most
+    /// `max_in_flight` at a time.
+    pub fn left_build(&self, chunk_size: usize, max_in_flight: usize) -> LeftBuild<'_> {
+        LeftBuild {
+            table: self,
+            chunk_size,
+            max_in_flight,
+            keys: HashMap::new(),
+            rows: Vec::new(),
+            used: 0,
+        }
+    }
+
+    /// Starts joining output rows against the spilled right side.
+    pub fn spilled_probe(&self) -> Result<SpilledProbe<'_>, Error> {
+        let dir = self.spill.as_ref().expect("the right side was spilled");
+        let count = self.partitions.lock().unwrap().len();
+        Ok(SpilledProbe {
+            table: self,
+            seq: 0,
+            partitions: (0..count).map(|_| dir.create()).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Output rows kept in memory with their keys, in input order, when they
+/// are the build side of the join.
+pub struct LeftBuild<'a> {
+    table: &'a JoinTable,
+    chunk_size: usize,
+    max_in_flight: usize,
+    /// Index of each distinct key of the output rows
+    keys: HashMap<Vec<u8>, usize>,
+    /// Key index and encoded row of each output row
+    rows: Vec<(usize, Vec<u8>)>,
+    /// Approximate bytes held by the output rows
+    used: usize,
+}
+
+impl LeftBuild<'_> {
+    /// Adds the next output row, encoded with `encode_row`.
+    pub fn push(&mut self, key: Vec<u8>, row: Vec<u8>) {
+        self.used += row.len() + ENTRY_OVERHEAD;
+        let index = match self.keys.get(&key) {
+            Some(&index) => index,
+            None => {
+                let index = self.keys.len();
+                self.used += key.len() + ENTRY_OVERHEAD;
+                self.keys.insert(key, index);
+                index
+            }
+        };
+        self.rows.push((index, row));
+    }
+
+    /// Streams the right side past the collected keys, matching its chunks
+    /// on the worker threads, and emits the joined rows in input order.
+    /// Returns the number of rows written and of rows without output.
+    pub fn finish(
+        self,
+        rows: &RowWriter,
+        rejects: &mut Rejects,
+        emit: &mut impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(usize, usize), Error> {
+        let (input, tokenizer) = self.table.deferred.lock().unwrap().take().expect("the right side is unread");
+        let mut matched = Matched::new(self.table, &self.rows, rows, self.keys.len(), self.used, self.chunk_size);
+        let mut position = input.data_position();
+        match input {
+            Input::Mapped { mmap, data_start, .. } => {
+                let chunks = tokenizer.chunks(&mmap[data_start..], self.chunk_size);
+                let chunks = chunks.map(|chunk| Ok(RightChunk::Text(Cow::Borrowed(chunk))));
+                self.probe(chunks, &tokenizer, rejects, &mut position, &mut matched)?;
+            }
+            Input::Stream { reader, .. } => {
+                let chunks = reader.map(|block| Ok(RightChunk::Text(Cow::Owned(block?))));
+                self.probe(chunks, &tokenizer, rejects, &mut position, &mut matched)?;
+            }
+            Input::Columnar { batches, .. } => {
+                let chunks = batches.map(|batch| Ok(RightChunk::Batch(batch?)));
+                self.probe(chunks, &tokenizer, rejects, &mut position, &mut matched)?;
+            }
+        }
+        matched.finish(emit)
+    }
+
+    /// Looks up the keys of the right rows on the worker threads and hands
+    /// the matching rows to `matched` in input order.
+    fn probe<'c>(
+        &self,
+        chunks: impl Iterator<Item = Result<RightChunk<'c>, Error>> + Send,
+        tokenizer: &Tokenizer,
+        rejects: &mut Rejects,
+        position: &mut (u64, u64),
+        matched: &mut Matched,
+    ) -> Result<(), Error> {
+        let with_values = self.table.kind == JoinType::Inner;
+        let detailed = rejects.detailed();
+        pipeline::run_ordered(
+            chunks,
+            self.max_in_flight,
+            |chunk| {
+                let mut found = Vec::new();
+                let mut key = Vec::new();
+                let mut f = |fields: &[Cow<[u8]>]| -> Result<(), Error> {
+                    let mut values = Vec::new();
+                    encode_right(fields, &self.table.keys, with_values, &mut key, &mut values);
+                    if let Some(&index) = self.keys.get(&key) {
+                        found.push((index, values));
+                    }
+                    Ok(())
+                };
+                let report = match chunk? {
+                    RightChunk::Text(data) => Some(scan(tokenizer, &data, detailed, &mut f)?),
+                    RightChunk::Batch(batch) => {
+                        batch_rows(&batch, &mut f)?;
+                        None
+                    }
+                };
+                Ok((found, report))
+            },
+            |chunk: Result<ChunkMatches, Error>| {
+                let (found, report) = chunk?;
+                if let Some(report) = report {
+                    rejects.handle_input(&report, position, Some(RIGHT_INPUT))?;
+                }
+                matched.add(found)
+            },
+        )
+    }
+}
+
+/// A chunk of the right side, matched against the output rows on a worker
+/// thread.
+enum RightChunk<'a> {
+    Text(Cow<'a, [u8]>),
+    Batch(RecordBatch),
+}
+
+/// Key index and right values of the matching rows of a chunk, with the
+/// report of its malformed rows if it is text.
+type ChunkMatches = (Vec<(usize, Vec<u8>)>, Option<ChunkReport>);
+
+/// Right rows matching the output rows of a `LeftBuild`, in input order.
+/// Their values are kept by key until they outgrow the budget; an inner
+/// join then joins them, and every later match, right away and sorts the
+/// joined rows by the position of their output row.
+struct Matched<'a> {
+    table: &'a JoinTable,
+    /// Key index and encoded row of each output row
+    left: &'a [(usize, Vec<u8>)],
+    rows: &'a RowWriter,
+    chunk_size: usize,
+    /// Whether each key has a match
+    found: Vec<bool>,
+    /// Right values matching each key, while they are kept by key
+    values: Vec<Vec<Vec<u8>>>,
+    /// Approximate bytes held by the output rows and the values
+    used: usize,
+    /// Budget left for the sorted joined rows
+    sort_budget: usize,
+    sorted: Option<SortedJoin<'a>>,
+}
+
+impl<'a> Matched<'a> {
+    fn new(
+        table: &'a JoinTable,
+        left: &'a [(usize, Vec<u8>)],
+        rows: &'a RowWriter,
+        keys: usize,
+        used: usize,
+        chunk_size: usize,
+    ) -> Self {
+        Self {
+            table,
+            left,
+            rows,
+            chunk_size,
+            found: vec![false; keys],
+            values: vec![Vec::new(); keys],
+            used,
+            sort_budget: table.budget.saturating_sub(used),
+            sorted: None,
+        }
+    }
+
+    /// Adds the next matches, as key index and right values.
+    fn add(&mut self, found: Vec<(usize, Vec<u8>)>) -> Result<(), Error> {
+        // A semi join only needs to know a key exists
+        let with_values = self.table.kind == JoinType::Inner;
+        for (key, values) in found {
+            self.found[key] = true;
+            match &mut self.sorted {
+                Some(sorted) => sorted.push(self.table, self.left, self.rows, key, std::slice::from_ref(&values))?,
+                None if with_values => {
+                    self.used += values.len() + ENTRY_OVERHEAD;
+                    self.values[key].push(values);
+                }
+                None => {}
+            }
+        }
+        if with_values && self.sorted.is_none() && self.used > self.table.budget {
+            self.sort()?;
+        }
+        Ok(())
+    }
+
+    /// Joins the values kept so far and starts sorting the joined rows.
+    fn sort(&mut self) -> Result<(), Error> {
+        let mut positions = vec![Vec::new(); self.values.len()];
+        for (position, &(key, _)) in self.left.iter().enumerate() {
+            positions[key].push(position);
+        }
+        let mut sorted = SortedJoin {
+            sorter: Sorter::new(self.sort_budget, &self.table.temp_dir),
+            positions,
+            chunk_size: self.chunk_size,
+            text: Vec::new(),
+            keys: Vec::new(),
+            ends: Vec::new(),
+            row: StagedRow::default(),
+            written: 0,
+        };
+        for (key, values) in std::mem::take(&mut self.values).into_iter().enumerate() {
+            sorted.push(self.table, self.left, self.rows, key, &values)?;
+        }
+        self.sorted = Some(sorted);
+        Ok(())
+    }
+
+    /// Emits the joined rows in input order. Returns the number of rows
+    /// written and of rows without output.
+    fn finish(self, emit: &mut impl FnMut(&[u8]) -> Result<(), Error>) -> Result<(usize, usize), Error> {
+        let unmatched = self.left.iter().filter(|&&(key, _)| !self.found[key]).count();
+        if let Some(sorted) = self.sorted {
+            return Ok((sorted.finish(emit)?, unmatched));
+        }
+
+        let mut written = 0;
+        let mut row = StagedRow::default();
+        let mut out = Vec::new();
+        for &(key, ref encoded) in self.left {
+            if !self.found[key] {
+                continue;
+            }
+            decode_row(encoded, &mut row);
+            out.clear();
+            written += self.table.write_joined(Some(&self.values[key]), &row, self.rows, &mut out, true);
+            emit(&out)?;
+        }
+        Ok((written, unmatched))
+    }
+}
+
+/// Joined rows of an inner join, sorted on disk by the position of their
+/// output row. Rows of the same output row keep the order of their right
+/// rows.
+struct SortedJoin<'a> {
+    sorter: Sorter<'a>,
+    /// Positions of the output rows with each key
+    positions: Vec<Vec<usize>>,
+    chunk_size: usize,
+    /// Joined rows not yet handed to the sorter, with their positions as
+    /// sort keys and the end offsets of both
+    text: Vec<u8>,
+    keys: Vec<u8>,
+    ends: Vec<(usize, usize)>,
+    row: StagedRow,
+    written: usize,
+}
+
+impl SortedJoin<'_> {
+    /// Joins every output row with key `key` with the right `values`.
+    fn push(
+        &mut self,
+        table: &JoinTable,
+        left: &[(usize, Vec<u8>)],
+        rows: &RowWriter,
+        key: usize,
+        values: &[Vec<u8>],
+    ) -> Result<(), Error> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        for &position in &self.positions[key] {
+            decode_row(&left[position].1, &mut self.row);
+            self.written += table.write_joined(Some(values), &self.row, rows, &mut self.text, true);
+            self.keys.extend_from_slice(&(position as u64).to_be_bytes());
+            self.ends.push((self.text.len(), self.keys.len()));
+        }
+        if self.text.len() >= self.chunk_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.ends.is_empty() {
+            let text = std::mem::take(&mut self.text);
+            let chunk = SortedChunk::with_keys(text, std::mem::take(&mut self.keys), &self.ends);
+            self.ends.clear();
+            self.sorter.push(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Emits the joined rows in input order and returns how many there are.
+    fn finish(mut self, emit: &mut impl FnMut(&[u8]) -> Result<(), Error>) -> Result<usize, Error> {
+        self.flush()?;
+        self.sorter.finish(emit)?;
+        Ok(self.written)
+    }
+}
+
+/// Output rows partitioned like the spilled right side, in input order.
+pub struct SpilledProbe<'a> {
+    table: &'a JoinTable,
+    /// Position of the next output row
+    seq: u64,
+    partitions: Vec<SpillWriter>,
+}
+
+impl SpilledProbe<'_> {
+    /// Adds the next output row, encoded with `encode_row`.
+    pub fn push(&mut self, key: &[u8], row: &[u8]) -> Result<(), Error> {
+        let partition = (spill::hash_key(key) % self.partitions.len() as u64) as usize;
+        self.partitions[partition].write(&[&self.seq.to_le_bytes(), key, row])?;
+        self.seq += 1;
+        Ok(())
+    }
+
+    /// Joins each partition in memory and emits the joined rows in input
+    /// order. Returns the number of rows written and of rows without output.
+    pub fn finish(
+        self,
+        rows: &RowWriter,
+        emit: &mut impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(usize, usize), Error> {
+        let table = self.table;
+        let dir = table.spill.as_ref().expect("the right side was spilled");
+        let right = std::mem::take(&mut *table.partitions.lock().unwrap());
+        let (mut written, mut unmatched) = (0, 0);
+        let mut runs = Vec::new();
+        let mut row = StagedRow::default();
+        let mut out = Vec::new();
+        for (mut right, left) in right.into_iter().zip(self.partitions) {
+            let mut matches: HashMap<Vec<u8>, Vec<Vec<u8>>> = HashMap::new();
+            let mut fields = [Vec::new(), Vec::new()];
+            while right.read(&mut fields)? {
+                let values = std::mem::take(&mut fields[1]);
+                matches.entry(std::mem::take(&mut fields[0])).or_default().push(values);
+            }
+
+            // Left partitions are written in input order, so their joined
+            // rows are too
+            let mut left = left.finish()?;
+            let mut run = dir.create()?;
+            let mut fields = [Vec::new(), Vec::new(), Vec::new()];
+            while left.read(&mut fields)? {
+                decode_row(&fields[2], &mut row);
+                out.clear();
+                match table.write_joined(matches.get(&fields[1]).map(Vec::as_slice), &row, rows, &mut out, true) {
+                    0 => unmatched += 1,
+                    n => {
+                        written += n;
+                        run.write(&[&fields[0], &out])?;
+                    }
+                }
+            }
+            runs.push(run.finish()?);
+        }
+
+        spill::merge_by_position(runs, emit)?;
+        Ok((written, unmatched))
+    }
+}
+
+/// Encodes an output row with the column of each value.
+pub fn encode_row(row: &StagedRow) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (column, value) in row.iter() {
+        out.extend_from_slice(&(column as u32).to_le_bytes());
+        push_value(&mut out, value);
+    }
+    out
+}
+
+fn decode_row(mut data: &[u8], row: &mut StagedRow) {
+    row.clear();
+    while data.len() >= 4 {
+        let column = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        let mut values = Values(&data[4..]);
+        let value = values.next().unwrap_or_default();
+        row.push(column, value);
+        data = values.0;
+    }
+}
+
+/// Encodes the key of a right row and, `with_values`, its other values.
+fn encode_right(
+    fields: &[Cow<[u8]>],
+    keys: &[(usize, usize)],
+    with_values: bool,
+    key: &mut Vec<u8>,
+    values: &mut Vec<u8>,
+) {
+    key.clear();
+    for &(_, right) in keys {
+        push_value(key, fields.get(right).map_or(&[][..], |f| f.as_ref()));
+    }
+    if with_values {
+        for (i, field) in fields.iter().enumerate() {
+            if !keys.iter().any(|&(_, right)| right == i) {
+                push_value(values, field);
+            }
+        }
+    }
+}
+
+/// Calls `f` with the fields of every row of `input`. Malformed rows are
+/// skipped and handed to `rejects`.
+fn for_each_row(
+    input: Input,
+    tokenizer: &Tokenizer,
+    rejects: &mut Rejects,
+    mut f: impl FnMut(&[Cow<[u8]>]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut position = input.data_position();
+    match input {
+        Input::Mapped { mmap, data_start, .. } => {
+            records(tokenizer, &mmap[data_start..], rejects, &mut position, &mut f)?
+        }
+        Input::Stream { reader, .. } => {
+            for block in reader {
+                records(tokenizer, &block?, rejects, &mut position, &mut f)?;
+            }
+        }
+        Input::Columnar { batches, .. } => {
+            for batch in batches {
+                batch_rows(&batch?, &mut f)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Calls `f` with the fields of every row of `batch`.
+fn batch_rows(batch: &RecordBatch, f: &mut impl FnMut(&[Cow<[u8]>]) -> Result<(), Error>) -> Result<(), Error> {
+    let mut batch = BatchFields::new(batch)?;
+    let mut fields = Vec::new();
+    for row in 0..batch.num_rows() {
+        batch.read_row(row, &mut fields);
+        f(&fields)?;
+    }
+    Ok(())
+}
+
+/// Calls `f` with the fields of every row of one chunk of text starting
+/// at `position`.
+fn records(
+    tokenizer: &Tokenizer,
+    data: &[u8],
+    rejects: &mut Rejects,
+    position: &mut (u64, u64),
+    f: &mut impl FnMut(&[Cow<[u8]>]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let report = scan(tokenizer, data, rejects.detailed(), f)?;
+    rejects.handle_input(&report, position, Some(RIGHT_INPUT))
+}
+
+/// Calls `f` with the fields of every row of one chunk of text, and
+/// returns the report of its malformed rows.
+fn scan(
+    tokenizer: &Tokenizer,
+    data: &[u8],
+    detailed: bool,
+    f: &mut impl FnMut(&[Cow<[u8]>]) -> Result<(), Error>,
+) -> Result<ChunkReport, Error> {
+    let mut report = ChunkReport::default();
+    let mut fields = Vec::new();
+    for record in tokenizer.records(data) {
+        if record.is_empty() {
+            continue;
+        }
+        fields.clear();
+        match tokenizer.split_fields(record, &mut fields) {
+            None => f(&fields)?,
+            Some((column, kind)) => {
+                // Records are subslices of the chunk
+                let offset = record.as_ptr() as usize - data.as_ptr() as usize;
+                report.reject_text(data, offset, record, Some(column), kind, detailed);
+            }
+        }
+    }
+    report.finish_text(data, detailed);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::error::OnError;
+    use crate::writer::OutputFormat;
+
+    /// Right side with a repeated key, a key no output row has and a
+    /// malformed row.
+    const RIGHT: &str = "cid,country\n1,France\n2,Spain\n1,Gaul\n9,Peru\n3,\"Ita\"ly\n";
+    /// Output rows of `id,cid`
+    const LEFT: [[&str; 2]; 5] = [["a", "1"], ["b", "3"], ["c", "2"], ["d", "1"], ["e", "7"]];
+
+    /// Builds the table over `RIGHT`, returning it with the rejects its
+    /// malformed row goes to.
+    fn table(kind: JoinType, budget: usize, build_left: bool) -> (JoinTable, Rejects) {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), None);
+        let reader = Box::new(Cursor::new(RIGHT.as_bytes().to_vec()));
+        let input = Input::from_reader(reader, tokenizer, true, 16).unwrap();
+        let mut rejects = Rejects::new(OnError::Skip, None, input.data_position()).unwrap();
+        let temp_dir = std::env::temp_dir();
+        let table =
+            JoinTable::build(input, &tokenizer, kind, vec![(1, 0)], 2, budget, &temp_dir, build_left, &mut rejects)
+                .unwrap();
+        (table, rejects)
+    }
+
+    fn left_rows() -> Vec<StagedRow> {
+        LEFT.iter()
+            .map(|values| {
+                let mut row = StagedRow::default();
+                for (column, value) in values.iter().enumerate() {
+                    row.push(column, value.as_bytes());
+                }
+                row
+            })
+            .collect()
+    }
+
+    fn row_writer(table: &JoinTable) -> RowWriter {
+        let mut names = vec![b"id".to_vec(), b"cid".to_vec()];
+        names.extend(table.names.iter().cloned());
+        RowWriter::new(OutputFormat::default(), names, false)
+    }
+
+    /// Joins the output rows the way the engine does for the table, and
+    /// returns the joined rows with the number of rows without output.
+    fn join(table: &JoinTable, rejects: &mut Rejects) -> (String, usize) {
+        let rows = row_writer(table);
+        let mut out = Vec::new();
+        let mut key = Vec::new();
+        let mut emit = |row: &[u8]| {
+            out.extend_from_slice(row);
+            Ok(())
+        };
+        let unmatched = if table.is_spilled() {
+            let mut probe = table.spilled_probe().unwrap();
+            for row in left_rows() {
+                key.clear();
+                table.left_key(&row, &mut key);
+                probe.push(&key, &encode_row(&row)).unwrap();
+            }
+            probe.finish(&rows, &mut emit).unwrap().1
+        } else if table.is_built_from_left() {
+            let mut build = table.left_build(16, 4);
+            for row in left_rows() {
+                key.clear();
+                table.left_key(&row, &mut key);
+                build.push(key.clone(), encode_row(&row));
+            }
+            build.finish(&rows, rejects, &mut emit).unwrap().1
+        } else {
+            let mut unmatched = 0;
+            for row in left_rows() {
+                key.clear();
+                table.left_key(&row, &mut key);
+                let mut joined = Vec::new();
+                match table.write_joined(table.matches(&key), &row, &rows, &mut joined, true) {
+                    0 => unmatched += 1,
+                    _ => emit(&joined).unwrap(),
+                }
+            }
+            unmatched
+        };
+        (String::from_utf8(out).unwrap(), unmatched)
+    }
+
+    fn expected(kind: JoinType) -> (&'static str, usize) {
+        match kind {
+            JoinType::Inner => ("a,1,France\na,1,Gaul\nc,2,Spain\nd,1,France\nd,1,Gaul\n", 2),
+            JoinType::Left => ("a,1,France\na,1,Gaul\nb,3,\nc,2,Spain\nd,1,France\nd,1,Gaul\ne,7,\n", 0),
+            JoinType::Semi => ("a,1\nc,2\nd,1\n", 2),
+            JoinType::Anti => ("b,3\ne,7\n", 3),
+        }
+    }
+
+    #[test]
+    fn joins_in_memory() {
+        for kind in [JoinType::Inner, JoinType::Left, JoinType::Semi, JoinType::Anti] {
+            let (table, mut rejects) = table(kind, usize::MAX, false);
+            assert!(!table.probes_later());
+            let (out, unmatched) = join(&table, &mut rejects);
+            assert_eq!((out.as_str(), unmatched), expected(kind), "{}", kind);
+            assert_eq!(rejects.finish().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn joins_spilled_partitions_in_input_order() {
+        for kind in [JoinType::Inner, JoinType::Left, JoinType::Semi, JoinType::Anti] {
+            let (table, mut rejects) = table(kind, 0, false);
+            assert!(table.is_spilled());
+            let (out, unmatched) = join(&table, &mut rejects);
+            assert_eq!((out.as_str(), unmatched), expected(kind), "{}", kind);
+            assert_eq!(rejects.finish().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn builds_the_table_from_the_output_rows() {
+        for kind in [JoinType::Inner, JoinType::Semi] {
+            let (table, mut rejects) = table(kind, usize::MAX, true);
+            assert!(table.is_built_from_left());
+            // The right side is only read, and its rows rejected, at the end
+            assert_eq!(rejects.finish().unwrap(), 0);
+            let (out, unmatched) = join(&table, &mut rejects);
+            assert_eq!((out.as_str(), unmatched), expected(kind), "{}", kind);
+            assert_eq!(rejects.finish().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn sorts_matches_beyond_the_budget_into_input_order() {
+        // Several threads match the right chunks out of order
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for kind in [JoinType::Inner, JoinType::Semi] {
+            let (table, mut rejects) = table(kind, 0, true);
+            assert!(table.is_built_from_left() && !table.is_spilled());
+            let (out, unmatched) = pool.install(|| join(&table, &mut rejects));
+            assert_eq!((out.as_str(), unmatched), expected(kind), "{}", kind);
+            assert_eq!(rejects.finish().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn encodes_rows_with_their_columns() {
+        let mut row = StagedRow::default();
+        row.push(0, b"x");
+        row.push(3, b"");
+        row.push(1, b"y,z");
+        let mut decoded = StagedRow::default();
+        decode_row(&encode_row(&row), &mut decoded);
+        assert_eq!(decoded.iter().collect::<Vec<_>>(), row.iter().collect::<Vec<_>>());
+    }
+}
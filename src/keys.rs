@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::compression::Compression;
+use crate::error::Error;
+use crate::input;
+use crate::spill::hash_key;
+
+/// Largest share of occupied slots in an exact set before it grows
+const MAX_LOAD: f64 = 0.7;
+
+/// Keys loaded from a file with one key per line, for `--include-keys` and
+/// `--exclude-keys`.
+#[derive(Debug)]
+pub enum KeySet {
+    Exact(ExactSet),
+    /// Uses a fraction of the memory of an exact set, but also contains
+    /// some keys that were not listed
+    Bloom(BloomFilter),
+}
+
+impl KeySet {
+    /// Loads the keys of `path`, which may be compressed. Empty lines are
+    /// skipped and line endings are not part of the keys. With a
+    /// `false_positive_rate` the keys go into a Bloom filter sized for it.
+    pub fn load(path: &Path, false_positive_rate: Option<f64>) -> Result<Self, Error> {
+        match false_positive_rate {
+            None => {
+                let mut set = ExactSet::default();
+                for_each_key(path, |key| set.insert(key))?;
+                Ok(KeySet::Exact(set))
+            }
+            Some(rate) if rate > 0.0 && rate < 1.0 => {
+                // A Bloom filter is sized up front, so the keys are counted first
+                let mut count = 0;
+                for_each_key(path, |_| count += 1)?;
+                let mut filter = BloomFilter::new(count, rate);
+                for_each_key(path, |key| filter.insert(key))?;
+                Ok(KeySet::Bloom(filter))
+            }
+            Some(rate) => Err(Error::Config(format!(
+                "false positive rate must be between 0 and 1, got {}",
+                rate
+            ))),
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        match self {
+            KeySet::Exact(set) => set.contains(key),
+            KeySet::Bloom(filter) => filter.contains(key),
+        }
+    }
+}
+
+/// Calls `f` with every non-empty line of `path`.
+fn for_each_key(path: &Path, mut f: impl FnMut(&[u8])) -> Result<(), Error> {
+    let file = File::open(path)?;
+    let mut reader = BufReader::new(input::decompress(Box::new(file), Compression::from_path(path))?);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        let mut key = &line[..];
+        if let Some(rest) = key.strip_suffix(b"\n") {
+            key = rest;
+        }
+        if let Some(rest) = key.strip_suffix(b"\r") {
+            key = rest;
+        }
+        if !key.is_empty() {
+            f(key);
+        }
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Hash set of byte strings stored back to back in one buffer, with an
+/// open-addressing table of offsets into it. This avoids an allocation
+/// and a pointer per key, which dominate for short keys.
+#[derive(Debug, Default)]
+pub struct ExactSet {
+    /// Keys, each prefixed with its length
+    data: Vec<u8>,
+    /// Offset of a key in `data` plus one, or 0 for an empty slot. The
+    /// length is a power of two.
+    slots: Vec<u64>,
+    len: usize,
+}
+
+impl ExactSet {
+    fn insert(&mut self, key: &[u8]) {
+        if (self.len + 1) as f64 > self.slots.len() as f64 * MAX_LOAD {
+            self.grow();
+        }
+        let slot = self.find(key);
+        if self.slots[slot] == 0 {
+            self.slots[slot] = self.data.len() as u64 + 1;
+            self.data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            self.data.extend_from_slice(key);
+            self.len += 1;
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        !self.slots.is_empty() && self.slots[self.find(key)] != 0
+    }
+
+    /// Slot holding `key`, or the empty slot where it belongs.
+    fn find(&self, key: &[u8]) -> usize {
+        let mask = self.slots.len() - 1;
+        let mut slot = hash_key(key) as usize & mask;
+        while self.slots[slot] != 0 && self.get(self.slots[slot]) != key {
+            slot = (slot + 1) & mask;
+        }
+        slot
+    }
+
+    fn get(&self, entry: u64) -> &[u8] {
+        let start = entry as usize - 1;
+        let len = u32::from_le_bytes(self.data[start..start + 4].try_into().unwrap()) as usize;
+        &self.data[start + 4..start + 4 + len]
+    }
+
+    fn grow(&mut self) {
+        let len = (self.slots.len() * 2).max(16);
+        let old = std::mem::replace(&mut self.slots, vec![0; len]);
+        for entry in old.into_iter().filter(|&entry| entry != 0) {
+            let slot = self.find(self.get(entry));
+            self.slots[slot] = entry;
+        }
+    }
+}
+
+/// Bloom filter with the number of bits and hashes chosen for a target
+/// false positive rate at a known number of keys.
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    fn new(keys: usize, false_positive_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(keys.max(1) as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = (bits / keys.max(1) as f64 * ln2).round().clamp(1.0, 32.0) as u32;
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(64)],
+            hashes,
+        }
+    }
+
+    /// Bit positions of `key`, derived from two hashes
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let h1 = hash_key(key);
+        // A second hash from mixing the first (splitmix64)
+        let mut h2 = h1.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        h2 = (h2 ^ (h2 >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        h2 = (h2 ^ (h2 >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        h2 ^= h2 >> 31;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        for position in self.positions(key) {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        self.positions(key).all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a key file named after the test.
+    fn key_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("pulsecsv-keys-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn finds_keys_across_resizes() {
+        let mut set = ExactSet::default();
+        assert!(!set.contains(b"0"));
+        for i in 0..5000 {
+            set.insert(format!("key{}", i).as_bytes());
+            // Every key inserted so far survives each resize
+            if i % 997 == 0 {
+                assert!((0..=i).all(|j| set.contains(format!("key{}", j).as_bytes())), "after {} keys", i + 1);
+            }
+        }
+        assert_eq!(set.len, 5000);
+        assert!(set.slots.len() as f64 * MAX_LOAD >= 5000.0);
+        assert!((0..5000).all(|i| set.contains(format!("key{}", i).as_bytes())));
+        assert!((5000..10000).all(|i| !set.contains(format!("key{}", i).as_bytes())));
+        assert!(!set.contains(b"key") && !set.contains(b""));
+    }
+
+    #[test]
+    fn stores_duplicate_keys_once() {
+        let path = key_file("duplicates", "a\nb\na\na\nb\n");
+        let KeySet::Exact(set) = KeySet::load(&path, None).unwrap() else {
+            panic!("expected an exact set");
+        };
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(set.len, 2);
+        assert_eq!(set.data.len(), 2 * (4 + 1));
+        assert!(set.contains(b"a") && set.contains(b"b"));
+    }
+
+    #[test]
+    fn loads_an_empty_key_file() {
+        let path = key_file("empty", "");
+        for rate in [None, Some(0.01)] {
+            let keys = KeySet::load(&path, rate).unwrap();
+            assert!(!keys.contains(b"") && !keys.contains(b"a"), "{:?}", rate);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn strips_crlf_line_endings() {
+        let path = key_file("crlf", "a\r\nb\r\n\r\nc\rd\r\ne");
+        for rate in [None, Some(0.001)] {
+            let keys = KeySet::load(&path, rate).unwrap();
+            for key in ["a", "b", "c\rd", "e"] {
+                assert!(keys.contains(key.as_bytes()), "{} with {:?}", key, rate);
+            }
+            if let KeySet::Exact(set) = &keys {
+                assert_eq!(set.len, 4);
+                assert!(!set.contains(b"a\r") && !set.contains(b""));
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bloom_filter_keeps_its_false_positive_rate() {
+        for rate in [0.1, 0.01, 0.001] {
+            let mut filter = BloomFilter::new(10_000, rate);
+            for i in 0..10_000 {
+                filter.insert(format!("key{}", i).as_bytes());
+            }
+            assert!((0..10_000).all(|i| filter.contains(format!("key{}", i).as_bytes())));
+            let trials = 200_000;
+            let false_positives = (0..trials).filter(|i| filter.contains(format!("other{}", i).as_bytes())).count();
+            let measured = false_positives as f64 / trials as f64;
+            assert!(measured < rate * 1.5, "measured {} for a rate of {}", measured, rate);
+        }
+    }
+}
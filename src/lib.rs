@@ -0,0 +1,65 @@
+//! Parallel CSV processing engine behind the `pulsecsv` command.
+//!
+//! A [`CsvProcessor`] reads CSV (or Parquet and Arrow IPC) from any
+//! [`Source`], filters and projects rows on all cores and writes CSV, JSON
+//! or Parquet to any [`Sink`], keeping the input order:
+//!
+//! ```no_run
+//! use pulsecsv::{CsvProcessor, Sink, Source};
+//!
+//! let input = std::fs::File::open("users.csv")?;
+//! let stats = CsvProcessor::builder()
+//!     .select(["user_id", "email"])
+//!     .filter("email LIKE '%@example.com'")
+//!     .sink(Sink::writer(std::io::stdout()))
+//!     .build()?
+//!     .process(Source::reader(input))?;
+//! eprintln!("kept {} rows", stats.rows_written);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! For custom per-row work, [`RecordReader`] reads borrowed [`Record`]s
+//! from a memory-mapped file, sequentially or in parallel.
+
+mod columnar;
+mod compression;
+mod dedup;
+mod error;
+mod expr;
+mod filter;
+mod groupby;
+mod header;
+mod input;
+mod join;
+mod keys;
+mod output;
+mod partition;
+mod pipeline;
+mod plan;
+mod processor;
+mod profile;
+mod progress;
+mod records;
+mod rejects;
+mod select;
+mod sort;
+mod spill;
+mod tokenizer;
+mod writer;
+
+pub use columnar::{ColumnType, ParquetCompression, ParquetOptions};
+pub use compression::Compression;
+pub use dedup::Keep;
+pub use error::{Error, OnError, ParseError, ParseErrorKind};
+pub use groupby::{Aggregate, Function};
+pub use header::{Column, Header};
+pub use input::Source;
+pub use join::{Join, JoinType};
+pub use output::Sink;
+pub use processor::{CsvProcessor, CsvProcessorBuilder, ProcessStats, ThreadStats};
+pub use profile::{ColumnStats, NumericStats, Profile, DEFAULT_TOP_K};
+pub use progress::Progress;
+pub use records::{Record, RecordReader, RecordReaderBuilder, Records};
+pub use select::{EmptyPolicy, ShortRows};
+pub use sort::{Compare, SortKey};
+pub use writer::{CsvFormat, Format, LineEnding, OutputFormat, QuoteStyle};
@@ -1,45 +1,372 @@
-use clap::Parser;
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand};
+use std::ffi::OsString;
 use std::path::PathBuf;
 use std::time::Instant;
-use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
 
-mod processor;
-
-use processor::CsvProcessor;
+use pulsecsv::{
+    Aggregate, Column, Compression, CsvFormat, CsvProcessor, CsvProcessorBuilder, EmptyPolicy, Format, Join, JoinType, Keep,
+    LineEnding,
+    OnError, OutputFormat, ParquetCompression, ParquetOptions, Progress, QuoteStyle, ShortRows, SortKey, DEFAULT_TOP_K,
+};
 
+/// Shared input and output options come before the command. Commands can
+/// be chained with `then`; each one works on the rows and columns the one
+/// before it outputs, all in a single parallel pass.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(after_help = "Example: pulsecsv -i users.csv -o out.csv -d , filter --where \"len(email) > 5\" then select -f email")]
 struct Args {
-    /// Input CSV file path
+    /// Input CSV file path (`-` for stdin)
     #[arg(short, long)]
     input: PathBuf,
 
-    /// Output file path
+    /// Output file path (`-` for stdout), or a path template with
+    /// --partition-by, --split-rows or --split-bytes
     #[arg(short, long)]
     output: PathBuf,
 
     /// Field delimiter
-    #[arg(short, long, default_value = ":")]
-    delimiter: char,
+    #[arg(short, long, default_value = ":", value_parser = parse_ascii)]
+    delimiter: u8,
+
+    /// Quote character for fields containing delimiters or newlines
+    #[arg(long, default_value = "\"", value_parser = parse_ascii)]
+    quote: u8,
+
+    /// Escape character inside quoted fields (defaults to doubling the quote)
+    #[arg(long, value_parser = parse_ascii)]
+    escape: Option<u8>,
+
+    /// Disable quote handling and split on every delimiter
+    #[arg(long)]
+    no_quote: bool,
 
     /// Number of threads to use
     #[arg(short, long)]
     threads: Option<usize>,
 
-    /// Fields to extract (comma-separated indices, 0-based)
-    #[arg(short, long, default_value = "1,2")]
-    fields: String,
+    /// Size of a parallel work unit (e.g. 512K, 4M)
+    #[arg(long, default_value = "4M", value_parser = parse_size)]
+    chunk_size: usize,
+
+    /// Approximate memory ceiling for buffered chunks (e.g. 100M, 1G)
+    #[arg(long, default_value = "100M", value_parser = parse_size)]
+    max_memory: usize,
+
+    /// Malformed rows (bad quoting, or too short unless padded): skip, fail
+    /// at the first one, or log each to stderr and skip
+    #[arg(long, default_value = "skip")]
+    on_error: OnError,
+
+    /// Write every malformed row, row dropped by --empty and blank line to
+    /// this CSV file with its line, byte offset, column and reason
+    #[arg(long, value_name = "PATH")]
+    rejects: Option<PathBuf>,
+
+    /// Output format: csv, jsonl (one object per line), json (array) or
+    /// parquet
+    #[arg(long, default_value = "csv")]
+    format: Format,
+
+    /// Write JSON numbers, booleans and nulls instead of only strings
+    #[arg(long)]
+    infer_types: bool,
+
+    /// Output field delimiter
+    #[arg(long, default_value = ",", value_parser = parse_ascii)]
+    output_delimiter: u8,
+
+    /// When to quote output fields: never, as-needed, always or non-numeric
+    #[arg(long, default_value = "as-needed")]
+    quote_style: QuoteStyle,
+
+    /// Output line ending: lf or crlf
+    #[arg(long, default_value = "lf")]
+    line_ending: LineEnding,
+
+    /// Output compression: none, gzip, zstd, bzip2 or xz (defaults to the
+    /// output file extension)
+    #[arg(long)]
+    compress: Option<Compression>,
+
+    /// Compression level for the chosen output format (gzip and xz 0-9,
+    /// bzip2 1-9, zstd 1-22)
+    #[arg(long)]
+    compress_level: Option<u32>,
+
+    /// Maximum rows per Parquet row group
+    #[arg(long, default_value_t = ParquetOptions::default().row_group_size)]
+    row_group_size: usize,
+
+    /// Parquet column compression: snappy, zstd or none
+    #[arg(long, default_value = "snappy")]
+    parquet_compression: ParquetCompression,
+
+    /// Parquet column types (format: name:type,...; string, int64, float64,
+    /// boolean or binary). Other columns are inferred from the first rows
+    #[arg(long)]
+    schema: Option<String>,
+
+    /// Write a JSON report of the run (counts, per-filter drops, per-thread
+    /// timings, throughput) to PATH, or to stderr without a PATH
+    #[arg(long, value_name = "PATH", num_args = 0..=1, require_equals = true, default_missing_value = "-")]
+    stats_json: Option<PathBuf>,
+
+    /// Drop output rows that repeat an earlier row
+    #[arg(long)]
+    dedup: bool,
+
+    /// Drop output rows that repeat the values of these output columns
+    /// (comma-separated indices or names)
+    #[arg(long, value_name = "COLS")]
+    dedup_key: Option<String>,
+
+    /// Which repeated row to keep: first or last, in input order
+    #[arg(long, default_value = "first")]
+    dedup_keep: Keep,
+
+    /// Write one file per value of this output column, named by the output
+    /// path with {value} filled in (e.g. out/{value}.csv)
+    #[arg(long, value_name = "COL")]
+    partition_by: Option<String>,
+
+    /// Most partition files kept open at once; the least recently written
+    /// is closed and reopened for appending when needed
+    #[arg(long, default_value_t = 64)]
+    max_open_files: usize,
+
+    /// Start a new output file every N rows, named by the output path with
+    /// {n} filled in (e.g. out/part-{n}.csv)
+    #[arg(long, value_name = "N", conflicts_with = "split_bytes")]
+    split_rows: Option<usize>,
+
+    /// Start a new output file before it grows past this size, counted
+    /// before compression (e.g. 100M)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    split_bytes: Option<usize>,
+
+    /// Directory for spill files (defaults to the system temp directory)
+    #[arg(long)]
+    temp_dir: Option<PathBuf>,
 
+    /// Treat the first line as data instead of a header row
+    #[arg(long)]
+    no_header: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Output chosen columns and regex captures
+    Select(SelectArgs),
+    /// Keep only rows that pass every condition
+    Filter(FilterArgs),
+    /// Sort the rows on one or more columns; must come last
+    Sort(SortArgs),
+    /// Write per-column statistics of the rows instead of the rows; must
+    /// come last
+    Stats(StatsArgs),
+    /// Join the rows with a second file on key columns; must come last
+    Join(JoinArgs),
+    /// Write one row per group of rows with aggregates; must come last
+    #[command(name = "groupby")]
+    GroupBy(GroupByArgs),
+}
+
+/// A command chained after `then`.
+#[derive(Parser, Debug)]
+#[command(name = "then", no_binary_name = true)]
+struct Then {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Args, Debug)]
+struct SelectArgs {
+    /// Fields to extract (comma-separated 0-based indices or header names)
+    #[arg(short, long, required_unless_present = "extract_patterns")]
+    fields: Option<String>,
+
+    /// Append regex capture groups from a column as new output columns
+    /// (format: col=REGEX, repeatable)
+    #[arg(long = "extract", value_name = "COL=REGEX", value_parser = parse_column_pattern)]
+    extract_patterns: Vec<(Column, String)>,
+
+    /// Empty output values: keep, drop-row, fill=VALUE or skip (omit the
+    /// value and its delimiter)
+    #[arg(long, default_value = "keep")]
+    empty: EmptyPolicy,
+
+    /// Rows with fewer fields than selected: drop, or pad with empty values
+    #[arg(long, default_value = "drop")]
+    short_rows: ShortRows,
+}
+
+#[derive(clap::Args, Debug)]
+#[command(group(ArgGroup::new("key_lists").multiple(true)))]
+struct FilterArgs {
     /// Filter rows where two columns are equal (format: col1,col2)
     #[arg(long)]
     filter_equal: Option<String>,
+
+    /// Keep rows matching an expression, e.g. "len(email) > 5 AND lower(#2) LIKE 'a%'"
+    #[arg(short, long = "where")]
+    where_clause: Option<String>,
+
+    /// Keep rows whose column matches a regex (format: col=REGEX, repeatable)
+    #[arg(long = "match", value_name = "COL=REGEX", value_parser = parse_column_pattern)]
+    match_patterns: Vec<(Column, String)>,
+
+    /// Drop rows whose column matches a regex (format: col=REGEX, repeatable)
+    #[arg(long = "not-match", value_name = "COL=REGEX", value_parser = parse_column_pattern)]
+    not_match_patterns: Vec<(Column, String)>,
+
+    /// Keep rows whose --key-col value is listed in FILE (one key per line)
+    #[arg(long, value_name = "FILE", requires = "key_col", group = "key_lists")]
+    include_keys: Option<PathBuf>,
+
+    /// Drop rows whose --key-col value is listed in FILE (one key per line)
+    #[arg(long, value_name = "FILE", requires = "key_col", group = "key_lists")]
+    exclude_keys: Option<PathBuf>,
+
+    /// Column looked up in --include-keys and --exclude-keys (index or name)
+    #[arg(long, value_name = "COL", requires = "key_lists")]
+    key_col: Option<Column>,
+
+    /// Load the key lists into Bloom filters with this false positive rate
+    /// (e.g. 0.01) instead of exact sets, to save memory
+    #[arg(long, value_name = "RATE", requires = "key_lists")]
+    key_fpr: Option<f64>,
+}
+
+#[derive(clap::Args, Debug)]
+struct SortArgs {
+    /// Column to sort on, with optional direction and comparison (format:
+    /// COL[:asc|desc][:lex|numeric|natural], repeatable, first key first)
+    #[arg(short, long = "key", value_name = "SPEC", required = true)]
+    keys: Vec<SortKey>,
+}
+
+#[derive(clap::Args, Debug)]
+struct JoinArgs {
+    /// File to join with, read with the same input options. Malformed rows
+    /// follow --on-error and --rejects like those of the input
+    #[arg(long = "with", value_name = "FILE")]
+    with: PathBuf,
+
+    /// Key columns among the rows (comma-separated indices or names)
+    #[arg(long, value_name = "COLS")]
+    on: String,
+
+    /// Key columns of the joined file, if they differ from --on
+    #[arg(long, value_name = "COLS")]
+    right_on: Option<String>,
+
+    /// Join type: inner, left (keep rows without a match), semi (rows with
+    /// a match, without its columns) or anti (rows without a match)
+    #[arg(long = "type", default_value = "inner")]
+    kind: JoinType,
+}
+
+#[derive(clap::Args, Debug)]
+struct GroupByArgs {
+    /// Columns to group on (comma-separated indices or names)
+    #[arg(long, value_name = "COLS")]
+    by: String,
+
+    /// Aggregate to compute per group: count, or FUNC:COL with count,
+    /// count-distinct, sum, min, max, mean, first, last or concat
+    /// (repeatable)
+    #[arg(short, long = "agg", value_name = "SPEC", required = true)]
+    aggregates: Vec<Aggregate>,
+
+    /// Separator between the values joined by concat
+    #[arg(long, default_value = ";")]
+    concat_sep: String,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+#[derive(clap::Args, Debug)]
+struct StatsArgs {
+    /// Number of most frequent values to report per column
+    #[arg(long, default_value_t = DEFAULT_TOP_K)]
+    top: usize,
+}
+
+/// Parses a byte size with an optional K, M or G suffix.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1024),
+        Some((i, 'm' | 'M')) => (&s[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&s[..i], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let n = digits.parse::<usize>().map_err(|_| format!("invalid size '{}'", s))?;
+    n.checked_mul(multiplier).ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// Parses a single ASCII character, since delimiters and quotes are
+/// matched as one byte.
+fn parse_ascii(s: &str) -> Result<u8, String> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii() => Ok(c as u8),
+        (Some(_), None) => Err(format!("'{}' is not an ASCII character", s)),
+        _ => Err(format!("expected a single character but got '{}'", s)),
+    }
+}
+
+/// Parses a `COL=REGEX` spec.
+fn parse_column_pattern(spec: &str) -> Result<(Column, String), String> {
+    let (column, pattern) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected COL=REGEX but got '{}'", spec))?;
+    Ok((Column::from(column), pattern.to_string()))
+}
+
+/// Splits the command line at each `then` that follows a complete command
+/// and comes before the name of another, parsing the first part with the
+/// shared options and the rest as chained commands. A `then` that is the
+/// value of an option, as in `filter --where then`, stays where it is.
+fn parse_chain() -> (Args, Vec<Command>) {
+    let commands = Then::command();
+    let mut parts: Vec<Vec<OsString>> = vec![Vec::new()];
+    let mut args = std::env::args_os().peekable();
+    while let Some(arg) = args.next() {
+        let splits = arg == "then"
+            && args.peek().is_some_and(|next| commands.find_subcommand(next).is_some())
+            && match parts.len() {
+                1 => Args::try_parse_from(&parts[0]).is_ok(),
+                _ => Then::try_parse_from(parts.last().unwrap()).is_ok(),
+            };
+        match splits {
+            true => parts.push(Vec::new()),
+            false => parts.last_mut().unwrap().push(arg),
+        }
+    }
+
+    let mut parts = parts.into_iter();
+    let args = Args::parse_from(parts.next().unwrap());
+    let chained = parts.map(|part| Then::parse_from(part).command).collect();
+    (args, chained)
+}
+
+fn main() {
+    let (args, chained) = parse_chain();
+    if let Err(e) = run(args, chained) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args, chained: Vec<Command>) -> Result<(), Box<dyn std::error::Error>> {
     
     // Set thread count if specified
     if let Some(threads) = args.threads {
@@ -51,56 +378,238 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let start = Instant::now();
     
-    // Parse field indices
-    let fields_to_extract: Vec<usize> = args.fields
-        .split(',')
-        .map(|s| s.trim().parse().unwrap())
-        .collect();
+    let quote = if args.no_quote { None } else { Some(args.quote) };
+    let mut builder = CsvProcessor::builder()
+        .delimiter(args.delimiter)
+        .quote(quote)
+        .escape(args.escape)
+        .has_header(!args.no_header)
+        .chunk_size(args.chunk_size)
+        .max_memory(args.max_memory)
+        .compression_level(args.compress_level)
+        .output_format(OutputFormat {
+            format: args.format,
+            csv: CsvFormat {
+                delimiter: args.output_delimiter,
+                quote: args.quote,
+                quote_style: args.quote_style,
+                line_ending: args.line_ending,
+            },
+            infer_types: args.infer_types,
+        })
+        .parquet(ParquetOptions {
+            row_group_size: args.row_group_size,
+            compression: args.parquet_compression,
+            schema: match &args.schema {
+                Some(spec) => ParquetOptions::parse_schema(spec)?,
+                None => Vec::new(),
+            },
+        })
+        .on_error(args.on_error)
+        .sink(args.output.clone());
+    if let Some(rejects) = &args.rejects {
+        builder = builder.rejects(rejects.as_path());
+    }
+    if let Some(compression) = args.compress {
+        builder = builder.compression(compression);
+    }
+    if let Some(columns) = &args.dedup_key {
+        builder = builder.dedup_by(columns.split(','), args.dedup_keep);
+    } else if args.dedup {
+        builder = builder.dedup(args.dedup_keep);
+    }
+    if let Some(temp_dir) = &args.temp_dir {
+        builder = builder.temp_dir(temp_dir);
+    }
+    if let Some(column) = &args.partition_by {
+        builder = builder.partition_by(column.as_str()).max_open_files(args.max_open_files);
+    }
+    if let Some(rows) = args.split_rows {
+        builder = builder.split_rows(rows);
+    }
+    if let Some(bytes) = args.split_bytes {
+        builder = builder.split_bytes(bytes as u64);
+    }
+
+    // A step filters, then selects; a command that has to see the columns
+    // an earlier select outputs starts the next step
+    let commands: Vec<_> = std::iter::once(&args.command).chain(&chained).collect();
+    let mut selected = false;
+    for (i, command) in commands.iter().enumerate() {
+        match command {
+            Command::Sort(_) if i + 1 < commands.len() => return Err("sort must be the last command".into()),
+            Command::Stats(_) if i + 1 < commands.len() => return Err("stats must be the last command".into()),
+            Command::Join(_) if i + 1 < commands.len() => return Err("join must be the last command".into()),
+            Command::GroupBy(_) if i + 1 < commands.len() => return Err("groupby must be the last command".into()),
+            _ => {}
+        }
+        if selected {
+            builder = builder.then();
+        }
+        (builder, selected) = add_command(builder, command)?;
+    }
     
-    // Parse filter columns if provided
-    let filter_equal = args.filter_equal.as_ref().map(|s| {
-        let parts: Vec<usize> = s.split(',').map(|s| s.trim().parse().unwrap()).collect();
-        (parts[0], parts[1])
+    // Report progress on stderr, which keeps stdout free for piped output.
+    // Nothing is drawn when stderr is redirected to a file or pipe
+    let progress = Arc::new(Progress::new());
+    let (done, finished) = mpsc::channel::<()>();
+    let reporter = io::stderr().is_terminal().then(|| {
+        let progress = progress.clone();
+        thread::spawn(move || report_progress(&progress, start, finished))
     });
-    
-    // Start progress reporting thread
-    let file_size = args.input.metadata()?.len();
-    let progress_counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
-    let counter_clone = progress_counter.clone();
-    
-    let _progress_thread = thread::spawn(move || {
-        let mut last_lines = 0;
-        loop {
-            thread::sleep(Duration::from_millis(100));
-            let current_lines = counter_clone.load(Ordering::Relaxed);
-            if current_lines > last_lines {
-                let elapsed = start.elapsed();
-                let mb_processed = (current_lines as f64 * 50.0) / (1024.0 * 1024.0);
-                let throughput = mb_processed / elapsed.as_secs_f64();
-                
-                // Clear line and show simple progress
-                print!("\rProcessing: {} lines | {:.1} MB/s", current_lines, throughput);
-                io::stdout().flush().unwrap();
-                last_lines = current_lines;
-            }
-        }
+
+    let top_k = match commands.last() {
+        Some(Command::Stats(stats)) => Some(stats.top),
+        _ => None,
+    };
+    let result = builder.progress(progress).build().and_then(|processor| match top_k {
+        Some(top_k) => processor.profile(args.input.clone(), top_k).map(|profile| profile.stats),
+        None => processor.process(args.input.clone()),
     });
-    
-    let processor = CsvProcessor::new(args.delimiter);
-    let processed_lines = processor.process_file_with_filter(
-        &args.input,
-        &args.output,
-        &progress_counter,
-        &fields_to_extract,
-        filter_equal
-    )?;
+    drop(done);
+    if let Some(reporter) = reporter {
+        let _ = reporter.join();
+    }
+    let stats = result?;
     
     let duration = start.elapsed();
     
-    // Clear the progress line and show completion
-    print!("\r");
-    println!("✅ Complete! {} lines processed in {:.1}s", processed_lines, duration.as_secs_f64());
-    println!("📊 Speed: {:.1} MB/s", (file_size as f64 / 1024.0 / 1024.0) / duration.as_secs_f64());
+    eprintln!("✅ Complete! {} lines processed in {:.1}s", stats.rows_written, duration.as_secs_f64());
+    if stats.rows_rejected > 0 {
+        eprintln!("⚠️  {} malformed rows rejected", stats.rows_rejected);
+    }
+    if let Some(file_size) = stats.input_bytes {
+        eprintln!("📊 Speed: {:.1} MB/s", (file_size as f64 / 1024.0 / 1024.0) / duration.as_secs_f64());
+    }
+
+    match args.stats_json.as_deref() {
+        Some(path) if path.as_os_str() == "-" => eprintln!("{}", stats.to_json()),
+        Some(path) => std::fs::write(path, stats.to_json() + "\n")?,
+        None => {}
+    }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Adds one command to the current step of the plan, returning whether it
+/// selected columns.
+fn add_command(
+    mut builder: CsvProcessorBuilder,
+    command: &Command,
+) -> Result<(CsvProcessorBuilder, bool), Box<dyn std::error::Error>> {
+    match command {
+        Command::Select(select) => {
+            // Field indices or names, and capture-group extractions
+            if let Some(fields) = &select.fields {
+                builder = builder.select(fields.split(','));
+            }
+            for (column, pattern) in &select.extract_patterns {
+                builder = builder.extract(column.clone(), pattern);
+            }
+            builder = builder.empty(select.empty.clone()).short_rows(select.short_rows);
+            Ok((builder, true))
+        }
+        Command::Filter(filter) => {
+            if let Some(spec) = &filter.filter_equal {
+                match spec.split(',').collect::<Vec<_>>()[..] {
+                    [col1, col2] => builder = builder.filter_equal(col1, col2),
+                    _ => return Err("--filter-equal expects exactly two columns".into()),
+                }
+            }
+            if let Some(expression) = &filter.where_clause {
+                builder = builder.filter(expression);
+            }
+            for (column, pattern) in &filter.match_patterns {
+                builder = builder.matches(column.clone(), pattern);
+            }
+            for (column, pattern) in &filter.not_match_patterns {
+                builder = builder.not_matches(column.clone(), pattern);
+            }
+            if let Some(column) = &filter.key_col {
+                if let Some(path) = &filter.include_keys {
+                    builder = builder.include_keys(column.clone(), path);
+                }
+                if let Some(path) = &filter.exclude_keys {
+                    builder = builder.exclude_keys(column.clone(), path);
+                }
+            }
+            if let Some(rate) = filter.key_fpr {
+                builder = builder.key_false_positive_rate(rate);
+            }
+            Ok((builder, false))
+        }
+        // Sorts the rows the earlier commands output
+        Command::Sort(sort) => Ok((builder.sort_by(sort.keys.iter().cloned()), false)),
+        // Profiles the columns the earlier commands output
+        Command::Stats(_) => Ok((builder, false)),
+        // Joins the rows the earlier commands output
+        Command::Join(join) => {
+            let mut spec = Join::new(join.with.as_path(), join.on.split(',')).kind(join.kind);
+            if let Some(columns) = &join.right_on {
+                spec = spec.right_on(columns.split(','));
+            }
+            Ok((builder.join(spec), false))
+        }
+        // Groups the rows the earlier commands output
+        Command::GroupBy(group_by) => {
+            let aggregates = group_by
+                .aggregates
+                .iter()
+                .map(|aggregate| aggregate.clone().separator(group_by.concat_sep.as_str()));
+            Ok((builder.group_by(group_by.by.split(','), aggregates), false))
+        }
+    }
+}
+
+/// Redraws the progress line every 100ms until `finished` disconnects, then
+/// draws it a last time and ends it with a newline.
+fn report_progress(progress: &Progress, start: Instant, finished: mpsc::Receiver<()>) {
+    let mut shown = false;
+    loop {
+        let done = finished.recv_timeout(Duration::from_millis(100)) != Err(RecvTimeoutError::Timeout);
+        if shown || progress.rows_read() > 0 {
+            eprint!("\r{}\x1b[K", progress_line(progress, start.elapsed()));
+            let _ = io::stderr().flush();
+            shown = true;
+        }
+        if done {
+            if shown {
+                eprintln!();
+            }
+            return;
+        }
+    }
+}
+
+/// Formats input bytes out of the file size with percentage, throughput and
+/// ETA (when the size is known), followed by the row counts.
+fn progress_line(progress: &Progress, elapsed: Duration) -> String {
+    const MB: f64 = 1024.0 * 1024.0;
+    let read = progress.bytes_read() as f64 / MB;
+    let throughput = read / elapsed.as_secs_f64().max(0.001);
+    let bytes = match (progress.total_bytes(), progress.fraction()) {
+        (Some(total), Some(fraction)) => {
+            let eta = match fraction > 0.0 {
+                true => Duration::from_secs(elapsed.mul_f64((1.0 - fraction) / fraction).as_secs()),
+                false => Duration::ZERO,
+            };
+            format!(
+                "{:.1}% | {:.1}/{:.1} MB | {:.1} MB/s | ETA {} | ",
+                fraction * 100.0,
+                read,
+                total as f64 / MB,
+                throughput,
+                humantime::format_duration(eta)
+            )
+        }
+        _ if progress.bytes_read() > 0 => format!("{:.1} MB | {:.1} MB/s | ", read, throughput),
+        _ => String::new(),
+    };
+    format!(
+        "Processing: {}rows in {}, out {}, rejected {}",
+        bytes,
+        progress.rows_read(),
+        progress.rows_written(),
+        progress.rows_rejected()
+    )
+}
@@ -0,0 +1,55 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Where processed rows are written.
+pub enum Sink {
+    /// A file, or stdout if the path is `-`
+    Path(PathBuf),
+    Writer(Box<dyn Write + Send>),
+}
+
+impl Sink {
+    pub fn writer(writer: impl Write + Send + 'static) -> Self {
+        Sink::Writer(Box::new(writer))
+    }
+
+    pub fn open(self) -> io::Result<Box<dyn Write + Send>> {
+        match self {
+            Sink::Path(path) => create(&path),
+            Sink::Writer(writer) => Ok(writer),
+        }
+    }
+}
+
+impl Default for Sink {
+    fn default() -> Self {
+        Sink::writer(io::stdout())
+    }
+}
+
+impl From<PathBuf> for Sink {
+    fn from(path: PathBuf) -> Self {
+        Sink::Path(path)
+    }
+}
+
+impl From<&Path> for Sink {
+    fn from(path: &Path) -> Self {
+        Sink::Path(path.to_path_buf())
+    }
+}
+
+impl From<&str> for Sink {
+    fn from(path: &str) -> Self {
+        Sink::Path(PathBuf::from(path))
+    }
+}
+
+/// Opens the output at `path`, or stdout if it is `-`.
+pub fn create(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+    if path.as_os_str() == "-" {
+        return Ok(Box::new(io::stdout()));
+    }
+    Ok(Box::new(File::create(path)?))
+}
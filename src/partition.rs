@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::columnar::StagedRow;
+use crate::compression::Compression;
+use crate::error::Error;
+use crate::processor::RowBuffer;
+use crate::writer::RowWriter;
+
+/// Default number of output files kept open at once
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+/// When `split_rows` or `split_bytes` starts a new output file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Split {
+    /// After this many rows
+    Rows(usize),
+    /// Before a row that would take the file past this many uncompressed
+    /// bytes, counting its header or JSON brackets. A file always gets at
+    /// least one row.
+    Bytes(u64),
+}
+
+/// Fills `placeholder` in an output path template.
+pub fn file_path(template: &str, placeholder: &str, value: &str) -> PathBuf {
+    PathBuf::from(template.replace(placeholder, value))
+}
+
+/// Makes a partition value usable as a file name, giving every value its
+/// own name: path separators, `%`, control characters and bytes that are
+/// not UTF-8 are percent-encoded, and so are all bytes of `.`, `..` and
+/// `_empty_`. Empty values go to `_empty_`.
+pub fn file_name(value: &[u8]) -> String {
+    let escape = |name: &mut String, bytes: &[u8]| {
+        for byte in bytes {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    };
+    let mut name = String::new();
+    match value {
+        b"" => name.push_str("_empty_"),
+        b"." | b".." | b"_empty_" => escape(&mut name, value),
+        _ => {
+            for chunk in value.utf8_chunks() {
+                for c in chunk.valid().chars() {
+                    match c {
+                        '/' | '\\' | '%' => escape(&mut name, &[c as u8]),
+                        c if c.is_control() => escape(&mut name, c.encode_utf8(&mut [0; 4]).as_bytes()),
+                        c => name.push(c),
+                    }
+                }
+                escape(&mut name, chunk.invalid());
+            }
+        }
+    }
+    name
+}
+
+/// Output files written a batch of rows at a time, each with its own
+/// header or JSON brackets and compressed like the sink. At most
+/// `max_open` files are open at once; the least recently written one is
+/// closed to make room and reopened for appending when it gets more rows.
+pub struct OutputFiles {
+    /// Start, end and row separator of every file, compressed
+    begin: Vec<u8>,
+    end: Vec<u8>,
+    separator: Vec<u8>,
+    max_open: usize,
+    files: HashMap<PathBuf, OutputFile>,
+    /// Open files by the time they were last written
+    open: BTreeMap<u64, PathBuf>,
+    clock: u64,
+}
+
+struct OutputFile {
+    rows: usize,
+    writer: Option<BufWriter<File>>,
+    last_used: u64,
+    finished: bool,
+}
+
+impl OutputFiles {
+    pub fn new(rows: &RowWriter, compression: Compression, level: Option<u32>, max_open: usize) -> Result<Self, Error> {
+        let compress = |data: Vec<u8>| match data.is_empty() {
+            true => Ok(data),
+            false => compression.compress(data, level),
+        };
+        Ok(Self {
+            begin: compress(rows.begin())?,
+            end: compress(rows.end().to_vec())?,
+            separator: compress(rows.row_separator().to_vec())?,
+            max_open: max_open.max(1),
+            files: HashMap::new(),
+            open: BTreeMap::new(),
+            clock: 0,
+        })
+    }
+
+    /// Appends `rows` rows to the file at `path`, creating it first if
+    /// this run has not written it yet. `data` is compressed and has
+    /// separators only between its own rows.
+    pub fn write(&mut self, path: &Path, data: &[u8], rows: usize) -> Result<(), Error> {
+        let created = !self.files.contains_key(path);
+        self.open_file(path)?;
+        let file = self.files.get_mut(path).unwrap();
+        let writer = file.writer.as_mut().unwrap();
+        if created {
+            writer.write_all(&self.begin)?;
+        }
+        if file.rows > 0 && rows > 0 {
+            writer.write_all(&self.separator)?;
+        }
+        writer.write_all(data)?;
+        file.rows += rows;
+        Ok(())
+    }
+
+    /// Opens the file at `path` if needed, closing the least recently
+    /// written file when too many are open.
+    fn open_file(&mut self, path: &Path) -> Result<(), Error> {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(file) = self.files.get_mut(path) {
+            if file.writer.is_some() {
+                self.open.remove(&file.last_used);
+                file.last_used = clock;
+                self.open.insert(clock, path.to_path_buf());
+                return Ok(());
+            }
+        }
+
+        if self.open.len() >= self.max_open {
+            if let Some((_, oldest)) = self.open.pop_first() {
+                let file = self.files.get_mut(&oldest).unwrap();
+                if let Some(mut writer) = file.writer.take() {
+                    writer.flush()?;
+                }
+            }
+        }
+        let writer = match self.files.get(path) {
+            Some(_) => File::options().append(true).open(path)?,
+            None => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                File::create(path)?
+            }
+        };
+        let file = self.files.entry(path.to_path_buf()).or_insert(OutputFile {
+            rows: 0,
+            writer: None,
+            last_used: 0,
+            finished: false,
+        });
+        file.writer = Some(BufWriter::new(writer));
+        file.last_used = clock;
+        self.open.insert(clock, path.to_path_buf());
+        Ok(())
+    }
+
+    /// Writes the end of the file at `path` and closes it for good.
+    pub fn finish_file(&mut self, path: &Path) -> Result<(), Error> {
+        if self.files.get(path).is_none_or(|file| file.finished) {
+            return Ok(());
+        }
+        self.open_file(path)?;
+        let file = self.files.get_mut(path).unwrap();
+        self.open.remove(&file.last_used);
+        let mut writer = file.writer.take().unwrap();
+        writer.write_all(&self.end)?;
+        writer.flush()?;
+        file.finished = true;
+        Ok(())
+    }
+
+    /// Finishes every file and returns how many were written.
+    pub fn finish(mut self) -> Result<usize, Error> {
+        let mut paths: Vec<PathBuf> = self.files.keys().cloned().collect();
+        paths.sort();
+        for path in &paths {
+            self.finish_file(path)?;
+        }
+        Ok(paths.len())
+    }
+}
+
+/// Output files numbered from 1 through a `{n}` placeholder, each taking
+/// rows until its `Split` limit is reached.
+pub struct SplitFiles {
+    files: OutputFiles,
+    template: String,
+    split: Split,
+    /// Number of the current file
+    index: usize,
+    /// Bytes of a file without rows: the header or JSON brackets
+    empty: u64,
+    separator: u64,
+    /// Rows and uncompressed bytes in the current file
+    rows: usize,
+    bytes: u64,
+}
+
+impl SplitFiles {
+    /// Splits the rows serialized by `rows` over `files`.
+    pub fn new(files: OutputFiles, template: String, split: Split, rows: &RowWriter) -> Self {
+        let empty = (rows.begin().len() + rows.end().len()) as u64;
+        Self {
+            files,
+            template,
+            split,
+            index: 1,
+            empty,
+            separator: rows.row_separator().len() as u64,
+            rows: 0,
+            bytes: empty,
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        file_path(&self.template, "{n}", &format!("{:04}", self.index))
+    }
+
+    /// Whether a row of `len` bytes has to start a new file.
+    pub fn is_full(&self, len: usize) -> bool {
+        self.rows > 0
+            && match self.split {
+                Split::Rows(rows) => self.rows >= rows,
+                Split::Bytes(bytes) => self.bytes + self.separator + len as u64 > bytes,
+            }
+    }
+
+    /// Counts a row of `len` bytes, and the separator before it, for the
+    /// current file.
+    pub fn count(&mut self, len: usize) {
+        if self.rows > 0 {
+            self.bytes += self.separator;
+        }
+        self.rows += 1;
+        self.bytes += len as u64;
+    }
+
+    /// Finishes the current file; later rows go to the next one.
+    pub fn next_file(&mut self) -> Result<(), Error> {
+        self.files.finish_file(&self.path())?;
+        self.index += 1;
+        self.rows = 0;
+        self.bytes = self.empty;
+        Ok(())
+    }
+
+    /// Appends compressed rows to the current file.
+    pub fn write(&mut self, data: &[u8], rows: usize) -> Result<(), Error> {
+        let path = self.path();
+        self.files.write(&path, data, rows)
+    }
+
+    /// Finishes every file and returns how many were written.
+    pub fn finish(self) -> Result<usize, Error> {
+        self.files.finish()
+    }
+}
+
+/// Rows of one chunk serialized separately for each value of the
+/// partition column.
+pub struct PartitionedRows<'a> {
+    rows: &'a RowWriter,
+    /// Output column whose value picks the file
+    column: usize,
+    row: StagedRow,
+    /// Value, serialized rows and row count of each partition, in order of
+    /// their first row
+    pub parts: Vec<(Vec<u8>, Vec<u8>, usize)>,
+    index: HashMap<Vec<u8>, usize>,
+}
+
+impl<'a> PartitionedRows<'a> {
+    pub fn new(rows: &'a RowWriter, column: usize) -> Self {
+        Self {
+            rows,
+            column,
+            row: StagedRow::default(),
+            parts: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl RowBuffer for PartitionedRows<'_> {
+    fn start_row(&mut self, _first: bool) {
+        self.row.clear();
+    }
+
+    fn write_value(&mut self, column: usize, _position: usize, value: &[u8]) {
+        self.row.push(column, value);
+    }
+
+    fn end_row(&mut self, written: usize) {
+        let value = self
+            .row
+            .iter()
+            .find(|&(column, _)| column == self.column)
+            .map_or(&[][..], |(_, value)| value);
+        let part = match self.index.get(value) {
+            Some(&part) => part,
+            None => {
+                self.index.insert(value.to_vec(), self.parts.len());
+                self.parts.push((value.to_vec(), Vec::new(), 0));
+                self.parts.len() - 1
+            }
+        };
+
+        let (_, out, count) = &mut self.parts[part];
+        self.rows.start_row(out, *count == 0);
+        let start = out.len();
+        for (position, (column, value)) in self.row.iter().enumerate() {
+            self.rows.write_value(out, column, position, value);
+        }
+        self.rows.end_row(out, start, written);
+        *count += 1;
+    }
+
+    fn discard_row(&mut self) {
+        self.row.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::{Format, OutputFormat};
+
+    /// A fresh directory for the files of one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pulsecsv-partition-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn row_writer(format: Format) -> RowWriter {
+        let format = OutputFormat {
+            format,
+            ..OutputFormat::default()
+        };
+        RowWriter::new(format, vec![b"id".to_vec(), b"name".to_vec()], true)
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn reopens_csv_files_for_appending() {
+        let dir = test_dir("csv");
+        let (a, b, c) = (dir.join("a.csv"), dir.join("b.csv"), dir.join("nested/c.csv"));
+        let mut files = OutputFiles::new(&row_writer(Format::Csv), Compression::None, None, 1).unwrap();
+        files.write(&a, b"1,x\n", 1).unwrap();
+        files.write(&b, b"2,y\n", 1).unwrap();
+        files.write(&a, b"3,z\n4,w\n", 2).unwrap();
+        files.write(&c, b"5,v\n", 1).unwrap();
+        files.write(&b, b"", 0).unwrap();
+        files.write(&a, b"6,u\n", 1).unwrap();
+        assert_eq!(files.open.len(), 1);
+        assert_eq!(files.finish().unwrap(), 3);
+
+        assert_eq!(read(&a), "id,name\n1,x\n3,z\n4,w\n6,u\n");
+        assert_eq!(read(&b), "id,name\n2,y\n");
+        assert_eq!(read(&c), "id,name\n5,v\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn separates_json_rows_across_reopens() {
+        let dir = test_dir("json");
+        let (a, b, empty) = (dir.join("a.json"), dir.join("b.json"), dir.join("empty.json"));
+        let mut files = OutputFiles::new(&row_writer(Format::Json), Compression::None, None, 1).unwrap();
+        files.write(&empty, b"", 0).unwrap();
+        files.write(&a, b"{\"id\":\"1\"}", 1).unwrap();
+        files.write(&b, b"{\"id\":\"2\"}", 1).unwrap();
+        files.write(&a, b"", 0).unwrap();
+        files.write(&b, b"{\"id\":\"3\"},\n{\"id\":\"4\"}", 2).unwrap();
+        files.write(&a, b"{\"id\":\"5\"}", 1).unwrap();
+        files.finish_file(&a).unwrap();
+        // Finished files stay closed
+        files.finish_file(&a).unwrap();
+        assert_eq!(files.finish().unwrap(), 3);
+
+        assert_eq!(read(&a), "[\n{\"id\":\"1\"},\n{\"id\":\"5\"}\n]\n");
+        assert_eq!(read(&b), "[\n{\"id\":\"2\"},\n{\"id\":\"3\"},\n{\"id\":\"4\"}\n]\n");
+        assert_eq!(read(&empty), "[\n\n]\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn splits_files_by_rows_and_bytes() {
+        let dir = test_dir("split");
+        let template = dir.join("part-{n}.csv").to_str().unwrap().to_string();
+        let rows = row_writer(Format::Csv);
+        for (split, expected) in [
+            (Split::Rows(2), vec!["id,name\n1,a\n2,bb\n", "id,name\n3,cccccccccc\n4,d\n", "id,name\n5,e\n"]),
+            // The header takes 8 of the 16 bytes; an oversized row still gets a file
+            (
+                Split::Bytes(16),
+                vec!["id,name\n1,a\n", "id,name\n2,bb\n", "id,name\n3,cccccccccc\n", "id,name\n4,d\n5,e\n"],
+            ),
+        ] {
+            let files = OutputFiles::new(&rows, Compression::None, None, 1).unwrap();
+            let mut split_files = SplitFiles::new(files, template.clone(), split, &rows);
+            for row in ["1,a\n", "2,bb\n", "3,cccccccccc\n", "4,d\n", "5,e\n"] {
+                if split_files.is_full(row.len()) {
+                    split_files.next_file().unwrap();
+                }
+                split_files.count(row.len());
+                split_files.write(row.as_bytes(), 1).unwrap();
+            }
+            assert_eq!(split_files.finish().unwrap(), expected.len(), "{:?}", split);
+            for (i, contents) in expected.iter().enumerate() {
+                assert_eq!(read(&dir.join(format!("part-{:04}.csv", i + 1))), *contents, "{:?}", split);
+            }
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn makes_values_safe_file_names() {
+        assert_eq!(file_name(b"Spain"), "Spain");
+        assert_eq!(file_name("Zürich".as_bytes()), "Zürich");
+        assert_eq!(file_name(b"a/b\\c\td%"), "a%2Fb%5Cc%09d%25");
+        assert_eq!(file_name(b"caf\xE9"), "caf%E9");
+        assert_eq!(file_name(b""), "_empty_");
+        assert_eq!(file_name(b"_empty_"), "%5F%65%6D%70%74%79%5F");
+        assert_eq!(file_name(b".."), "%2E%2E");
+        assert_eq!(file_name(b"..."), "...");
+        assert_eq!(file_path("out/{value}.csv", "{value}", "x"), PathBuf::from("out/x.csv"));
+    }
+
+    /// Reverses `file_name`.
+    fn value_of(name: &str) -> Vec<u8> {
+        if name == "_empty_" {
+            return Vec::new();
+        }
+        let mut value = Vec::new();
+        let mut bytes = name.bytes();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'%' => {
+                    let hex = [bytes.next().unwrap(), bytes.next().unwrap()];
+                    value.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+                }
+                byte => value.push(byte),
+            }
+        }
+        value
+    }
+
+    #[test]
+    fn gives_every_value_its_own_file_name() {
+        let values: [&[u8]; 16] = [
+            b"a/b", b"a_b", b"a%2Fb", b"a\\b", b".", b"_", b"%2E", b"..", b"__", b"", b"_empty_", b"%", b"\xFF",
+            b"\xFE", b"\x00", b"\n",
+        ];
+        let mut names = std::collections::HashSet::new();
+        for value in values {
+            let name = file_name(value);
+            assert!(!name.contains(['/', '\\']) && !name.chars().any(char::is_control), "{}", name);
+            assert!(!["", ".", ".."].contains(&name.as_str()), "{:?}", value);
+            assert_eq!(value_of(&name), value, "{}", name);
+            assert!(names.insert(name), "{:?}", value);
+        }
+    }
+}
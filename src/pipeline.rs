@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::sync::{Condvar, Mutex};
+
+struct State<U> {
+    units: U,
+    /// Number of units handed out to workers so far
+    pulled: usize,
+    /// Number of units handed to the sink so far
+    written: usize,
+    exhausted: bool,
+    aborted: bool,
+}
+
+/// Processes work units in parallel and hands the results to `sink` in input
+/// order as soon as they are ready.
+///
+/// Workers pull units from `units` on the rayon pool while the calling thread
+/// reassembles and writes the results. At most `max_in_flight` units are being
+/// processed or waiting for reassembly at any time, so memory stays bounded
+/// no matter how large the input is.
+pub fn run_ordered<U, I, O, P, S, E>(units: U, max_in_flight: usize, process: P, mut sink: S) -> Result<(), E>
+where
+    U: Iterator<Item = I> + Send,
+    I: Send,
+    O: Send,
+    P: Fn(I) -> O + Sync,
+    S: FnMut(O) -> Result<(), E>,
+{
+    let max_in_flight = max_in_flight.max(1);
+    let state = Mutex::new(State {
+        units,
+        pulled: 0,
+        written: 0,
+        exhausted: false,
+        aborted: false,
+    });
+    let slot_freed = Condvar::new();
+    let (tx, rx) = mpsc::channel::<(usize, O)>();
+
+    rayon::in_place_scope(|scope| {
+        for _ in 0..rayon::current_num_threads() {
+            let tx = tx.clone();
+            let (state, slot_freed, process) = (&state, &slot_freed, &process);
+            scope.spawn(move |_| {
+                let _guard = AbortOnPanic { state, slot_freed };
+                while let Some((index, unit)) = next_unit(state, slot_freed, max_in_flight) {
+                    if tx.send((index, process(unit))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // Workers hold the remaining senders, so the receive loop below ends
+        // once all of them have finished
+        drop(tx);
+
+        let mut pending = BTreeMap::new();
+        let mut next_index = 0;
+        let mut result = Ok(());
+
+        for (index, output) in rx.iter() {
+            pending.insert(index, output);
+
+            // Write every unit that is now next in line
+            while let Some(output) = pending.remove(&next_index) {
+                if let Err(e) = sink(output) {
+                    result = Err(e);
+                    break;
+                }
+                next_index += 1;
+            }
+
+            let mut state = state.lock().unwrap();
+            state.written = next_index;
+            state.aborted |= result.is_err();
+            drop(state);
+            slot_freed.notify_all();
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        result
+    })
+}
+
+/// Aborts the run if a worker panics, so the remaining workers do not wait
+/// forever for a unit that will never be written.
+struct AbortOnPanic<'a, U> {
+    state: &'a Mutex<State<U>>,
+    slot_freed: &'a Condvar,
+}
+
+impl<U> Drop for AbortOnPanic<'_, U> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            if let Ok(mut state) = self.state.lock() {
+                state.aborted = true;
+            }
+            self.slot_freed.notify_all();
+        }
+    }
+}
+
+/// Waits for a free slot and pulls the next unit, or returns `None` once the
+/// units are exhausted or the run was aborted.
+fn next_unit<U, I>(state: &Mutex<State<U>>, slot_freed: &Condvar, max_in_flight: usize) -> Option<(usize, I)>
+where
+    U: Iterator<Item = I>,
+{
+    let mut state = state.lock().unwrap();
+    loop {
+        if state.exhausted || state.aborted {
+            return None;
+        }
+        if state.pulled - state.written < max_in_flight {
+            break;
+        }
+        state = slot_freed.wait(state).unwrap();
+    }
+
+    match state.units.next() {
+        Some(unit) => {
+            let index = state.pulled;
+            state.pulled += 1;
+            Some((index, unit))
+        }
+        None => {
+            state.exhausted = true;
+            drop(state);
+            slot_freed.notify_all();
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Runs `f` on a pool of several threads, so units finish out of order
+    /// even on a single core.
+    fn with_threads<R: Send>(f: impl FnOnce() -> R + Send) -> R {
+        rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap().install(f)
+    }
+
+    #[test]
+    fn writes_in_input_order() {
+        let mut written = Vec::new();
+        let result: Result<(), ()> = with_threads(|| {
+            run_ordered(
+                0..40u64,
+                8,
+                |i| {
+                    // Later units of each batch finish first
+                    std::thread::sleep(Duration::from_millis(8 - i % 8));
+                    i * 2
+                },
+                |out| {
+                    written.push(out);
+                    Ok(())
+                },
+            )
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(written, (0..40).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bounds_units_in_flight() {
+        let in_flight = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let units = (0..100).inspect(|_| {
+            let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+        });
+        let result: Result<(), ()> = with_threads(|| {
+            run_ordered(
+                units,
+                3,
+                |i| {
+                    std::thread::sleep(Duration::from_millis(1));
+                    i
+                },
+                |_| {
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                },
+            )
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+        assert!(peak.load(Ordering::SeqCst) <= 3, "{} units in flight", peak.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn stops_at_a_sink_error() {
+        let pulled = AtomicUsize::new(0);
+        let units = (0..1000).inspect(|_| {
+            pulled.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut written = Vec::new();
+        let result = with_threads(|| {
+            run_ordered(units, 4, |i| i, |i| match i {
+                5 => Err(format!("failed at {}", i)),
+                i => {
+                    written.push(i);
+                    Ok(())
+                }
+            })
+        });
+        assert_eq!(result, Err("failed at 5".to_string()));
+        assert_eq!(written, [0, 1, 2, 3, 4]);
+        assert!(pulled.load(Ordering::SeqCst) < 1000);
+    }
+
+    #[test]
+    fn stops_at_a_process_error() {
+        let pulled = AtomicUsize::new(0);
+        let units = (0..1000).inspect(|_| {
+            pulled.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut written = 0;
+        let result = with_threads(|| {
+            run_ordered(
+                units,
+                4,
+                |i| match i {
+                    7 => Err(format!("failed at {}", i)),
+                    i => Ok(i),
+                },
+                |out: Result<usize, String>| {
+                    out?;
+                    written += 1;
+                    Ok(())
+                },
+            )
+        });
+        assert_eq!(result, Err("failed at 7".to_string()));
+        assert_eq!(written, 7);
+        assert!(pulled.load(Ordering::SeqCst) < 1000);
+    }
+}
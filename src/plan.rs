@@ -0,0 +1,199 @@
+use crate::error::Error;
+use crate::expr::Expr;
+use std::path::PathBuf;
+
+use crate::filter::{FieldMatch, KeyMatch, RowFilter};
+use crate::header::{Column, Header};
+use crate::keys::KeySet;
+use crate::select::{EmptyPolicy, Extract, Selection, ShortRows};
+
+/// Chain of steps as configured, resolved against the header of the input
+/// once it is opened. Each step filters the rows it receives and may then
+/// project them, and the next step sees the projected columns.
+pub struct Plan {
+    pub steps: Vec<Step>,
+}
+
+impl Default for Plan {
+    fn default() -> Self {
+        Self {
+            steps: vec![Step::default()],
+        }
+    }
+}
+
+/// Filters and selection of one step, with columns still unresolved.
+#[derive(Default)]
+pub struct Step {
+    pub fields: Vec<Column>,
+    pub extracts: Vec<(Column, String)>,
+    pub empty: EmptyPolicy,
+    pub short_rows: ShortRows,
+    pub filter_equal: Option<(Column, Column)>,
+    pub predicate: Option<String>,
+    /// Regex conditions, with whether they are negated
+    pub matches: Vec<(Column, String, bool)>,
+    /// Key list files, with whether listed keys are dropped
+    pub key_lists: Vec<(Column, PathBuf, bool)>,
+    /// Loads the key lists into Bloom filters with this false positive rate
+    pub key_false_positive_rate: Option<f64>,
+}
+
+/// A step resolved against the columns it receives.
+pub struct Stage {
+    pub filter: RowFilter,
+    /// Projection applied after the filter. The last stage has none; its
+    /// projection is the output selection.
+    pub selection: Option<Selection>,
+    /// Index of the first filter of this stage among all filters of the plan
+    pub first_filter: usize,
+}
+
+/// A plan resolved against the header of the input.
+pub struct Resolved {
+    pub stages: Vec<Stage>,
+    /// Projection of the last stage, which produces the output rows
+    pub output: Selection,
+    /// Names of the output columns
+    pub names: Vec<Vec<u8>>,
+}
+
+impl Step {
+    pub fn has_selection(&self) -> bool {
+        !self.fields.is_empty() || !self.extracts.is_empty()
+    }
+
+    fn selection(&self, header: Option<&Header>) -> Result<Selection, Error> {
+        Ok(Selection {
+            fields: self
+                .fields
+                .iter()
+                .map(|column| column.resolve(header))
+                .collect::<Result<_, _>>()?,
+            extracts: self
+                .extracts
+                .iter()
+                .map(|(column, pattern)| Extract::new(column.resolve(header)?, pattern))
+                .collect::<Result<_, _>>()?,
+            empty: self.empty.clone(),
+            short_rows: self.short_rows,
+        })
+    }
+
+    fn filter(&self, header: Option<&Header>) -> Result<RowFilter, Error> {
+        Ok(RowFilter {
+            filter_equal: match &self.filter_equal {
+                Some((col1, col2)) => Some((col1.resolve(header)?, col2.resolve(header)?)),
+                None => None,
+            },
+            // Compile the row predicate once up front
+            predicate: match &self.predicate {
+                Some(source) => Some(Expr::compile(source, header)?),
+                None => None,
+            },
+            matches: self
+                .matches
+                .iter()
+                .map(|(column, pattern, negate)| FieldMatch::new(column.resolve(header)?, pattern, *negate))
+                .collect::<Result<_, _>>()?,
+            key_lists: self
+                .key_lists
+                .iter()
+                .map(|(column, path, exclude)| {
+                    let keys = KeySet::load(path, self.key_false_positive_rate)?;
+                    Ok(KeyMatch::new(column.resolve(header)?, keys, *exclude))
+                })
+                .collect::<Result<_, Error>>()?,
+        })
+    }
+
+    /// Describes the row filters, in the order `RowFilter` numbers them.
+    fn filter_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Some((col1, col2)) = &self.filter_equal {
+            names.push(format!("filter-equal {},{}", col1, col2));
+        }
+        for (column, pattern, negate) in &self.matches {
+            let flag = if *negate { "not-match" } else { "match" };
+            names.push(format!("{} {}={}", flag, column, pattern));
+        }
+        for (column, path, exclude) in &self.key_lists {
+            let flag = if *exclude { "exclude-keys" } else { "include-keys" };
+            names.push(format!("{} {}={}", flag, column, path.display()));
+        }
+        if let Some(source) = &self.predicate {
+            names.push(format!("where {}", source));
+        }
+        names
+    }
+}
+
+impl Plan {
+    /// The step that options apply to, which is the last one.
+    pub fn step(&mut self) -> &mut Step {
+        self.steps.last_mut().expect("a plan has at least one step")
+    }
+
+    /// Resolves the steps in order, each against the columns of the one
+    /// before.
+    pub fn resolve(&self, header: Option<&Header>) -> Result<Resolved, Error> {
+        let mut header = header.cloned();
+        // Names of the columns the current step receives. Without an input
+        // header these are `col<N>` names only used for output, and columns
+        // are still addressed by index
+        let mut names = header.clone();
+        // Number of columns the current step receives, if known
+        let mut width = header.as_ref().map(Header::len);
+        let mut stages = Vec::new();
+        let mut first_filter = 0;
+        let mut output = None;
+
+        for (i, step) in self.steps.iter().enumerate() {
+            let filter = step.filter(header.as_ref())?;
+            let selection = match step.has_selection() {
+                true => Some(step.selection(header.as_ref())?),
+                false => None,
+            };
+
+            if i + 1 == self.steps.len() {
+                output = Some(match selection {
+                    Some(selection) => selection,
+                    None => Selection::all(width.ok_or_else(|| {
+                        Error::Config("no output columns selected and the input has no header".to_string())
+                    })?),
+                });
+                stages.push(Stage {
+                    filter,
+                    selection: None,
+                    first_filter,
+                });
+                break;
+            }
+
+            if let Some(selection) = &selection {
+                let next = Header::from_names(selection.column_names(names.as_ref()));
+                width = Some(next.len());
+                header = header.map(|_| next.clone());
+                names = Some(next);
+            }
+            stages.push(Stage {
+                filter,
+                selection,
+                first_filter,
+            });
+            first_filter += step.filter_names().len();
+        }
+
+        let output = output.expect("a plan has at least one step");
+        Ok(Resolved {
+            names: output.column_names(names.as_ref()),
+            stages,
+            output,
+        })
+    }
+
+    /// Describes the row filters of all steps, in the order they are numbered.
+    pub fn filter_names(&self) -> Vec<String> {
+        self.steps.iter().flat_map(Step::filter_names).collect()
+    }
+}
@@ -1,251 +1,2032 @@
-use memmap2::Mmap;
-use rayon::prelude::*;
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use arrow_array::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use std::io::{self, BufWriter, Write};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::columnar::{BatchBuilder, BatchFields, ColumnSchema, ParquetOptions, StagedRow, TypeSampler};
+use crate::compression::Compression;
+use crate::dedup::{Deduplicator, Keep};
+use crate::error::{Error, OnError, ParseErrorKind};
+use crate::groupby::{Aggregate, Aggregator, Function, Grouping, PartialGroups};
+use crate::header::{Column, Header};
+use crate::input::{Input, Source};
+use crate::join::{self, Join, JoinTable, JoinType};
+use crate::output::Sink;
+use crate::partition::{self, OutputFiles, PartitionedRows, Split, SplitFiles};
+use crate::pipeline;
+use crate::plan::{Plan, Resolved, Stage};
+use crate::profile::{self, ColumnStats, Profile, Profiler};
+use crate::progress::Progress;
+use crate::rejects::{ChunkReport, Reason, Rejects};
+use crate::select::{EmptyPolicy, Selection, ShortRows};
+use crate::sort::{SortKey, SortOrder, SortedChunk, Sorter};
+use crate::tokenizer::Tokenizer;
+use crate::writer::{self, Format, OutputFormat, RowWriter};
+
+/// Default size of a parallel work unit
+const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// Default ceiling for chunk buffers held in memory at once
+const DEFAULT_MAX_MEMORY: usize = 100 * 1024 * 1024;
+
+/// Parallel CSV processing engine, configured with `CsvProcessor::builder()`.
+///
+/// A processor reads one source, keeps the rows that pass its filters,
+/// projects the selected columns and writes them to its sink.
 pub struct CsvProcessor {
+    engine: Engine,
+    plan: Plan,
+    sink: Sink,
+    on_error: OnError,
+    rejects: Option<Sink>,
+    progress: Option<Arc<Progress>>,
+    /// Key columns of `dedup`, empty for the whole row
+    dedup: Option<(Vec<Column>, Keep)>,
+    sort: Vec<SortKey>,
+    join: Option<Join>,
+    /// Key columns and aggregates of `group_by`
+    group_by: Option<(Vec<Column>, Vec<Aggregate>)>,
+    partition_by: Option<Column>,
+    split: Option<Split>,
+}
+
+/// Settings read by the worker threads.
+struct Engine {
+    tokenizer: Tokenizer,
+    has_header: bool,
+    chunk_size: usize,
+    max_memory: usize,
+    compression: Compression,
+    compression_level: Option<u32>,
+    format: OutputFormat,
+    parquet: ParquetOptions,
+    /// Where spill files are created
+    temp_dir: PathBuf,
+    /// Most partition or split files kept open at once
+    max_open_files: usize,
+}
+
+/// Counts reported after a run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProcessStats {
+    /// Input rows read, excluding the header
+    pub rows_read: usize,
+    /// Rows written to the sink
+    pub rows_written: usize,
+    /// Malformed rows that were dropped
+    pub rows_rejected: usize,
+    /// Rows dropped by each filter, in the order the filters run
+    pub rows_filtered: Vec<(String, usize)>,
+    /// Rows dropped for empty values (`EmptyPolicy::DropRow`, or `Skip` with
+    /// every value empty)
+    pub rows_dropped_empty: usize,
+    /// Blank lines in the input, which are skipped and not counted as rows
+    pub rows_blank: usize,
+    /// Rows dropped by `dedup` as repeats of a kept row
+    pub rows_duplicate: usize,
+    /// Size of the input in bytes, if it was known up front
+    pub input_bytes: Option<u64>,
+    /// Input bytes processed after decompression (0 for columnar input)
+    pub bytes_read: u64,
+    /// Work done by each worker thread
+    pub threads: Vec<ThreadStats>,
+    pub elapsed: Duration,
+}
+
+/// Work done by one worker thread during a run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ThreadStats {
+    /// Index of the thread in the rayon pool
+    pub thread: usize,
+    pub chunks: usize,
+    pub rows_read: usize,
+    /// Time spent parsing, filtering and serializing chunks
+    pub busy: Duration,
+}
+
+impl ProcessStats {
+    /// Input bytes per second, counting decompressed bytes for text input
+    /// and the file size for columnar input.
+    pub fn throughput(&self) -> Option<f64> {
+        let bytes = match self.bytes_read {
+            0 => self.input_bytes?,
+            bytes => bytes,
+        };
+        Some(bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON))
+    }
+
+    /// Renders the stats as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        let mut filters = Vec::new();
+        for (i, (filter, rows)) in self.rows_filtered.iter().enumerate() {
+            if i > 0 {
+                filters.push(b',');
+            }
+            filters.extend_from_slice(b"{\"filter\":");
+            writer::write_json_string(&mut filters, filter.as_bytes());
+            filters.extend_from_slice(format!(",\"rows\":{}}}", rows).as_bytes());
+        }
+        let threads = self
+            .threads
+            .iter()
+            .map(|t| {
+                format!(
+                    "{{\"thread\":{},\"chunks\":{},\"rows_read\":{},\"busy_secs\":{:.6}}}",
+                    t.thread,
+                    t.chunks,
+                    t.rows_read,
+                    t.busy.as_secs_f64()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+
+        format!(
+            "{{\"input_bytes\":{},\"bytes_read\":{},\"rows_read\":{},\"rows_kept\":{},\"rows_rejected\":{},\
+             \"rows_dropped_empty\":{},\"rows_blank\":{},\"rows_duplicate\":{},\"rows_filtered\":[{}],\"threads\":[{}],\"wall_secs\":{:.6},\
+             \"throughput_bytes_per_sec\":{}}}",
+            optional(self.input_bytes.map(|b| b.to_string())),
+            self.bytes_read,
+            self.rows_read,
+            self.rows_written,
+            self.rows_rejected,
+            self.rows_dropped_empty,
+            self.rows_blank,
+            self.rows_duplicate,
+            String::from_utf8_lossy(&filters),
+            threads,
+            self.elapsed.as_secs_f64(),
+            optional(self.throughput().map(|t| format!("{:.0}", t))),
+        )
+    }
+}
+
+/// Per-run state shared by the worker threads.
+struct Run<'a> {
+    stages: &'a [Stage],
+    output: &'a Selection,
+    rows: RowWriter,
+    progress: &'a Progress,
+    /// Whether malformed rows are reported with their position and contents
+    detailed: bool,
+    /// Whether rows dropped for empty values and blank lines are too
+    audited: bool,
+    /// Which output columns make up the key of `dedup`, and which row of a
+    /// key is kept
+    dedup: Option<(Vec<bool>, Keep)>,
+    sort: Option<SortOrder>,
+    /// Right side of the join, and the filter index its dropped rows are
+    /// counted under
+    join: Option<(JoinTable, usize)>,
+    group_by: Option<Grouping>,
+    /// Files the output is spread over instead of the sink
+    files: Option<FileOutput>,
+    counters: Mutex<Counters>,
+}
+
+/// How `partition_by` or `split_rows` and `split_bytes` spread the output
+/// over files named by the sink path.
+enum FileOutput {
+    /// One file per value of an output column, filling `{value}`
+    Partition { column: usize, template: String },
+    /// Numbered files, filling `{n}`
+    Split { split: Split, template: String },
+}
+
+/// Chunk reports merged for `ProcessStats`.
+#[derive(Default)]
+struct Counters {
+    filtered: Vec<usize>,
+    dropped_empty: usize,
+    blank: usize,
+    duplicates: usize,
+    threads: BTreeMap<usize, ThreadStats>,
+}
+
+impl Run<'_> {
+    /// Adds a processed chunk to the counters; `started` is when its worker
+    /// picked it up.
+    fn record(&self, report: &ChunkReport, started: Instant) {
+        self.progress.add(report.len, report.read, report.kept, report.rejected);
+
+        let thread = rayon::current_thread_index().unwrap_or(0);
+        let mut counters = self.counters.lock().unwrap();
+        if counters.filtered.len() < report.filtered.len() {
+            counters.filtered.resize(report.filtered.len(), 0);
+        }
+        for (total, rows) in counters.filtered.iter_mut().zip(&report.filtered) {
+            *total += rows;
+        }
+        counters.dropped_empty += report.dropped_empty;
+        counters.blank += report.blank;
+        let stats = counters.threads.entry(thread).or_insert_with(|| ThreadStats {
+            thread,
+            ..ThreadStats::default()
+        });
+        stats.chunks += 1;
+        stats.rows_read += report.read;
+        stats.busy += started.elapsed();
+    }
+}
+
+/// What happened to one input row.
+enum Outcome {
+    Kept,
+    /// Dropped by the filter with this index
+    Filtered(usize),
+    /// Dropped for an empty value
+    Empty,
+    Malformed(Option<usize>, ParseErrorKind),
+}
+
+/// Values of a row projected by a stage of a chain, for the next stage to
+/// read. Kept for a whole chunk so the value buffers are reused from row to
+/// row instead of allocated for each.
+#[derive(Default)]
+struct StagedValues {
+    values: Vec<Vec<u8>>,
+    len: usize,
+}
+
+impl StagedValues {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, value: &[u8]) {
+        if self.len == self.values.len() {
+            self.values.push(Vec::new());
+        }
+        let slot = &mut self.values[self.len];
+        slot.clear();
+        slot.extend_from_slice(value);
+        self.len += 1;
+    }
+
+    fn values(&self) -> &[Vec<u8>] {
+        &self.values[..self.len]
+    }
+}
+
+/// Builder for a `CsvProcessor`.
+///
+/// ```no_run
+/// use pulsecsv::{CsvProcessor, Sink};
+///
+/// let stats = CsvProcessor::builder()
+///     .delimiter(b',')
+///     .select(["email", "name"])
+///     .filter("len(email) > 5")
+///     .sink(Sink::writer(std::io::stdout()))
+///     .build()?
+///     .process("users.csv")?;
+/// eprintln!("{} of {} rows kept", stats.rows_written, stats.rows_read);
+/// # Ok::<(), pulsecsv::Error>(())
+/// ```
+pub struct CsvProcessorBuilder {
     delimiter: u8,
+    quote: Option<u8>,
+    escape: Option<u8>,
+    has_header: bool,
+    chunk_size: usize,
+    max_memory: usize,
+    compression: Option<Compression>,
+    compression_level: Option<u32>,
+    format: OutputFormat,
+    parquet: ParquetOptions,
+    plan: Plan,
+    sink: Sink,
+    on_error: OnError,
+    rejects: Option<Sink>,
+    progress: Option<Arc<Progress>>,
+    dedup: Option<(Vec<Column>, Keep)>,
+    sort: Vec<SortKey>,
+    join: Option<Join>,
+    group_by: Option<(Vec<Column>, Vec<Aggregate>)>,
+    partition_by: Option<Column>,
+    split: Option<Split>,
+    max_open_files: usize,
+    temp_dir: PathBuf,
 }
 
-impl CsvProcessor {
-    pub fn new(delimiter: char) -> Self {
+impl Default for CsvProcessorBuilder {
+    fn default() -> Self {
         Self {
-            delimiter: delimiter as u8,
+            delimiter: b',',
+            quote: Some(b'"'),
+            escape: None,
+            has_header: true,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_memory: DEFAULT_MAX_MEMORY,
+            compression: None,
+            compression_level: None,
+            format: OutputFormat::default(),
+            parquet: ParquetOptions::default(),
+            plan: Plan::default(),
+            sink: Sink::writer(io::stdout()),
+            on_error: OnError::default(),
+            rejects: None,
+            progress: None,
+            dedup: None,
+            sort: Vec::new(),
+            join: None,
+            group_by: None,
+            partition_by: None,
+            split: None,
+            max_open_files: partition::DEFAULT_MAX_OPEN_FILES,
+            temp_dir: std::env::temp_dir(),
         }
     }
+}
 
-    pub fn process_file(&self, input_path: &Path, output_path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
-        let file = File::open(input_path)?;
-        let mmap = unsafe { Mmap::map(&file)? };
-        
-        // Find line boundaries for parallel processing
-        let chunk_size = mmap.len() / rayon::current_num_threads().max(1);
-        let mut chunk_boundaries = vec![0];
-        
-        let mut pos = 0;
-        while pos < mmap.len() {
-            let end = (pos + chunk_size).min(mmap.len());
-            let boundary = self.find_line_boundary(&mmap, end);
-            chunk_boundaries.push(boundary);
-            pos = boundary;
+impl CsvProcessorBuilder {
+    /// Sets the input field delimiter (`,` by default).
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets the quote character, or disables quote handling with `None`.
+    pub fn quote(mut self, quote: Option<u8>) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    /// Sets the escape character inside quoted fields. By default a quote is
+    /// escaped by doubling it.
+    pub fn escape(mut self, escape: Option<u8>) -> Self {
+        self.escape = escape;
+        self
+    }
+
+    /// Sets whether the first record of the input is a header row.
+    pub fn has_header(mut self, has_header: bool) -> Self {
+        self.has_header = has_header;
+        self
+    }
+
+    /// Sets the size of a parallel work unit in bytes.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Sets the approximate ceiling in bytes for chunk buffers held in memory
+    /// while waiting to be processed or written.
+    pub fn max_memory(mut self, max_memory: usize) -> Self {
+        self.max_memory = max_memory;
+        self
+    }
+
+    /// Sets the compression applied to the output. Chunks are compressed on
+    /// the worker threads, so the writer never becomes the bottleneck. By
+    /// default it follows the extension of a sink path.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Sets the compression level for the output compression or the
+    /// Parquet zstd codec.
+    pub fn compression_level(mut self, level: Option<u32>) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Sets how output rows are serialized.
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the row group size, compression and column types used for
+    /// Parquet output.
+    pub fn parquet(mut self, parquet: ParquetOptions) -> Self {
+        self.parquet = parquet;
+        self
+    }
+
+    /// Selects the input columns to output, by index or header name.
+    pub fn select<I>(mut self, columns: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Column>,
+    {
+        self.plan.step().fields = columns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Appends the capture groups of `pattern` in `column` as output columns.
+    /// A pattern without groups yields the whole match.
+    pub fn extract(mut self, column: impl Into<Column>, pattern: impl Into<String>) -> Self {
+        self.plan.step().extracts.push((column.into(), pattern.into()));
+        self
+    }
+
+    /// Sets what happens to empty output values.
+    pub fn empty(mut self, empty: EmptyPolicy) -> Self {
+        self.plan.step().empty = empty;
+        self
+    }
+
+    /// Sets what happens to rows with fewer fields than the selection needs.
+    pub fn short_rows(mut self, short_rows: ShortRows) -> Self {
+        self.plan.step().short_rows = short_rows;
+        self
+    }
+
+    /// Keeps only rows matching a `--where` expression.
+    pub fn filter(mut self, expression: impl Into<String>) -> Self {
+        self.plan.step().predicate = Some(expression.into());
+        self
+    }
+
+    /// Drops rows where the two columns are equal.
+    pub fn filter_equal(mut self, col1: impl Into<Column>, col2: impl Into<Column>) -> Self {
+        self.plan.step().filter_equal = Some((col1.into(), col2.into()));
+        self
+    }
+
+    /// Keeps only rows whose `column` matches the regex `pattern`.
+    pub fn matches(mut self, column: impl Into<Column>, pattern: impl Into<String>) -> Self {
+        self.plan.step().matches.push((column.into(), pattern.into(), false));
+        self
+    }
+
+    /// Drops rows whose `column` matches the regex `pattern`.
+    pub fn not_matches(mut self, column: impl Into<Column>, pattern: impl Into<String>) -> Self {
+        self.plan.step().matches.push((column.into(), pattern.into(), true));
+        self
+    }
+
+    /// Keeps only rows whose `column` is one of the keys in the file at
+    /// `path`, one key per line. The keys are held in memory.
+    pub fn include_keys(mut self, column: impl Into<Column>, path: impl Into<PathBuf>) -> Self {
+        self.plan.step().key_lists.push((column.into(), path.into(), false));
+        self
+    }
+
+    /// Drops rows whose `column` is one of the keys in the file at `path`.
+    pub fn exclude_keys(mut self, column: impl Into<Column>, path: impl Into<PathBuf>) -> Self {
+        self.plan.step().key_lists.push((column.into(), path.into(), true));
+        self
+    }
+
+    /// Loads the key lists of the current step into Bloom filters instead
+    /// of exact sets, using about 10 bits per key at a 1% rate. Rows whose
+    /// key is not listed then pass `include_keys`, or are dropped by
+    /// `exclude_keys`, at about `false_positive_rate`.
+    pub fn key_false_positive_rate(mut self, false_positive_rate: f64) -> Self {
+        self.plan.step().key_false_positive_rate = Some(false_positive_rate);
+        self
+    }
+
+    /// Starts a new step. Filters and selections set from here on apply to
+    /// the rows and columns the previous step outputs, like piping one run
+    /// into another within a single pass.
+    pub fn then(mut self) -> Self {
+        self.plan.steps.push(Default::default());
+        self
+    }
+
+    /// Sets where output goes (stdout by default).
+    pub fn sink(mut self, sink: impl Into<Sink>) -> Self {
+        self.sink = sink.into();
+        self
+    }
+
+    /// Sets what happens to malformed rows: unterminated quotes, text after
+    /// a closing quote, and rows too short for the selection unless padded.
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    /// Writes every malformed row, row dropped for an empty value and blank
+    /// line to `rejects` as CSV, with its line, byte offset, column and
+    /// reason.
+    pub fn rejects(mut self, rejects: impl Into<Sink>) -> Self {
+        self.rejects = Some(rejects.into());
+        self
+    }
+
+    /// Reports input bytes and row counts to `progress` as chunks complete.
+    pub fn progress(mut self, progress: Arc<Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Drops output rows that repeat an earlier row, keeping the first or
+    /// last occurrence in input order. Keys that do not fit in `max_memory`
+    /// are spilled to disk, so inputs larger than memory work too.
+    pub fn dedup(mut self, keep: Keep) -> Self {
+        self.dedup = Some((Vec::new(), keep));
+        self
+    }
+
+    /// Like `dedup`, but rows are compared on the output `columns` only.
+    pub fn dedup_by<I>(mut self, columns: I, keep: Keep) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Column>,
+    {
+        self.dedup = Some((columns.into_iter().map(Into::into).collect(), keep));
+        self
+    }
+
+    /// Sorts the output rows on `keys`, which name output columns. Equal
+    /// rows keep their input order. Chunks are sorted on the worker
+    /// threads; sorted runs beyond `max_memory` are spilled to disk and
+    /// merged at the end.
+    pub fn sort_by(mut self, keys: impl IntoIterator<Item = SortKey>) -> Self {
+        self.sort = keys.into_iter().collect();
+        self
+    }
+
+    /// Joins the output rows with a second input on key columns. The right
+    /// side is loaded into a hash table; if it exceeds `max_memory` both
+    /// sides are partitioned to disk and joined one partition at a time.
+    /// Inner and semi joins of a smaller input against a larger file build
+    /// the table from the output rows instead, and sort the joined rows on
+    /// disk if the matching right rows exceed `max_memory`. Output rows keep
+    /// their input order.
+    pub fn join(mut self, join: Join) -> Self {
+        self.join = Some(join);
+        self
+    }
+
+    /// Writes one row per distinct value of the output `columns` instead of
+    /// the rows, with those values followed by `aggregates` over the rows of
+    /// the group. Groups are written in the order of their first row. Each
+    /// chunk is aggregated on its worker thread and the partial groups are
+    /// merged in the writer stage; groups beyond `max_memory` are spilled
+    /// to disk by key hash and merged one partition at a time.
+    pub fn group_by<I>(mut self, columns: I, aggregates: impl IntoIterator<Item = Aggregate>) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Column>,
+    {
+        self.group_by = Some((
+            columns.into_iter().map(Into::into).collect(),
+            aggregates.into_iter().collect(),
+        ));
+        self
+    }
+
+    /// Writes the output rows to one file per value of the output `column`
+    /// instead of a single file. The sink must be a path containing
+    /// `{value}`, such as `out/{value}.csv`, which is filled in with the
+    /// value; missing directories are created. Each file gets its own
+    /// header and compression, and keeps its rows in input order.
+    pub fn partition_by(mut self, column: impl Into<Column>) -> Self {
+        self.partition_by = Some(column.into());
+        self
+    }
+
+    /// Sets how many partition files may be open at once (64 by default).
+    /// When another one is needed, the least recently written file is
+    /// closed and reopened for appending when it gets more rows.
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = max_open_files.max(1);
+        self
+    }
+
+    /// Starts a new output file after every `rows` rows. The sink must be a
+    /// path containing `{n}`, such as `out/part-{n}.csv`, which is filled in
+    /// with the file number from `0001`.
+    pub fn split_rows(mut self, rows: usize) -> Self {
+        self.split = Some(Split::Rows(rows.max(1)));
+        self
+    }
+
+    /// Like `split_rows`, but starts a new file before a row that would take
+    /// the current one past `bytes` bytes, counted before compression.
+    pub fn split_bytes(mut self, bytes: u64) -> Self {
+        self.split = Some(Split::Bytes(bytes));
+        self
+    }
+
+    /// Sets the directory for spill files (the system temp directory by
+    /// default).
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+
+    pub fn build(self) -> Result<CsvProcessor, Error> {
+        // With a header, a plan that selects nothing outputs every column
+        if !self.has_header && !self.plan.steps.iter().any(|step| step.has_selection()) {
+            return Err(Error::Config("no output columns selected".to_string()));
         }
-        
-        if chunk_boundaries.last() != Some(&mmap.len()) {
-            chunk_boundaries.push(mmap.len());
+
+        if !self.sort.is_empty() {
+            if self.dedup.is_some() {
+                return Err(Error::Config("--dedup can not be combined with sort".to_string()));
+            }
+            if self.plan.steps.last().is_some_and(|step| step.empty == EmptyPolicy::Skip) {
+                return Err(Error::Config("--empty skip would shift the sort key columns".to_string()));
+            }
         }
-        
-        let processed_count = AtomicUsize::new(0);
-        
-        // Process chunks in parallel
-        let results: Vec<_> = chunk_boundaries
-            .windows(2)
-            .map(|bounds| {
-                let start = bounds[0];
-                let end = bounds[1];
-                &mmap[start..end]
-            })
-            .collect();
-            
-        let output_data: Vec<_> = results
-            .into_par_iter()
-            .map(|chunk| self.process_chunk(chunk))
-            .collect();
-        
-        // Write results sequentially to maintain order
-        let mut writer = BufWriter::new(File::create(output_path)?);
-        for data in output_data {
-            if !data.is_empty() {
-                writer.write_all(&data)?;
-                processed_count.fetch_add(
-                    data.iter().filter(|&&b| b == b'\n').count(),
-                    Ordering::Relaxed
-                );
+
+        if self.join.is_some() && (self.dedup.is_some() || !self.sort.is_empty()) {
+            return Err(Error::Config("join can not be combined with --dedup or sort".to_string()));
+        }
+        if self.group_by.is_some() && (self.dedup.is_some() || !self.sort.is_empty() || self.join.is_some()) {
+            return Err(Error::Config("groupby can not be combined with --dedup, sort or join".to_string()));
+        }
+
+        let template = match &self.sink {
+            Sink::Path(path) => path.to_string_lossy().into_owned(),
+            Sink::Writer(_) => String::new(),
+        };
+        if self.partition_by.is_some() {
+            if self.split.is_some() {
+                return Err(Error::Config(
+                    "--partition-by can not be combined with --split-rows or --split-bytes".to_string(),
+                ));
+            }
+            if self.dedup.is_some() || !self.sort.is_empty() || self.join.is_some() || self.group_by.is_some() {
+                return Err(Error::Config(
+                    "--partition-by can not be combined with --dedup, sort, join or groupby".to_string(),
+                ));
+            }
+            if !template.contains("{value}") {
+                return Err(Error::Config(
+                    "--partition-by needs an output path with {value}, e.g. out/{value}.csv".to_string(),
+                ));
             }
         }
-        
-        Ok(processed_count.into_inner())
+        if self.split.is_some() {
+            if self.join.is_some() {
+                return Err(Error::Config(
+                    "--split-rows and --split-bytes can not be combined with join".to_string(),
+                ));
+            }
+            if !template.contains("{n}") {
+                return Err(Error::Config(
+                    "--split-rows and --split-bytes need an output path with {n}, e.g. out/part-{n}.csv".to_string(),
+                ));
+            }
+        }
+
+        let compression = match (self.compression, &self.sink) {
+            (Some(compression), _) => compression,
+            (None, Sink::Path(path)) => Compression::from_path(path),
+            (None, Sink::Writer(_)) => Compression::None,
+        };
+        if let Some(level) = self.compression_level {
+            if compression != Compression::None && !compression.levels().contains(&level) {
+                return Err(Error::Config(format!(
+                    "compression level for {:?} must be between {} and {}, got {}",
+                    compression,
+                    compression.levels().start(),
+                    compression.levels().end(),
+                    level
+                )));
+            }
+        }
+        if self.format.format == Format::Parquet {
+            if self.plan.steps.last().is_some_and(|step| step.empty == EmptyPolicy::Skip) {
+                return Err(Error::Config(
+                    "--empty skip would shift columns and is not supported for parquet output".to_string(),
+                ));
+            }
+            if compression != Compression::None {
+                return Err(Error::Config(
+                    "parquet compresses internally; use --parquet-compression instead of --compress".to_string(),
+                ));
+            }
+            if self.dedup.is_some() {
+                return Err(Error::Config("--dedup is not supported for parquet output".to_string()));
+            }
+            if !self.sort.is_empty() {
+                return Err(Error::Config("sort is not supported for parquet output".to_string()));
+            }
+            if self.join.is_some() {
+                return Err(Error::Config("join is not supported for parquet output".to_string()));
+            }
+            if self.group_by.is_some() {
+                return Err(Error::Config("groupby is not supported for parquet output".to_string()));
+            }
+            if self.partition_by.is_some() || self.split.is_some() {
+                return Err(Error::Config(
+                    "--partition-by and --split-rows/--split-bytes are not supported for parquet output".to_string(),
+                ));
+            }
+        }
+
+        Ok(CsvProcessor {
+            engine: Engine {
+                tokenizer: Tokenizer::new(self.delimiter, self.quote, self.escape),
+                has_header: self.has_header,
+                chunk_size: self.chunk_size,
+                max_memory: self.max_memory,
+                compression,
+                compression_level: self.compression_level,
+                format: self.format,
+                parquet: self.parquet,
+                temp_dir: self.temp_dir,
+                max_open_files: self.max_open_files,
+            },
+            plan: self.plan,
+            sink: self.sink,
+            on_error: self.on_error,
+            rejects: self.rejects,
+            progress: self.progress,
+            dedup: self.dedup,
+            sort: self.sort,
+            join: self.join,
+            group_by: self.group_by,
+            partition_by: self.partition_by,
+            split: self.split,
+        })
     }
+}
 
-    pub fn process_file_with_filter(
-        &self,
-        input_path: &Path,
-        output_path: &Path,
-        progress_counter: &std::sync::Arc<std::sync::atomic::AtomicUsize>,
-        fields_to_extract: &[usize],
-        filter_equal: Option<(usize, usize)>,
-    ) -> Result<usize, Box<dyn std::error::Error>> {
-        let file = File::open(input_path)?;
-        let mmap = unsafe { Mmap::map(&file)? };
-        
-        // Find line boundaries for parallel processing
-        let chunk_size = mmap.len() / rayon::current_num_threads().max(1);
-        let mut chunk_boundaries = vec![0];
-        
-        let mut pos = 0;
-        while pos < mmap.len() {
-            let end = (pos + chunk_size).min(mmap.len());
-            let boundary = self.find_line_boundary(&mmap, end);
-            chunk_boundaries.push(boundary);
-            pos = boundary;
+impl CsvProcessor {
+    pub fn builder() -> CsvProcessorBuilder {
+        CsvProcessorBuilder::default()
+    }
+
+    /// Processes `source` into the sink. Column names are resolved against
+    /// the header of the source.
+    pub fn process(self, source: impl Into<Source>) -> Result<ProcessStats, Error> {
+        let format = self.engine.format.format;
+        // A Parquet file cut short by an error can not be read at all
+        let partial = match (&self.sink, format) {
+            (Sink::Path(path), Format::Parquet) if path.as_os_str() != "-" => Some(path.clone()),
+            _ => None,
+        };
+        let result = self.execute(source, |engine, input, output, names, run, rejects| match format {
+            Format::Parquet => match input {
+                Input::Mapped { mmap, data_start, .. } => {
+                    let chunks = engine.tokenizer.chunks(&mmap[data_start..], engine.chunk_size);
+                    engine.write_parquet(chunks.map(Ok::<_, io::Error>), output, names, run, rejects)
+                }
+                Input::Stream { reader, .. } => engine.write_parquet(reader, output, names, run, rejects),
+                Input::Columnar { batches, .. } => engine.write_parquet(batches, output, names, run, rejects),
+            },
+            _ => engine.write_text(input, output, run, rejects),
+        });
+        match (result, partial) {
+            (Ok(((), stats)), _) => Ok(stats),
+            (Err(e), Some(path)) => {
+                let _ = std::fs::remove_file(path);
+                Err(e)
+            }
+            (Err(e), None) => Err(e),
         }
-        
-        if chunk_boundaries.last() != Some(&mmap.len()) {
-            chunk_boundaries.push(mmap.len());
+    }
+
+    /// Profiles the output columns of `source` in one parallel pass and
+    /// writes a report with one row per column to the sink, as CSV or JSON.
+    /// Up to `top_k` most frequent values are reported per column.
+    ///
+    /// ```no_run
+    /// use pulsecsv::CsvProcessor;
+    ///
+    /// let profile = CsvProcessor::builder()
+    ///     .sink("profile.csv")
+    ///     .build()?
+    ///     .profile("users.csv", 5)?;
+    /// for column in &profile.columns {
+    ///     eprintln!("{}: ~{} distinct", column.name, column.distinct);
+    /// }
+    /// # Ok::<(), pulsecsv::Error>(())
+    /// ```
+    pub fn profile(self, source: impl Into<Source>, top_k: usize) -> Result<Profile, Error> {
+        if self.engine.format.format == Format::Parquet {
+            return Err(Error::Config("column statistics are written as csv or json".to_string()));
         }
-        
-        // Process chunks in parallel with filtering
-        let results: Vec<_> = chunk_boundaries
-            .windows(2)
-            .map(|bounds| {
-                let start = bounds[0];
-                let end = bounds[1];
-                &mmap[start..end]
-            })
-            .collect();
-            
-        let output_data: Vec<_> = results
-            .into_par_iter()
-            .map(|chunk| {
-                let result = self.process_chunk_with_filter(chunk, fields_to_extract, filter_equal);
-                progress_counter.fetch_add(
-                    result.iter().filter(|&&b| b == b'\n').count(),
-                    Ordering::Relaxed
-                );
-                result
-            })
-            .collect();
-        
-        // Write results sequentially to maintain order
-        let mut writer = BufWriter::new(File::create(output_path)?);
-        for data in output_data {
-            if !data.is_empty() {
-                writer.write_all(&data)?;
+        if self.dedup.is_some() || !self.sort.is_empty() || self.join.is_some() || self.group_by.is_some() {
+            return Err(Error::Config("--dedup, sort, join and groupby are not supported with stats".to_string()));
+        }
+        if self.partition_by.is_some() || self.split.is_some() {
+            return Err(Error::Config(
+                "column statistics are written to one file, not partitioned or split".to_string(),
+            ));
+        }
+        let (columns, stats) = self.execute(source, |engine, input, output, names, run, rejects| {
+            engine.write_profile(input, output, names, top_k, run, rejects)
+        })?;
+        Ok(Profile { columns, stats })
+    }
+
+    /// Opens `source`, resolves the plan against its header and runs
+    /// `write` on the opened input and sink, collecting the stats.
+    fn execute<R>(
+        self,
+        source: impl Into<Source>,
+        write: impl FnOnce(&Engine, Input, Box<dyn Write + Send>, &[Vec<u8>], &Run, &mut Rejects) -> Result<R, Error>,
+    ) -> Result<(R, ProcessStats), Error> {
+        let CsvProcessor {
+            engine,
+            plan,
+            sink,
+            on_error,
+            rejects,
+            progress,
+            dedup,
+            sort,
+            join,
+            group_by,
+            partition_by,
+            split,
+        } = self;
+        let start = Instant::now();
+        let input = match source.into() {
+            Source::Path(path) => Input::open(&path, engine.tokenizer, engine.has_header, engine.chunk_size)?,
+            Source::Reader(reader) => {
+                Input::from_reader(reader, engine.tokenizer, engine.has_header, engine.chunk_size)?
             }
+        };
+        let input_bytes = input.size();
+        let progress = progress.unwrap_or_default();
+        // Bytes are only counted for text; the size of a compressed or
+        // columnar input says nothing about how much of it is done
+        match input {
+            Input::Mapped { .. } => progress.start(input_bytes, input.data_position().0),
+            _ => progress.start(None, input.data_position().0),
         }
-        
-        Ok(progress_counter.load(Ordering::Relaxed))
+
+        let Resolved { stages, output, mut names } = plan.resolve(input.header())?;
+        // Dedup, sort and groupby keys are output columns
+        let header = input.header().map(|_| Header::from_names(names.clone()));
+        let resolve = |column: &Column| match column.resolve(header.as_ref())? {
+            index if index < names.len() => Ok(index),
+            index => Err(Error::Config(format!(
+                "key column {} is out of range for {} output columns",
+                index,
+                names.len()
+            ))),
+        };
+        let dedup = match dedup {
+            Some((columns, keep)) => {
+                let mut key = vec![columns.is_empty(); names.len()];
+                for column in &columns {
+                    key[resolve(column)?] = true;
+                }
+                Some((key, keep))
+            }
+            None => None,
+        };
+        let sort = match sort.is_empty() {
+            true => None,
+            false => {
+                let keys = sort
+                    .iter()
+                    .map(|key| Ok((resolve(&key.column)?, key)))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Some(SortOrder::new(names.len(), &keys))
+            }
+        };
+        let group_by = match group_by {
+            Some((columns, aggregates)) => {
+                let by = columns.iter().map(&resolve).collect::<Result<Vec<_>, Error>>()?;
+                let aggregates = aggregates
+                    .iter()
+                    .map(|aggregate| match &aggregate.column {
+                        Some(column) => Ok((Some(resolve(column)?), aggregate)),
+                        None if aggregate.function == Function::Count => Ok((None, aggregate)),
+                        None => Err(Error::Config(format!("{} needs a column", aggregate.function.name()))),
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Some(Grouping::new(&names, by, &aggregates))
+            }
+            None => None,
+        };
+        // Partition and split files are named by the sink path
+        let template = match &sink {
+            Sink::Path(path) => path.to_string_lossy().into_owned(),
+            Sink::Writer(_) => String::new(),
+        };
+        let files = match (partition_by, split) {
+            (Some(column), _) => Some(FileOutput::Partition {
+                column: resolve(&column)?,
+                template,
+            }),
+            (None, Some(split)) => Some(FileOutput::Split { split, template }),
+            (None, None) => None,
+        };
+        // The join is counted as the last filter, for the rows it drops
+        let mut filter_names = plan.filter_names();
+        let mut rejects = Rejects::new(on_error, rejects, input.data_position())?;
+        let join = match join {
+            Some(join) => {
+                let table = engine.join_table(join, &names, input.size(), &mut rejects, resolve)?;
+                names.extend(table.names.iter().cloned());
+                filter_names.push(format!("{} join", table.kind()));
+                Some((table, filter_names.len() - 1))
+            }
+            None => None,
+        };
+        // Grouping writes its own columns
+        if let Some(grouping) = &group_by {
+            names = grouping.names.clone();
+        }
+        let run = Run {
+            stages: &stages,
+            output: &output,
+            rows: RowWriter::new(engine.format, names.clone(), input.header().is_some()),
+            progress: &progress,
+            detailed: rejects.detailed(),
+            audited: rejects.audited(),
+            dedup,
+            sort,
+            join,
+            group_by,
+            files,
+            counters: Mutex::default(),
+        };
+        let output: Box<dyn Write + Send> = match run.files {
+            Some(_) => Box::new(io::sink()),
+            None => sink.open()?,
+        };
+
+        let result = write(&engine, input, output, &names, &run, &mut rejects)?;
+        let rows_rejected = rejects.finish()?;
+        let counters = run.counters.into_inner().unwrap();
+        let mut rows_filtered: Vec<_> = filter_names.into_iter().map(|name| (name, 0)).collect();
+        for ((_, total), rows) in rows_filtered.iter_mut().zip(counters.filtered) {
+            *total = rows;
+        }
+
+        let stats = ProcessStats {
+            rows_read: progress.rows_read(),
+            rows_written: progress.rows_written() - counters.duplicates,
+            rows_rejected,
+            rows_filtered,
+            rows_dropped_empty: counters.dropped_empty,
+            rows_blank: counters.blank,
+            rows_duplicate: counters.duplicates,
+            input_bytes,
+            bytes_read: progress.bytes_read(),
+            threads: counters.threads.into_values().collect(),
+            elapsed: start.elapsed(),
+        };
+        Ok((result, stats))
+    }
+}
+
+impl Engine {
+    /// Number of chunks that may be in flight without exceeding the memory
+    /// ceiling. Each chunk's output is at most about the size of its input.
+    fn max_chunks_in_flight(&self) -> usize {
+        (self.max_memory / self.chunk_size).max(1)
     }
 
-    fn process_chunk_with_filter(
+    /// Writes the kept rows as CSV or JSON.
+    fn write_text(
         &self,
-        chunk: &[u8],
-        fields_to_extract: &[usize],
-        filter_equal: Option<(usize, usize)>,
-    ) -> Vec<u8> {
-        let mut result = Vec::new();
-        let mut lines = chunk.split(|&b| b == b'\n');
-        
-        // Skip header if this is the first chunk
-        let skip_header = chunk == &chunk[0..];
-        if skip_header {
-            let _ = lines.next();
+        input: Input,
+        output: Box<dyn Write + Send>,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<(), Error> {
+        let mut writer = BufWriter::new(output);
+        let mut written = 0;
+        let begin = run.rows.begin();
+        if !begin.is_empty() {
+            let data = self.compression.compress(begin, self.compression_level)?;
+            writer.write_all(&data)?;
+            written += data.len();
         }
         
-        for line in lines {
-            if line.is_empty() {
-                continue;
+        // Process record-aligned chunks in parallel and write them in order
+        // as soon as they are ready
+        match input {
+            Input::Mapped { mmap, data_start, .. } => {
+                let chunks = self.tokenizer.chunks(&mmap[data_start..], self.chunk_size);
+                written += self.run_chunks(chunks.map(Ok::<_, io::Error>), &mut writer, run, rejects)?;
             }
-            
-            if let Some(extracted) = self.extract_and_filter(line, fields_to_extract, filter_equal) {
-                result.extend_from_slice(&extracted);
-                result.push(b'\n');
+            Input::Stream { reader, .. } => {
+                written += self.run_chunks(reader, &mut writer, run, rejects)?;
+            }
+            Input::Columnar { batches, .. } => {
+                written += self.run_chunks(batches, &mut writer, run, rejects)?;
             }
         }
         
-        result
+        let end = run.rows.end();
+        if !end.is_empty() || (written == 0 && self.compression != Compression::None) {
+            // An empty file is not a valid compressed stream either
+            writer.write_all(&self.compression.compress(end.to_vec(), self.compression_level)?)?;
+        }
+        writer.flush()?;
+        Ok(())
     }
 
-    fn extract_and_filter(
+    fn run_chunks<U, T, E>(
         &self,
-        line: &[u8],
-        fields_to_extract: &[usize],
-        filter_equal: Option<(usize, usize)>,
-    ) -> Option<Vec<u8>> {
-        let fields: Vec<&[u8]> = line.split(|&b| b == self.delimiter).collect();
-        
-        // Skip if we don't have enough fields
-        if fields.len() <= fields_to_extract.iter().max().copied().unwrap_or(0) {
-            return None;
+        chunks: U,
+        writer: &mut impl Write,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<usize, Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        if let Some(FileOutput::Partition { column, template }) = &run.files {
+            return self.partition_chunks(chunks, *column, template, run, rejects);
         }
-        
-        // Apply filter if specified
-        if let Some((col1, col2)) = filter_equal {
-            if col1 < fields.len() && col2 < fields.len() && fields[col1] == fields[col2] {
-                return None;
-            }
+        if run.dedup.is_some() {
+            return self.dedup_chunks(chunks, writer, run, rejects);
         }
-        
-        // Extract requested fields
-        let mut result = Vec::new();
-        for (i, &field_idx) in fields_to_extract.iter().enumerate() {
-            if field_idx < fields.len() {
-                if !fields[field_idx].is_empty() {
-                    if i > 0 {
-                        result.push(b',');
+        if run.sort.is_some() {
+            return self.sort_chunks(chunks, writer, run, rejects);
+        }
+        if run.group_by.is_some() {
+            return self.group_chunks(chunks, writer, run, rejects);
+        }
+        if run.join.as_ref().is_some_and(|(table, _)| table.is_spilled()) {
+            return self.join_spilled_chunks(chunks, writer, run, rejects);
+        }
+        if run.join.as_ref().is_some_and(|(table, _)| table.is_built_from_left()) {
+            return self.join_left_chunks(chunks, writer, run, rejects);
+        }
+        if run.files.is_some() {
+            return self.split_chunks(chunks, writer, run, rejects);
+        }
+        let separator = self.compression.compress(run.rows.row_separator().to_vec(), self.compression_level)?;
+        let mut written = 0;
+        let mut rows_written = 0;
+        pipeline::run_ordered(
+            chunks,
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let chunk = chunk.map_err(Into::into)?;
+                let (out, report) = match &run.join {
+                    Some((table, filter)) => {
+                        let mut rows = JoinRows::new(&run.rows, table);
+                        let mut report = chunk.process(self, run, &mut rows)?;
+                        report.kept = rows.written;
+                        report.filter_rows(*filter, rows.unmatched);
+                        (rows.out, report)
+                    }
+                    None => {
+                        let mut text = TextRows::new(&run.rows);
+                        let report = chunk.process(self, run, &mut text)?;
+                        (text.out, report)
                     }
-                    result.extend_from_slice(fields[field_idx]);
+                };
+                let data = match out.is_empty() {
+                    true => out,
+                    false => self.compression.compress(out, self.compression_level)?,
+                };
+                run.record(&report, started);
+                Ok((data, report))
+            },
+            |data: Result<(Vec<u8>, ChunkReport), Error>| -> Result<(), Error> {
+                let (data, report) = data?;
+                rejects.handle(&report)?;
+                let rows = report.kept;
+                // Chunks are serialized independently, so the separator
+                // between their rows is added here
+                if rows > 0 && rows_written > 0 && !run.rows.row_separator().is_empty() {
+                    writer.write_all(&separator)?;
+                    written += separator.len();
                 }
-            }
+                rows_written += rows;
+                written += data.len();
+                writer.write_all(&data)?;
+                Ok(())
+            },
+        )?;
+        Ok(written)
+    }
+
+    /// Writes the kept rows without repeated keys. Workers serialize the rows
+    /// and their keys, and the writer stage drops the repeats in input
+    /// order, writing the rows one batch at a time.
+    fn dedup_chunks<U, T, E>(
+        &self,
+        chunks: U,
+        writer: &mut impl Write,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<usize, Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        let (key, keep) = run.dedup.as_ref().expect("dedup is configured");
+        let mut dedup = Deduplicator::new(*keep, self.max_memory, &self.temp_dir, run.progress.total_bytes());
+        let mut out = self.emitter(writer, run)?;
+
+        pipeline::run_ordered(
+            chunks,
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let mut rows = KeyedRows::new(&run.rows, key);
+                let report = chunk.map_err(Into::into)?.process(self, run, &mut rows)?;
+                run.record(&report, started);
+                Ok((rows, report))
+            },
+            |rows: Result<(KeyedRows, ChunkReport), Error>| -> Result<(), Error> {
+                let (rows, report) = rows?;
+                rejects.handle(&report)?;
+                for (key, row) in rows.iter() {
+                    dedup.push(key, row, &mut |row| out.emit(row))?;
+                }
+                out.flush()
+            },
+        )?;
+        let duplicates = dedup.finish(&mut |row| out.emit(row))?;
+        run.counters.lock().unwrap().duplicates = duplicates;
+        out.finish()
+    }
+
+    /// Writes the kept rows in sorted order. Each chunk is sorted on its
+    /// worker as a run; the writer stage collects the runs in input order
+    /// and merges them, through spill files if they exceed `max_memory`.
+    fn sort_chunks<U, T, E>(
+        &self,
+        chunks: U,
+        writer: &mut impl Write,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<usize, Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        let order = run.sort.as_ref().expect("sort is configured");
+        let mut sorter = Sorter::new(self.max_memory, &self.temp_dir);
+        let mut out = self.emitter(writer, run)?;
+
+        pipeline::run_ordered(
+            chunks,
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let mut rows = KeyedRows::new(&run.rows, &order.columns);
+                let report = chunk.map_err(Into::into)?.process(self, run, &mut rows)?;
+                let sorted = SortedChunk::new(rows.text.out, &rows.keys, &rows.ends, order);
+                run.record(&report, started);
+                Ok((sorted, report))
+            },
+            |sorted: Result<(SortedChunk, ChunkReport), Error>| -> Result<(), Error> {
+                let (sorted, report) = sorted?;
+                rejects.handle(&report)?;
+                sorter.push(sorted)
+            },
+        )?;
+        sorter.finish(&mut |row| out.emit(row))?;
+        out.finish()
+    }
+
+    /// Writes one row per group of the kept rows. Each chunk is aggregated
+    /// on its worker, and the writer stage merges the partial groups in
+    /// input order.
+    fn group_chunks<U, T, E>(
+        &self,
+        chunks: U,
+        writer: &mut impl Write,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<usize, Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        let grouping = run.group_by.as_ref().expect("groupby is configured");
+        let mut aggregator = Aggregator::new(grouping, self.max_memory, &self.temp_dir, run.progress.total_bytes());
+        let mut out = self.emitter(writer, run)?;
+
+        pipeline::run_ordered(
+            chunks,
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let mut groups = PartialGroups::new(grouping);
+                let mut report = chunk.map_err(Into::into)?.process(self, run, &mut groups)?;
+                // Groups are counted as written once they are merged
+                report.kept = 0;
+                run.record(&report, started);
+                Ok((groups, report))
+            },
+            |groups: Result<(PartialGroups, ChunkReport), Error>| -> Result<(), Error> {
+                let (groups, report) = groups?;
+                rejects.handle(&report)?;
+                aggregator.push(groups)
+            },
+        )?;
+        let groups = aggregator.finish(&run.rows, &mut |row| out.emit(row))?;
+        run.progress.add(0, 0, groups, 0);
+        out.finish()
+    }
+
+    /// Writes the kept rows joined with a right side that was spilled to
+    /// disk. Workers capture the rows with their keys, the writer stage
+    /// partitions them like the right side, and each partition is then
+    /// joined in memory and merged back into input order.
+    fn join_spilled_chunks<U, T, E>(
+        &self,
+        chunks: U,
+        writer: &mut impl Write,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<usize, Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        let (table, filter) = run.join.as_ref().expect("join is configured");
+        let mut probe = table.spilled_probe()?;
+        let mut out = self.emitter(writer, run)?;
+
+        pipeline::run_ordered(
+            chunks,
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let mut rows = JoinRows::new(&run.rows, table);
+                let mut report = chunk.map_err(Into::into)?.process(self, run, &mut rows)?;
+                // Rows are counted as written once they are joined
+                report.kept = 0;
+                run.record(&report, started);
+                Ok((rows, report))
+            },
+            |rows: Result<(JoinRows, ChunkReport), Error>| -> Result<(), Error> {
+                let (rows, report) = rows?;
+                rejects.handle(&report)?;
+                for (key, row) in rows.spilled {
+                    probe.push(&key, &row)?;
+                }
+                Ok(())
+            },
+        )?;
+        let (written, unmatched) = probe.finish(&run.rows, &mut |row| out.emit(row))?;
+        let bytes = out.finish()?;
+        run.progress.add(0, 0, written, 0);
+        let mut counters = run.counters.lock().unwrap();
+        if counters.filtered.len() <= *filter {
+            counters.filtered.resize(filter + 1, 0);
         }
-        
-        if result.is_empty() {
-            None
-        } else {
-            Some(result)
+        counters.filtered[*filter] += unmatched;
+        Ok(bytes)
+    }
+
+    /// Writes the kept rows joined with a right side larger than them.
+    /// Workers capture the rows with their keys, and once all are in, the
+    /// right side is matched against their keys in parallel chunks and the
+    /// rows are joined in input order.
+    fn join_left_chunks<U, T, E>(
+        &self,
+        chunks: U,
+        writer: &mut impl Write,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<usize, Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        let (table, filter) = run.join.as_ref().expect("join is configured");
+        let mut build = table.left_build(self.chunk_size, self.max_chunks_in_flight());
+        let mut out = self.emitter(writer, run)?;
+
+        pipeline::run_ordered(
+            chunks,
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let mut rows = JoinRows::new(&run.rows, table);
+                let mut report = chunk.map_err(Into::into)?.process(self, run, &mut rows)?;
+                // Rows are counted as written once they are joined
+                report.kept = 0;
+                run.record(&report, started);
+                Ok((rows, report))
+            },
+            |rows: Result<(JoinRows, ChunkReport), Error>| -> Result<(), Error> {
+                let (rows, report) = rows?;
+                rejects.handle(&report)?;
+                for (key, row) in rows.spilled {
+                    build.push(key, row);
+                }
+                Ok(())
+            },
+        )?;
+        let (written, unmatched) = build.finish(&run.rows, rejects, &mut |row| out.emit(row))?;
+        let bytes = out.finish()?;
+        run.progress.add(0, 0, written, 0);
+        let mut counters = run.counters.lock().unwrap();
+        if counters.filtered.len() <= *filter {
+            counters.filtered.resize(filter + 1, 0);
         }
+        counters.filtered[*filter] += unmatched;
+        Ok(bytes)
     }
-    
-    fn find_line_boundary(&self, data: &[u8], mut pos: usize) -> usize {
-        while pos < data.len() && data[pos] != b'\n' {
-            pos += 1;
+
+    /// Writes the kept rows to numbered files, starting the next file when
+    /// the current one is full. Workers serialize the rows and the writer
+    /// stage hands them over one at a time, in input order.
+    fn split_chunks<U, T, E>(
+        &self,
+        chunks: U,
+        writer: &mut impl Write,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<usize, Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        let key = vec![false; run.rows.columns()];
+        let mut out = self.emitter(writer, run)?;
+
+        pipeline::run_ordered(
+            chunks,
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let mut rows = KeyedRows::new(&run.rows, &key);
+                let report = chunk.map_err(Into::into)?.process(self, run, &mut rows)?;
+                run.record(&report, started);
+                Ok((rows, report))
+            },
+            |rows: Result<(KeyedRows, ChunkReport), Error>| -> Result<(), Error> {
+                let (rows, report) = rows?;
+                rejects.handle(&report)?;
+                for (_, row) in rows.iter() {
+                    out.emit(row)?;
+                }
+                out.flush()
+            },
+        )?;
+        out.finish()
+    }
+
+    /// Writes the kept rows to one file per value of output `column`. Each
+    /// worker groups the rows of its chunk by value and compresses each
+    /// group, and the writer stage appends the groups to their files in
+    /// input order.
+    fn partition_chunks<U, T, E>(
+        &self,
+        chunks: U,
+        column: usize,
+        template: &str,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<usize, Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        let mut files = OutputFiles::new(&run.rows, self.compression, self.compression_level, self.max_open_files)?;
+        let mut written = 0;
+        pipeline::run_ordered(
+            chunks,
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let mut rows = PartitionedRows::new(&run.rows, column);
+                let report = chunk.map_err(Into::into)?.process(self, run, &mut rows)?;
+                for (_, out, _) in &mut rows.parts {
+                    *out = self.compression.compress(std::mem::take(out), self.compression_level)?;
+                }
+                run.record(&report, started);
+                Ok((rows, report))
+            },
+            |rows: Result<(PartitionedRows, ChunkReport), Error>| -> Result<(), Error> {
+                let (rows, report) = rows?;
+                rejects.handle(&report)?;
+                for (value, data, rows) in rows.parts {
+                    let path = partition::file_path(template, "{value}", &partition::file_name(&value));
+                    files.write(&path, &data, rows)?;
+                    written += data.len();
+                }
+                Ok(())
+            },
+        )?;
+        files.finish()?;
+        Ok(written)
+    }
+
+    /// A `RowEmitter` for `writer`, or for the numbered files of a split run.
+    fn emitter<'a, W>(&'a self, writer: &'a mut W, run: &Run) -> Result<RowEmitter<'a, W>, Error> {
+        let files = match &run.files {
+            Some(FileOutput::Split { split, template }) => {
+                let files = OutputFiles::new(&run.rows, self.compression, self.compression_level, self.max_open_files)?;
+                Some(SplitFiles::new(files, template.clone(), *split, &run.rows))
+            }
+            _ => None,
+        };
+        Ok(RowEmitter {
+            engine: self,
+            writer,
+            separator: run.rows.row_separator(),
+            pending: Vec::new(),
+            rows: 0,
+            pending_rows: 0,
+            written: 0,
+            files,
+        })
+    }
+
+    /// Opens the right side of `join` and loads it into a hash table, or
+    /// leaves it to be streamed when an inner or semi join has fewer bytes
+    /// of output rows, `left_size`, than of right rows. `resolve` finds the
+    /// output column of a key.
+    fn join_table(
+        &self,
+        join: Join,
+        names: &[Vec<u8>],
+        left_size: Option<u64>,
+        rejects: &mut Rejects,
+        resolve: impl Fn(&Column) -> Result<usize, Error>,
+    ) -> Result<JoinTable, Error> {
+        let right_on = match join.right_on.is_empty() {
+            true => &join.on,
+            false => &join.right_on,
+        };
+        if join.on.is_empty() {
+            return Err(Error::Config("join needs at least one key column".to_string()));
         }
-        if pos < data.len() {
-            pos + 1
-        } else {
-            data.len()
+        if right_on.len() != join.on.len() {
+            return Err(Error::Config(format!(
+                "join has {} key columns but {} right key columns",
+                join.on.len(),
+                right_on.len()
+            )));
         }
+
+        let input = match join.source {
+            Source::Path(path) => Input::open(&path, self.tokenizer, self.has_header, self.chunk_size)?,
+            Source::Reader(reader) => Input::from_reader(reader, self.tokenizer, self.has_header, self.chunk_size)?,
+        };
+        let keys = join
+            .on
+            .iter()
+            .zip(right_on)
+            .map(|(left, right)| Ok((resolve(left)?, right.resolve(input.header())?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        // An unread right side has the width of its header
+        let build_left = match (left_size, input.size()) {
+            (Some(left), Some(right)) => {
+                matches!(join.kind, JoinType::Inner | JoinType::Semi)
+                    && (join.kind == JoinType::Semi || input.header().is_some())
+                    && left < right
+                    && left <= self.max_memory as u64
+            }
+            _ => false,
+        };
+        JoinTable::build(
+            input,
+            &self.tokenizer,
+            join.kind,
+            keys,
+            names.len(),
+            self.max_memory,
+            &self.temp_dir,
+            build_left,
+            rejects,
+        )
     }
-    
-    fn process_chunk(&self, chunk: &[u8]) -> Vec<u8> {
-        let mut result = Vec::new();
-        let mut lines = chunk.split(|&b| b == b'\n');
-        
-        // Skip header if this is the first chunk
-        let skip_header = chunk == &chunk[0..];
-        if skip_header {
-            let _ = lines.next();
+
+    /// Writes the kept rows as Parquet. Each chunk becomes an Arrow record
+    /// batch on the worker threads, and the writer stage appends the batches
+    /// in order, cutting row groups at the configured size.
+    fn write_parquet<U, T, E>(
+        &self,
+        mut chunks: U,
+        output: Box<dyn Write + Send>,
+        names: &[Vec<u8>],
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<(), Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        // Column types not given explicitly are inferred from the first chunk
+        let first = chunks.next().transpose().map_err(Into::into)?;
+        let mut sampler = TypeSampler::new(names.len());
+        if let Some(chunk) = &first {
+            chunk.process(self, run, &mut sampler)?;
         }
+        let schema = ColumnSchema::new(names, sampler.finish(), &self.parquet.schema)?;
+
+        let properties = self.parquet.writer_properties(self.compression_level)?;
+        let mut writer = ArrowWriter::try_new(BufWriter::new(output), schema.arrow(), Some(properties))?;
+        pipeline::run_ordered(
+            first.map(Ok).into_iter().chain(chunks),
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let mut batch = BatchBuilder::new(&schema);
+                let report = chunk.map_err(Into::into)?.process(self, run, &mut batch)?;
+                let batch = batch.finish()?;
+                run.record(&report, started);
+                Ok((batch, report))
+            },
+            |batch: Result<(RecordBatch, ChunkReport), Error>| -> Result<(), Error> {
+                let (batch, report) = batch?;
+                rejects.handle(&report)?;
+                writer.write(&batch)?;
+                Ok(())
+            },
+        )?;
+        writer.close()?;
+        Ok(())
+    }
+
+    /// Profiles the kept rows and writes the report. Each chunk is
+    /// profiled on a worker thread and the writer stage merges the partial
+    /// profiles.
+    fn write_profile(
+        &self,
+        input: Input,
+        output: Box<dyn Write + Send>,
+        names: &[Vec<u8>],
+        top_k: usize,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<Vec<ColumnStats>, Error> {
+        let profiler = match input {
+            Input::Mapped { mmap, data_start, .. } => {
+                let chunks = self.tokenizer.chunks(&mmap[data_start..], self.chunk_size);
+                self.profile_chunks(chunks.map(Ok::<_, io::Error>), names.len(), top_k, run, rejects)?
+            }
+            Input::Stream { reader, .. } => self.profile_chunks(reader, names.len(), top_k, run, rejects)?,
+            Input::Columnar { batches, .. } => self.profile_chunks(batches, names.len(), top_k, run, rejects)?,
+        };
+        let columns = profiler.finish(names, top_k);
+
+        let mut writer = BufWriter::new(output);
+        let report = profile::report(&columns, self.format, top_k);
+        writer.write_all(&self.compression.compress(report, self.compression_level)?)?;
+        writer.flush()?;
+        Ok(columns)
+    }
+
+    fn profile_chunks<U, T, E>(
+        &self,
+        chunks: U,
+        columns: usize,
+        top_k: usize,
+        run: &Run,
+        rejects: &mut Rejects,
+    ) -> Result<Profiler, Error>
+    where
+        U: Iterator<Item = Result<T, E>> + Send,
+        T: WorkUnit,
+        E: Into<Error> + Send,
+    {
+        let mut total = Profiler::new(columns, top_k);
+        pipeline::run_ordered(
+            chunks,
+            self.max_chunks_in_flight(),
+            |chunk| {
+                let started = Instant::now();
+                let mut profiler = Profiler::new(columns, top_k);
+                let report = chunk.map_err(Into::into)?.process(self, run, &mut profiler)?;
+                run.record(&report, started);
+                Ok((profiler, report))
+            },
+            |profiler: Result<(Profiler, ChunkReport), Error>| -> Result<(), Error> {
+                let (profiler, report) = profiler?;
+                rejects.handle(&report)?;
+                total.merge(profiler);
+                Ok(())
+            },
+        )?;
+        Ok(total)
+    }
+
+    /// Processes one chunk, adding the kept rows to `buffer`.
+    fn process_chunk_with_filter(&self, chunk: &[u8], run: &Run, buffer: &mut impl RowBuffer) -> ChunkReport {
+        let mut report = ChunkReport::default();
+        let mut fields = Vec::new();
+        let mut staged = Default::default();
         
-        for line in lines {
-            if line.is_empty() {
+        for record in self.tokenizer.records(chunk) {
+            // Records are subslices of the chunk
+            let offset = record.as_ptr() as usize - chunk.as_ptr() as usize;
+            if record.is_empty() {
+                report.blank += 1;
+                report.drop_text(chunk, offset, record, Reason::Blank, run.audited);
                 continue;
             }
             
-            if let Some((email, username)) = self.extract_columns(line) {
-                result.extend_from_slice(email);
-                result.push(b',');
-                result.extend_from_slice(username);
-                result.push(b'\n');
+            report.read += 1;
+            fields.clear();
+            let outcome = match self.tokenizer.split_fields(record, &mut fields) {
+                Some((column, kind)) => Outcome::Malformed(Some(column), kind),
+                None => self.extract_and_filter(&fields, run, report.kept == 0, &mut staged, buffer),
+            };
+            match outcome {
+                Outcome::Kept => report.kept += 1,
+                Outcome::Filtered(index) => report.filter(index),
+                Outcome::Empty => {
+                    report.dropped_empty += 1;
+                    report.drop_text(chunk, offset, record, Reason::Empty, run.audited);
+                }
+                Outcome::Malformed(column, kind) => {
+                    report.reject_text(chunk, offset, record, column, kind, run.detailed);
+                }
             }
         }
         
-        result
+        report.finish_text(chunk, run.detailed);
+        report
     }
-    
-    fn extract_columns<'a>(&self, line: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
-        let mut fields = line.split(|&b| b == self.delimiter);
-        
-        // Skip user_id
-        let _ = fields.next()?;
-        
-        // Get email (column 2)
-        let email = fields.next()?;
+
+    /// Processes the rows of one record batch from a columnar input.
+    fn process_batch(&self, batch: &RecordBatch, run: &Run, buffer: &mut impl RowBuffer) -> Result<ChunkReport, Error> {
+        let mut batch = BatchFields::new(batch)?;
+        let mut report = ChunkReport::default();
+        let mut fields = Vec::new();
+        let mut staged = Default::default();
+
+        for row in 0..batch.num_rows() {
+            batch.read_row(row, &mut fields);
+            match self.extract_and_filter(&fields, run, report.kept == 0, &mut staged, buffer) {
+                Outcome::Kept => report.kept += 1,
+                Outcome::Filtered(index) => report.filter(index),
+                Outcome::Empty => {
+                    report.dropped_empty += 1;
+                    report.drop_row(row, &fields, Reason::Empty, run.audited);
+                }
+                Outcome::Malformed(column, kind) => report.reject_row(row, &fields, column, kind, run.detailed),
+            }
+        }
+
+        report.read = batch.num_rows();
+        report.lines = batch.num_rows() as u64;
+        Ok(report)
+    }
+
+    /// Runs one row through the stages and adds its output values to
+    /// `buffer`.
+    fn extract_and_filter(
+        &self,
+        fields: &[Cow<[u8]>],
+        run: &Run,
+        first: bool,
+        staged: &mut [StagedValues; 2],
+        buffer: &mut impl RowBuffer,
+    ) -> Outcome {
+        // Stages that project write their row to one of `staged`, and the
+        // next stage reads it from there while writing to the other one
+        let mut current = None;
+        for stage in run.stages {
+            let next = match current {
+                Some(0) => 1,
+                _ => 0,
+            };
+            let [a, b] = &mut *staged;
+            let (read, write) = match next {
+                0 => (&*b, a),
+                _ => (&*a, b),
+            };
+            let result = match current {
+                None => Self::apply_stage(stage, run, fields, write),
+                Some(_) => Self::apply_stage(stage, run, read.values(), write),
+            };
+            match result {
+                Ok(true) => current = Some(next),
+                Ok(false) => {}
+                Err(outcome) => return outcome,
+            }
+        }
+
+        match current {
+            None => Self::write_row(fields, run, first, buffer),
+            Some(i) => Self::write_row(staged[i].values(), run, first, buffer),
+        }
+    }
+
+    /// Checks one row against the filters of `stage` and projects it into
+    /// `out` if the stage selects columns. Returns whether it did, or what
+    /// happened to a row that goes no further.
+    fn apply_stage<F: AsRef<[u8]>>(
+        stage: &Stage,
+        run: &Run,
+        current: &[F],
+        out: &mut StagedValues,
+    ) -> Result<bool, Outcome> {
+        let selection = stage.selection.as_ref().unwrap_or(run.output);
+
+        // Rows too short for the selection are rejected unless padding
+        let expected = selection.max_column().map_or(0, |max| max + 1);
+        if current.len() < expected && selection.short_rows == ShortRows::Drop {
+            return Err(Outcome::Malformed(None, ParseErrorKind::TooFewFields { expected, found: current.len() }));
+        }
+
+        // Apply filters if specified
+        if let Some(index) = stage.filter.rejecting_filter(current) {
+            return Err(Outcome::Filtered(stage.first_filter + index));
+        }
+
+        let Some(selection) = &stage.selection else {
+            return Ok(false);
+        };
+        out.clear();
+        if !selection.project_row(current, |_, value| out.push(value)) {
+            return Err(Outcome::Empty);
+        }
+        Ok(true)
+    }
+
+    /// Adds the output values of a row that passed every stage to `buffer`.
+    fn write_row<F: AsRef<[u8]>>(fields: &[F], run: &Run, first: bool, buffer: &mut impl RowBuffer) -> Outcome {
+        // Extract requested fields and captures
+        buffer.start_row(first);
+        let mut written = 0;
+        let kept = run.output.project_row(fields, |column, value| {
+            buffer.write_value(column, written, value);
+            written += 1;
+        });
         
-        // Get username_or_id (column 3)
-        let username = fields.next()?;
+        if !kept {
+            buffer.discard_row();
+            return Outcome::Empty;
+        }
         
-        Some((email, username))
+        buffer.end_row(written);
+        Outcome::Kept
+    }
+}
+
+/// A unit of parallel work: a record-aligned chunk of text, or a record
+/// batch from a columnar input.
+trait WorkUnit: Send {
+    /// Adds the kept rows to `buffer`, returning what happened to the rows.
+    fn process(&self, engine: &Engine, run: &Run, buffer: &mut impl RowBuffer) -> Result<ChunkReport, Error>;
+}
+
+impl WorkUnit for &[u8] {
+    fn process(&self, engine: &Engine, run: &Run, buffer: &mut impl RowBuffer) -> Result<ChunkReport, Error> {
+        Ok(engine.process_chunk_with_filter(self, run, buffer))
+    }
+}
+
+impl WorkUnit for Vec<u8> {
+    fn process(&self, engine: &Engine, run: &Run, buffer: &mut impl RowBuffer) -> Result<ChunkReport, Error> {
+        Ok(engine.process_chunk_with_filter(self, run, buffer))
     }
-}
\ No newline at end of file
+}
+
+impl WorkUnit for RecordBatch {
+    fn process(&self, engine: &Engine, run: &Run, buffer: &mut impl RowBuffer) -> Result<ChunkReport, Error> {
+        engine.process_batch(self, run, buffer)
+    }
+}
+
+/// Receives the values of the rows kept from one chunk.
+pub trait RowBuffer {
+    /// Starts a row; `first` is whether it is the first row of its chunk.
+    fn start_row(&mut self, first: bool);
+    /// Adds the value of output column `column`, which is the `position`-th
+    /// value written for this row.
+    fn write_value(&mut self, column: usize, position: usize, value: &[u8]);
+    /// Ends a row with `written` values.
+    fn end_row(&mut self, written: usize);
+    /// Drops the row started last.
+    fn discard_row(&mut self);
+}
+
+/// Rows serialized as text with the key of each row, for `dedup` and sort. Rows are
+/// serialized as if each were the first of its chunk, since separators are
+/// only known once the repeats are dropped.
+struct KeyedRows<'a> {
+    text: TextRows<'a>,
+    /// Whether each output column is part of the key
+    key: &'a [bool],
+    /// Key values of all rows, each prefixed with its length
+    keys: Vec<u8>,
+    key_start: usize,
+    /// End offsets of each row in `text.out` and `keys`
+    ends: Vec<(usize, usize)>,
+}
+
+impl<'a> KeyedRows<'a> {
+    fn new(rows: &'a RowWriter, key: &'a [bool]) -> Self {
+        Self {
+            text: TextRows::new(rows),
+            key,
+            keys: Vec::new(),
+            key_start: 0,
+            ends: Vec::new(),
+        }
+    }
+
+    /// The key and serialized row of each kept row, in order.
+    fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        let mut start = (0, 0);
+        self.ends.iter().map(move |&(row_end, key_end)| {
+            let item = (&self.keys[start.1..key_end], &self.text.out[start.0..row_end]);
+            start = (row_end, key_end);
+            item
+        })
+    }
+}
+
+impl RowBuffer for KeyedRows<'_> {
+    fn start_row(&mut self, _first: bool) {
+        self.text.start_row(true);
+        self.key_start = self.keys.len();
+    }
+
+    fn write_value(&mut self, column: usize, position: usize, value: &[u8]) {
+        self.text.write_value(column, position, value);
+        if self.key[column] {
+            self.keys.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            self.keys.extend_from_slice(value);
+        }
+    }
+
+    fn end_row(&mut self, written: usize) {
+        self.text.end_row(written);
+        self.ends.push((self.text.out.len(), self.keys.len()));
+    }
+
+    fn discard_row(&mut self) {
+        self.text.discard_row();
+        self.keys.truncate(self.key_start);
+    }
+}
+
+/// Output rows joined with the right side of a join. Left values are
+/// staged until the row is kept, then written once per matching right row.
+struct JoinRows<'a> {
+    rows: &'a RowWriter,
+    table: &'a JoinTable,
+    row: StagedRow,
+    key: Vec<u8>,
+    out: Vec<u8>,
+    /// Rows written to `out`
+    written: usize,
+    /// Kept rows the join wrote nothing for
+    unmatched: usize,
+    /// Encoded rows with their keys, collected instead when the right side
+    /// was spilled or is read last
+    spilled: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> JoinRows<'a> {
+    fn new(rows: &'a RowWriter, table: &'a JoinTable) -> Self {
+        Self {
+            rows,
+            table,
+            row: StagedRow::default(),
+            key: Vec::new(),
+            out: Vec::new(),
+            written: 0,
+            unmatched: 0,
+            spilled: Vec::new(),
+        }
+    }
+}
+
+impl RowBuffer for JoinRows<'_> {
+    fn start_row(&mut self, _first: bool) {
+        self.row.clear();
+    }
+
+    fn write_value(&mut self, column: usize, _position: usize, value: &[u8]) {
+        self.row.push(column, value);
+    }
+
+    fn end_row(&mut self, _written: usize) {
+        self.key.clear();
+        self.table.left_key(&self.row, &mut self.key);
+        if self.table.probes_later() {
+            self.spilled.push((self.key.clone(), join::encode_row(&self.row)));
+            return;
+        }
+        let first = self.written == 0;
+        match self.table.write_joined(self.table.matches(&self.key), &self.row, self.rows, &mut self.out, first) {
+            0 => self.unmatched += 1,
+            n => self.written += n,
+        }
+    }
+
+    fn discard_row(&mut self) {
+        self.row.clear();
+    }
+}
+
+/// Writes rows handed over one at a time by the writer stage, compressed in
+/// batches.
+struct RowEmitter<'a, W> {
+    engine: &'a Engine,
+    writer: &'a mut W,
+    separator: &'static [u8],
+    pending: Vec<u8>,
+    rows: usize,
+    /// Rows in `pending`
+    pending_rows: usize,
+    /// Bytes written so far
+    written: usize,
+    /// Numbered files that take the rows instead of `writer`
+    files: Option<SplitFiles>,
+}
+
+impl<W: Write> RowEmitter<'_, W> {
+    fn emit(&mut self, row: &[u8]) -> Result<(), Error> {
+        if self.files.as_ref().is_some_and(|files| files.is_full(row.len())) {
+            self.flush()?;
+            self.files.as_mut().unwrap().next_file()?;
+        }
+        // Split files add the separator between batches themselves
+        let separated = match self.files {
+            Some(_) => self.pending_rows > 0,
+            None => self.rows > 0,
+        };
+        if separated {
+            self.pending.extend_from_slice(self.separator);
+        }
+        self.pending.extend_from_slice(row);
+        self.rows += 1;
+        self.pending_rows += 1;
+        if let Some(files) = &mut self.files {
+            files.count(row.len());
+        }
+        // Rows merged from spill files arrive all at once
+        if self.pending.len() >= self.engine.chunk_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            let data = self.engine.compression.compress(pending, self.engine.compression_level)?;
+            match &mut self.files {
+                Some(files) => files.write(&data, self.pending_rows)?,
+                None => self.writer.write_all(&data)?,
+            }
+            self.written += data.len();
+            self.pending_rows = 0;
+        }
+        Ok(())
+    }
+
+    /// Flushes the last rows and finishes the split files, returning the
+    /// bytes written.
+    fn finish(mut self) -> Result<usize, Error> {
+        self.flush()?;
+        if let Some(files) = self.files.take() {
+            files.finish()?;
+        }
+        Ok(self.written)
+    }
+}
+
+/// Rows serialized as text by a `RowWriter`.
+struct TextRows<'a> {
+    rows: &'a RowWriter,
+    out: Vec<u8>,
+    row_start: usize,
+    values_start: usize,
+}
+
+impl<'a> TextRows<'a> {
+    fn new(rows: &'a RowWriter) -> Self {
+        Self {
+            rows,
+            out: Vec::new(),
+            row_start: 0,
+            values_start: 0,
+        }
+    }
+}
+
+impl RowBuffer for TextRows<'_> {
+    fn start_row(&mut self, first: bool) {
+        self.row_start = self.out.len();
+        self.rows.start_row(&mut self.out, first);
+        self.values_start = self.out.len();
+    }
+
+    fn write_value(&mut self, column: usize, position: usize, value: &[u8]) {
+        self.rows.write_value(&mut self.out, column, position, value);
+    }
+
+    fn end_row(&mut self, written: usize) {
+        self.rows.end_row(&mut self.out, self.values_start, written);
+    }
+
+    fn discard_row(&mut self) {
+        self.out.truncate(self.row_start);
+    }
+}
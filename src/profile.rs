@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hasher};
+
+use crate::columnar::{ColumnType, StagedRow};
+use crate::expr::parse_number;
+use crate::processor::{ProcessStats, RowBuffer};
+use crate::writer::{OutputFormat, RowWriter};
+
+/// Default number of most frequent values reported per column
+pub const DEFAULT_TOP_K: usize = 5;
+
+/// HyperLogLog registers are indexed by this many bits of the value hash,
+/// for a standard error of about 0.8%
+const HLL_BITS: u32 = 14;
+/// Smallest number of distinct values the frequency summary of a column
+/// counts exactly before it is pruned
+const MIN_SUMMARY: usize = 64 * 1024;
+
+/// Result of `CsvProcessor::profile`.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    /// Statistics of each output column
+    pub columns: Vec<ColumnStats>,
+    pub stats: ProcessStats,
+}
+
+/// Statistics of one output column, over its non-empty values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnStats {
+    pub name: String,
+    pub non_empty: u64,
+    /// Number of distinct values. Exact while the column has fewer distinct
+    /// values than its frequency summary holds, and a HyperLogLog estimate
+    /// beyond that.
+    pub distinct: u64,
+    /// Shortest and longest value in characters (0 if all are empty)
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Narrowest type that fits every value, or `None` if all are empty
+    pub inferred_type: Option<ColumnType>,
+    /// Set when the inferred type is numeric
+    pub numeric: Option<NumericStats>,
+    /// Most frequent values, most frequent first, ties in value order
+    pub top_values: Vec<(String, u64)>,
+    /// Whether `top_values` are exact. Columns with more distinct values
+    /// than the frequency summary holds only report values frequent
+    /// enough to survive pruning, with lower bounds of their counts.
+    pub top_values_exact: bool,
+}
+
+/// Summary of a numeric column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NumericStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Sample standard deviation
+    pub stddev: f64,
+}
+
+/// Running count, range, mean and sum of squared deviations (Welford).
+#[derive(Clone, Copy)]
+struct Moments {
+    count: u64,
+    min: f64,
+    max: f64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Default for Moments {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+}
+
+impl Moments {
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Combines the moments of two disjoint samples (Chan et al.).
+    fn merge(&mut self, other: &Moments) {
+        if other.count == 0 {
+            return;
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.m2 += other.m2 + delta * delta * self.count as f64 * other.count as f64 / count as f64;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count = count;
+    }
+
+    fn stats(&self) -> Option<NumericStats> {
+        (self.count > 0).then(|| NumericStats {
+            min: self.min,
+            max: self.max,
+            mean: self.mean,
+            stddev: match self.count {
+                1 => 0.0,
+                n => (self.m2 / (n - 1) as f64).sqrt(),
+            },
+        })
+    }
+}
+
+/// Partial statistics of one column, mergeable with those of other chunks.
+struct ColumnProfile {
+    non_empty: u64,
+    min_length: usize,
+    max_length: usize,
+    ty: Option<ColumnType>,
+    numbers: Moments,
+    /// HyperLogLog registers, allocated with the first value
+    registers: Vec<u8>,
+    /// Misra-Gries frequency summary. Until it is first pruned it holds
+    /// every distinct value with its exact count, so the result does not
+    /// depend on how the rows were split into chunks.
+    counts: HashMap<Vec<u8>, u64>,
+    pruned: bool,
+}
+
+impl ColumnProfile {
+    fn new() -> Self {
+        Self {
+            non_empty: 0,
+            min_length: usize::MAX,
+            max_length: 0,
+            ty: None,
+            numbers: Moments::default(),
+            registers: Vec::new(),
+            counts: HashMap::new(),
+            pruned: false,
+        }
+    }
+
+    fn add(&mut self, value: &[u8]) {
+        self.non_empty += 1;
+        // Count characters by skipping UTF-8 continuation bytes
+        let length = value.iter().filter(|&&b| (b as i8) >= -0x40).count();
+        self.min_length = self.min_length.min(length);
+        self.max_length = self.max_length.max(length);
+
+        // Numbers only matter while the column can still be numeric
+        self.ty = Some(ColumnType::widen(self.ty, value));
+        if matches!(self.ty, Some(ColumnType::Int64 | ColumnType::Float64)) {
+            if let Some(number) = parse_number(value) {
+                self.numbers.add(number);
+            }
+        }
+
+        if self.registers.is_empty() {
+            self.registers = vec![0; 1 << HLL_BITS];
+        }
+        // DefaultHasher::new() has fixed keys, so every chunk hashes alike
+        let mut hasher = DefaultHasher::new();
+        hasher.write(value);
+        let hash = hasher.finish();
+        let register = (hash >> (64 - HLL_BITS)) as usize;
+        let rank = ((hash << HLL_BITS) | (1 << (HLL_BITS - 1))).leading_zeros() as u8 + 1;
+        self.registers[register] = self.registers[register].max(rank);
+
+        match self.counts.get_mut(value) {
+            Some(count) => *count += 1,
+            None => {
+                self.counts.insert(value.to_vec(), 1);
+            }
+        }
+    }
+
+    fn merge(&mut self, other: ColumnProfile) {
+        self.non_empty += other.non_empty;
+        self.min_length = self.min_length.min(other.min_length);
+        self.max_length = self.max_length.max(other.max_length);
+        self.ty = ColumnType::join(self.ty, other.ty);
+        self.numbers.merge(&other.numbers);
+
+        if self.registers.is_empty() {
+            self.registers = other.registers;
+        } else {
+            for (register, rank) in self.registers.iter_mut().zip(other.registers) {
+                *register = (*register).max(rank);
+            }
+        }
+
+        self.pruned |= other.pruned;
+        for (value, count) in other.counts {
+            *self.counts.entry(value).or_insert(0) += count;
+        }
+    }
+
+    /// Shrinks the frequency summary to at most `capacity` values by
+    /// subtracting the count of the next most frequent value from all.
+    /// Any value more frequent than `1/capacity` of the rows survives.
+    fn prune(&mut self, capacity: usize) {
+        if self.counts.len() <= capacity {
+            return;
+        }
+        let mut counts: Vec<u64> = self.counts.values().copied().collect();
+        let (_, &mut cut, _) = counts.select_nth_unstable_by(capacity, |a, b| b.cmp(a));
+        self.counts.retain(|_, count| {
+            *count -= cut.min(*count);
+            *count > 0
+        });
+        self.pruned = true;
+    }
+
+    /// HyperLogLog estimate, with linear counting for small cardinalities.
+    fn estimate_distinct(&self) -> u64 {
+        if !self.pruned {
+            return self.counts.len() as u64;
+        }
+        let m = self.registers.len() as f64;
+        let sum: f64 = self.registers.iter().map(|&rank| (-(rank as f64)).exp2()).sum();
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        let estimate = match raw <= 2.5 * m && zeros > 0 {
+            true => m * (m / zeros as f64).ln(),
+            false => raw,
+        };
+        (estimate.round() as u64).min(self.non_empty)
+    }
+
+    fn finish(self, name: String, top_k: usize) -> ColumnStats {
+        let distinct = self.estimate_distinct();
+        let mut top: Vec<_> = self.counts.into_iter().collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(top_k);
+        ColumnStats {
+            name,
+            non_empty: self.non_empty,
+            distinct,
+            min_length: if self.non_empty == 0 { 0 } else { self.min_length },
+            max_length: self.max_length,
+            inferred_type: self.ty,
+            numeric: match self.ty {
+                Some(ColumnType::Int64 | ColumnType::Float64) => self.numbers.stats(),
+                _ => None,
+            },
+            top_values: top
+                .into_iter()
+                .map(|(value, count)| (String::from_utf8_lossy(&value).into_owned(), count))
+                .collect(),
+            top_values_exact: !self.pruned,
+        }
+    }
+}
+
+/// Collects column statistics from the kept rows of a chunk. Workers
+/// profile their chunks separately and the partial profiles are merged in
+/// the writer stage, where the frequency summaries are pruned.
+pub struct Profiler {
+    columns: Vec<ColumnProfile>,
+    /// Values kept in each frequency summary
+    capacity: usize,
+    row: StagedRow,
+}
+
+impl Profiler {
+    pub fn new(columns: usize, top_k: usize) -> Self {
+        Self {
+            columns: (0..columns).map(|_| ColumnProfile::new()).collect(),
+            capacity: (top_k * 16).max(MIN_SUMMARY),
+            row: StagedRow::default(),
+        }
+    }
+
+    /// Adds the partial profile of another chunk.
+    pub fn merge(&mut self, other: Profiler) {
+        for (column, other) in self.columns.iter_mut().zip(other.columns) {
+            column.merge(other);
+            column.prune(self.capacity);
+        }
+    }
+
+    pub fn finish(self, names: &[Vec<u8>], top_k: usize) -> Vec<ColumnStats> {
+        self.columns
+            .into_iter()
+            .zip(names)
+            .map(|(column, name)| column.finish(String::from_utf8_lossy(name).into_owned(), top_k))
+            .collect()
+    }
+}
+
+impl RowBuffer for Profiler {
+    fn start_row(&mut self, _first: bool) {
+        self.row.clear();
+    }
+
+    fn write_value(&mut self, column: usize, _position: usize, value: &[u8]) {
+        self.row.push(column, value);
+    }
+
+    fn end_row(&mut self, _written: usize) {
+        for (column, value) in self.row.iter() {
+            if !value.is_empty() {
+                self.columns[column].add(value);
+            }
+        }
+    }
+
+    fn discard_row(&mut self) {}
+}
+
+/// Serializes the statistics as one row per column, with a value and a
+/// count column for each of the `top_k` most frequent values.
+pub fn report(columns: &[ColumnStats], format: OutputFormat, top_k: usize) -> Vec<u8> {
+    const NAMES: [&str; 11] = [
+        "column",
+        "type",
+        "non_empty",
+        "distinct",
+        "min_length",
+        "max_length",
+        "min",
+        "max",
+        "mean",
+        "stddev",
+        "top_exact",
+    ];
+    let mut names: Vec<Vec<u8>> = NAMES.iter().map(|name| name.as_bytes().to_vec()).collect();
+    for rank in 1..=top_k {
+        names.push(format!("top_{}", rank).into_bytes());
+        names.push(format!("top_{}_count", rank).into_bytes());
+    }
+    let rows = RowWriter::new(format, names, true);
+    let mut out = rows.begin();
+    for (i, column) in columns.iter().enumerate() {
+        let numeric = |f: fn(&NumericStats) -> f64| column.numeric.as_ref().map(|n| f(n).to_string());
+        let mut values = vec![
+            column.name.clone(),
+            column.inferred_type.map(|ty| ty.name().to_string()).unwrap_or_default(),
+            column.non_empty.to_string(),
+            column.distinct.to_string(),
+            column.min_length.to_string(),
+            column.max_length.to_string(),
+            numeric(|n| n.min).unwrap_or_default(),
+            numeric(|n| n.max).unwrap_or_default(),
+            numeric(|n| n.mean).unwrap_or_default(),
+            numeric(|n| n.stddev).unwrap_or_default(),
+            column.top_values_exact.to_string(),
+        ];
+        for rank in 0..top_k {
+            match column.top_values.get(rank) {
+                Some((value, count)) => values.extend([value.clone(), count.to_string()]),
+                None => values.extend([String::new(), String::new()]),
+            }
+        }
+
+        rows.start_row(&mut out, i == 0);
+        let row_start = out.len();
+        for (position, value) in values.iter().enumerate() {
+            rows.write_value(&mut out, position, position, value.as_bytes());
+        }
+        rows.end_row(&mut out, row_start, values.len());
+    }
+    out.extend_from_slice(rows.end());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Profiles `chunks` of rows separately and merges them like the writer
+    /// stage does.
+    fn profile(chunks: &[Vec<Vec<String>>], columns: usize, top_k: usize) -> Vec<ColumnStats> {
+        let mut total = Profiler::new(columns, top_k);
+        for rows in chunks {
+            let mut profiler = Profiler::new(columns, top_k);
+            for row in rows {
+                profiler.start_row(false);
+                for (column, value) in row.iter().enumerate() {
+                    profiler.write_value(column, column, value.as_bytes());
+                }
+                profiler.end_row(row.len());
+            }
+            total.merge(profiler);
+        }
+        let names: Vec<Vec<u8>> = (0..columns).map(|i| format!("c{}", i).into_bytes()).collect();
+        total.finish(&names, top_k)
+    }
+
+    fn rows(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter().map(|row| row.iter().map(|value| value.to_string()).collect()).collect()
+    }
+
+    #[test]
+    fn profiles_columns() {
+        let chunk = rows(&[
+            &["1", "béta", "true", ""],
+            &["4", "a", "false", ""],
+            &["", "a", "TRUE", ""],
+            &["7", "ccc", "true", ""],
+            &["4", "béta", "x", ""],
+        ]);
+        let columns = profile(&[chunk], 4, 2);
+
+        let ints = &columns[0];
+        assert_eq!((ints.non_empty, ints.distinct, ints.min_length, ints.max_length), (4, 3, 1, 1));
+        assert_eq!(ints.inferred_type, Some(ColumnType::Int64));
+        let numeric = ints.numeric.unwrap();
+        assert_eq!((numeric.min, numeric.max, numeric.mean), (1.0, 7.0, 4.0));
+        assert!((numeric.stddev - 6f64.sqrt()).abs() < 1e-12);
+        assert_eq!(ints.top_values, [("4".to_string(), 2), ("1".to_string(), 1)]);
+        assert!(ints.top_values_exact);
+
+        // Lengths count characters, and ties are in value order
+        let text = &columns[1];
+        assert_eq!((text.min_length, text.max_length), (1, 4));
+        assert_eq!((text.inferred_type, text.numeric), (Some(ColumnType::String), None));
+        assert_eq!(text.top_values, [("a".to_string(), 2), ("béta".to_string(), 2)]);
+
+        assert_eq!(columns[2].inferred_type, Some(ColumnType::String));
+        let empty = &columns[3];
+        assert_eq!((empty.non_empty, empty.distinct, empty.min_length, empty.inferred_type), (0, 0, 0, None));
+        assert!(empty.top_values.is_empty());
+    }
+
+    #[test]
+    fn merges_chunks_like_one() {
+        let all: Vec<Vec<String>> = (0..500).map(|i| vec![(i % 37).to_string(), format!("{}.5", i % 11)]).collect();
+        let expected = profile(std::slice::from_ref(&all), 2, 3);
+        for size in [1, 7, 100] {
+            let chunks: Vec<_> = all.chunks(size).map(<[_]>::to_vec).collect();
+            let columns = profile(&chunks, 2, 3);
+            for (column, expected) in columns.iter().zip(&expected) {
+                let numeric = (column.numeric.unwrap(), expected.numeric.unwrap());
+                assert!((numeric.0.mean - numeric.1.mean).abs() < 1e-9);
+                assert!((numeric.0.stddev - numeric.1.stddev).abs() < 1e-9);
+                assert_eq!(column.top_values, expected.top_values, "chunks of {}", size);
+                assert_eq!(column.distinct, expected.distinct);
+            }
+        }
+        assert_eq!(expected[0].distinct, 37);
+        assert_eq!(expected[1].inferred_type, Some(ColumnType::Float64));
+    }
+
+    #[test]
+    fn estimates_high_cardinality_columns() {
+        // Distinct values well past the summary, and one frequent value
+        let distinct = 3 * MIN_SUMMARY;
+        let all: Vec<Vec<String>> = (0..distinct)
+            .map(|i| match i % 4 {
+                0 => vec!["hot".to_string()],
+                _ => vec![format!("v{}", i)],
+            })
+            .collect();
+        let chunks: Vec<_> = all.chunks(4096).map(<[_]>::to_vec).collect();
+        let column = &profile(&chunks, 1, 1)[0];
+
+        assert!(!column.top_values_exact);
+        let expected = (distinct - distinct / 4 + 1) as f64;
+        let error = (column.distinct as f64 - expected).abs() / expected;
+        assert!(error < 0.03, "estimated {} distinct for {}", column.distinct, expected);
+        let (value, count) = &column.top_values[0];
+        assert_eq!(value, "hot");
+        assert!(*count <= (distinct / 4) as u64 && *count > (distinct / 8) as u64, "{}", count);
+    }
+
+    #[test]
+    fn reports_one_row_per_column() {
+        let columns = profile(&[rows(&[&["1", "x"], &["2", "x"]])], 2, 1);
+        let report = String::from_utf8(report(&columns, OutputFormat::default(), 1)).unwrap();
+        assert_eq!(
+            report,
+            "column,type,non_empty,distinct,min_length,max_length,min,max,mean,stddev,top_exact,top_1,top_1_count\n\
+             c0,int64,2,2,1,1,1,2,1.5,0.7071067811865476,true,1,1\n\
+             c1,string,2,1,1,1,,,,,true,x,2\n"
+        );
+    }
+}
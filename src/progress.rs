@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+/// Live counters of a running `CsvProcessor`, updated as each chunk
+/// completes. Pass one to `CsvProcessorBuilder::progress` and poll it from
+/// another thread.
+#[derive(Debug, Default)]
+pub struct Progress {
+    bytes_read: AtomicU64,
+    total_bytes: OnceLock<u64>,
+    rows_read: AtomicUsize,
+    rows_written: AtomicUsize,
+    rows_rejected: AtomicUsize,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Input bytes consumed so far, after decompression.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Size of the input, if it is an uncompressed regular file whose bytes
+    /// are counted as they are processed.
+    pub fn total_bytes(&self) -> Option<u64> {
+        self.total_bytes.get().copied()
+    }
+
+    pub fn rows_read(&self) -> usize {
+        self.rows_read.load(Ordering::Relaxed)
+    }
+
+    pub fn rows_written(&self) -> usize {
+        self.rows_written.load(Ordering::Relaxed)
+    }
+
+    pub fn rows_rejected(&self) -> usize {
+        self.rows_rejected.load(Ordering::Relaxed)
+    }
+
+    /// Share of the input processed, from 0 to 1, if its size is known.
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total_bytes()? {
+            0 => Some(1.0),
+            total => Some((self.bytes_read() as f64 / total as f64).min(1.0)),
+        }
+    }
+
+    pub(crate) fn start(&self, total_bytes: Option<u64>, header_bytes: u64) {
+        if let Some(total) = total_bytes {
+            let _ = self.total_bytes.set(total);
+        }
+        self.bytes_read.fetch_add(header_bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add(&self, bytes: u64, read: usize, written: usize, rejected: usize) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+        self.rows_read.fetch_add(read, Ordering::Relaxed);
+        self.rows_written.fetch_add(written, Ordering::Relaxed);
+        self.rows_rejected.fetch_add(rejected, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,308 @@
+use memmap2::Mmap;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::path::Path;
+
+use crate::error::{Error, ParseError};
+use crate::header::Header;
+use crate::input::Input;
+use crate::tokenizer::{self, count_lines, Tokenizer};
+
+/// Default size of the chunks `par_records` splits the file into
+const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// One record, with its fields borrowed from the memory-mapped file. Only
+/// quoted fields containing escaped quotes are copied.
+#[derive(Clone, Debug)]
+pub struct Record<'a> {
+    raw: &'a [u8],
+    fields: Vec<Cow<'a, [u8]>>,
+}
+
+impl<'a> Record<'a> {
+    /// The field at 0-based `index`, borrowed from the file so it outlives
+    /// the record. Only a quoted field with escaped quotes is an owned copy.
+    pub fn get(&self, index: usize) -> Option<Cow<'a, [u8]>> {
+        self.fields.get(index).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The fields in order, borrowed from the file like `get`.
+    pub fn iter(&self) -> impl Iterator<Item = Cow<'a, [u8]>> + '_ {
+        self.fields.iter().cloned()
+    }
+
+    /// The record as it appears in the file, without its line terminator.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.raw
+    }
+}
+
+/// Reads the records of a memory-mapped CSV file, sequentially or in
+/// parallel, without copying them.
+///
+/// ```no_run
+/// use pulsecsv::{Error, RecordReader};
+/// use rayon::prelude::*;
+///
+/// let reader = RecordReader::builder().delimiter(b',').open("users.csv")?;
+/// let email = reader.header().and_then(|h| h.index_of("email")).unwrap_or(1);
+/// let gmail = reader
+///     .par_records()
+///     .map(|mut records| {
+///         let mut count = 0;
+///         while let Some(record) = records.next_record() {
+///             count += record?.get(email).is_some_and(|e| e.ends_with(b"@gmail.com")) as usize;
+///         }
+///         Ok(count)
+///     })
+///     .sum::<Result<usize, Error>>()?;
+/// # Ok::<(), pulsecsv::Error>(())
+/// ```
+pub struct RecordReader {
+    mmap: Mmap,
+    tokenizer: Tokenizer,
+    header: Option<Header>,
+    data_start: usize,
+    chunk_size: usize,
+}
+
+/// Builder for a `RecordReader`.
+pub struct RecordReaderBuilder {
+    delimiter: u8,
+    quote: Option<u8>,
+    escape: Option<u8>,
+    has_header: bool,
+    chunk_size: usize,
+}
+
+impl Default for RecordReaderBuilder {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: Some(b'"'),
+            escape: None,
+            has_header: true,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+impl RecordReaderBuilder {
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets the quote character, or disables quote handling with `None`.
+    pub fn quote(mut self, quote: Option<u8>) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    pub fn escape(mut self, escape: Option<u8>) -> Self {
+        self.escape = escape;
+        self
+    }
+
+    /// Sets whether the first record is a header row rather than data.
+    pub fn has_header(mut self, has_header: bool) -> Self {
+        self.has_header = has_header;
+        self
+    }
+
+    /// Sets the size of the work units of `par_records` in bytes.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Memory-maps the file at `path`, which must be an uncompressed
+    /// regular file.
+    pub fn open(self, path: impl AsRef<Path>) -> Result<RecordReader, Error> {
+        let tokenizer = Tokenizer::new(self.delimiter, self.quote, self.escape);
+        match Input::open(path.as_ref(), tokenizer, self.has_header, self.chunk_size)? {
+            Input::Mapped {
+                mmap,
+                header,
+                data_start,
+            } => Ok(RecordReader {
+                mmap,
+                tokenizer,
+                header,
+                data_start,
+                chunk_size: self.chunk_size,
+            }),
+            Input::Stream { .. } | Input::Columnar { .. } => Err(Error::Config(format!(
+                "{} is not an uncompressed regular CSV file and cannot be memory-mapped",
+                path.as_ref().display()
+            ))),
+        }
+    }
+}
+
+impl RecordReader {
+    pub fn builder() -> RecordReaderBuilder {
+        RecordReaderBuilder::default()
+    }
+
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.mmap[self.data_start..]
+    }
+
+    /// Reads the records in file order. Empty lines are skipped.
+    pub fn records(&self) -> Records<'_> {
+        self.reader(self.data())
+    }
+
+    /// Reads the records on the rayon pool, one `Records` for each of the
+    /// record-aligned chunks the file is split into like the processing
+    /// engine does, so quoted fields spanning lines stay intact. The
+    /// chunks come in file order.
+    pub fn par_records(&self) -> impl IndexedParallelIterator<Item = Records<'_>> {
+        let chunks: Vec<_> = self.tokenizer.chunks(self.data(), self.chunk_size).collect();
+        chunks.into_par_iter().map(move |chunk| self.reader(chunk))
+    }
+
+    fn reader<'a>(&'a self, data: &'a [u8]) -> Records<'a> {
+        Records {
+            tokenizer: &self.tokenizer,
+            file: &self.mmap,
+            inner: self.tokenizer.records(data),
+            record: Record {
+                raw: &[],
+                fields: Vec::new(),
+            },
+            lines: None,
+        }
+    }
+}
+
+/// Reads records one at a time into the same `Record`, so its field list
+/// is allocated once rather than for every record. Call `next_record` in a
+/// `while let` loop; the record it returns is borrowed until the next call.
+pub struct Records<'a> {
+    tokenizer: &'a Tokenizer,
+    /// The whole file, which error positions are relative to
+    file: &'a [u8],
+    inner: tokenizer::Records<'a, 'a>,
+    record: Record<'a>,
+    /// Position in the file up to which lines have been counted, and the
+    /// count; lines are only counted once a record is malformed
+    lines: Option<(usize, u64)>,
+}
+
+impl<'a> Records<'a> {
+    /// Parses the next record, or returns the position and reason it is
+    /// malformed. Reading can go on past a malformed record.
+    pub fn next_record(&mut self) -> Option<Result<&Record<'a>, Error>> {
+        let raw = self.inner.find(|record| !record.is_empty())?;
+        self.record.raw = raw;
+        self.record.fields.clear();
+        match self.tokenizer.split_fields(raw, &mut self.record.fields) {
+            None => Some(Ok(&self.record)),
+            Some((column, kind)) => {
+                // Records are subslices of the file
+                let offset = raw.as_ptr() as usize - self.file.as_ptr() as usize;
+                let (counted, lines) = self.lines.get_or_insert((0, 0));
+                *lines += count_lines(&self.file[*counted..offset]);
+                *counted = offset;
+                Some(Err(Error::Parse(ParseError {
+                    line: *lines + 1,
+                    byte_offset: offset as u64,
+                    column: Some(column),
+                    kind,
+                })))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const CSV: &str = "id,name\n1,plain\n\n2,\"two\nlines\"\n3,\"say \"\"hi\"\"\"\n4,\"bad\"x\n5,last\n";
+
+    /// Writes `contents` to a file of its own and opens it.
+    fn open(name: &str, contents: &str, chunk_size: usize) -> (RecordReader, PathBuf) {
+        let path = std::env::temp_dir().join(format!("pulsecsv-records-{}-{}.csv", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        let reader = RecordReader::builder().chunk_size(chunk_size).open(&path).unwrap();
+        (reader, path)
+    }
+
+    /// The ids of the records, or the line and offset of the malformed ones.
+    fn read(mut records: Records<'_>) -> Vec<String> {
+        let mut out = Vec::new();
+        while let Some(record) = records.next_record() {
+            out.push(match record {
+                Ok(record) => String::from_utf8_lossy(&record.get(0).unwrap()).into_owned(),
+                Err(Error::Parse(e)) => format!("line {} byte {} column {:?}", e.line, e.byte_offset, e.column),
+                Err(e) => panic!("{}", e),
+            });
+        }
+        out
+    }
+
+    #[test]
+    fn reads_records_in_order() {
+        let (reader, path) = open("order", CSV, 1024);
+        assert_eq!(reader.header().unwrap().index_of("name"), Some(1));
+        assert_eq!(read(reader.records()), ["1", "2", "3", "line 7 byte 46 column Some(1)", "5"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_the_same_records_in_parallel() {
+        let (reader, path) = open("parallel", CSV, 8);
+        assert!(reader.par_records().len() > 1);
+        let records: Vec<Vec<String>> = reader.par_records().map(read).collect();
+        assert_eq!(records.concat(), read(reader.records()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fields_outlive_the_record() {
+        let (reader, path) = open("fields", CSV, 1024);
+        let mut records = reader.records();
+        let mut names = Vec::new();
+        while let Some(record) = records.next_record() {
+            if let Ok(record) = record {
+                assert_eq!(record.iter().count(), record.len());
+                names.push(record.get(1).unwrap());
+            }
+        }
+        assert_eq!(names, [&b"plain"[..], b"two\nlines", b"say \"hi\"", b"last"]);
+        // Only the field with escaped quotes is copied
+        let copied: Vec<_> = names.iter().map(|name| matches!(name, Cow::Owned(_))).collect();
+        assert_eq!(copied, [false, false, true, false]);
+
+        let mut records = reader.records();
+        let record = records.next_record().unwrap().unwrap();
+        assert_eq!(record.as_bytes(), b"1,plain");
+        assert_eq!(record.get(2), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_compressed_files() {
+        let path = std::env::temp_dir().join(format!("pulsecsv-records-{}.csv.gz", std::process::id()));
+        std::fs::write(&path, crate::compression::Compression::Gzip.compress(CSV.as_bytes().to_vec(), None).unwrap())
+            .unwrap();
+        assert!(matches!(RecordReader::builder().open(&path), Err(Error::Config(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt;
+use std::io::{BufWriter, Write};
+
+use crate::error::{Error, OnError, ParseError, ParseErrorKind};
+use crate::output::Sink;
+use crate::tokenizer::count_lines;
+use crate::writer::CsvFormat;
+
+/// Outcome of one work unit, resolved to absolute positions by `Rejects` in
+/// the writer stage, where the units arrive in input order.
+#[derive(Debug, Default)]
+pub struct ChunkReport {
+    pub read: usize,
+    pub kept: usize,
+    pub rejected: usize,
+    /// Rows dropped by each filter, indexed like `RowFilter::rejecting_filter`
+    pub filtered: Vec<usize>,
+    /// Rows dropped for empty values
+    pub dropped_empty: usize,
+    /// Blank lines, which are not rows
+    pub blank: usize,
+    /// Bytes in the chunk
+    pub len: u64,
+    /// Line feeds in the chunk, counted only when errors are reported
+    pub lines: u64,
+    /// Malformed rows, collected only when errors are reported, and other
+    /// dropped rows, collected only for the rejects file
+    pub rejects: Vec<Reject>,
+    /// Position in the chunk up to which `lines` has been counted
+    counted: usize,
+}
+
+/// A malformed or dropped row, positioned relative to the start of its
+/// chunk.
+#[derive(Debug)]
+pub struct Reject {
+    line: u64,
+    offset: u64,
+    column: Option<usize>,
+    reason: Reason,
+    record: Vec<u8>,
+}
+
+/// Why a row was left out of the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    Malformed(ParseErrorKind),
+    /// An output value was empty under `EmptyPolicy::DropRow`, or every
+    /// value was under `EmptyPolicy::Skip`
+    Empty,
+    /// The line was blank
+    Blank,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Malformed(kind) => write!(f, "{}", kind),
+            Reason::Empty => write!(f, "dropped for an empty value"),
+            Reason::Blank => write!(f, "blank line"),
+        }
+    }
+}
+
+impl ChunkReport {
+    /// Counts a row dropped by filter `index`.
+    pub fn filter(&mut self, index: usize) {
+        self.filter_rows(index, 1);
+    }
+
+    /// Counts `rows` rows dropped by filter `index`.
+    pub fn filter_rows(&mut self, index: usize, rows: usize) {
+        if self.filtered.len() <= index {
+            self.filtered.resize(index + 1, 0);
+        }
+        self.filtered[index] += rows;
+    }
+
+    /// Records a malformed `record` starting at `offset` in a text chunk.
+    pub fn reject_text(
+        &mut self,
+        chunk: &[u8],
+        offset: usize,
+        record: &[u8],
+        column: Option<usize>,
+        kind: ParseErrorKind,
+        detailed: bool,
+    ) {
+        self.rejected += 1;
+        if detailed {
+            self.push_text(chunk, offset, record, column, Reason::Malformed(kind));
+        }
+    }
+
+    /// Records a `record` of a text chunk dropped for `reason` other than
+    /// being malformed; `audited` when a rejects file is written. The
+    /// caller counts it.
+    pub fn drop_text(&mut self, chunk: &[u8], offset: usize, record: &[u8], reason: Reason, audited: bool) {
+        if audited {
+            self.push_text(chunk, offset, record, None, reason);
+        }
+    }
+
+    fn push_text(&mut self, chunk: &[u8], offset: usize, record: &[u8], column: Option<usize>, reason: Reason) {
+        // Lines are counted incrementally, so each byte is scanned once
+        self.lines += count_lines(&chunk[self.counted..offset]);
+        self.counted = offset;
+        self.rejects.push(Reject {
+            line: self.lines,
+            offset: offset as u64,
+            column,
+            reason,
+            record: record.to_vec(),
+        });
+    }
+
+    /// Records a malformed row of a record batch; rows stand in for lines.
+    pub fn reject_row(
+        &mut self,
+        row: usize,
+        fields: &[impl AsRef<[u8]>],
+        column: Option<usize>,
+        kind: ParseErrorKind,
+        detailed: bool,
+    ) {
+        self.rejected += 1;
+        if detailed {
+            self.push_row(row, fields, column, Reason::Malformed(kind));
+        }
+    }
+
+    /// Records a row of a record batch dropped for `reason` other than
+    /// being malformed; `audited` when a rejects file is written. The
+    /// caller counts it.
+    pub fn drop_row(&mut self, row: usize, fields: &[impl AsRef<[u8]>], reason: Reason, audited: bool) {
+        if audited {
+            self.push_row(row, fields, None, reason);
+        }
+    }
+
+    fn push_row(&mut self, row: usize, fields: &[impl AsRef<[u8]>], column: Option<usize>, reason: Reason) {
+        let csv = CsvFormat::default();
+        let mut record = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            csv.write_value(&mut record, i, field.as_ref());
+        }
+        self.rejects.push(Reject {
+            line: row as u64,
+            offset: 0,
+            column,
+            reason,
+            record,
+        });
+    }
+
+    /// Completes the report of a text chunk.
+    pub fn finish_text(&mut self, chunk: &[u8], detailed: bool) {
+        self.len = chunk.len() as u64;
+        if detailed {
+            self.lines += count_lines(&chunk[self.counted..]);
+            self.counted = chunk.len();
+        }
+    }
+}
+
+/// Applies the `--on-error` policy to malformed rows and writes them, and
+/// the rows dropped for empty values and blank lines, to the rejects file,
+/// as CSV with the position and reason of each row.
+pub struct Rejects {
+    policy: OnError,
+    writer: Option<BufWriter<Box<dyn Write + Send>>>,
+    /// Byte offset and line count at the start of the next chunk
+    position: (u64, u64),
+    count: usize,
+}
+
+impl Rejects {
+    pub fn new(policy: OnError, sink: Option<Sink>, position: (u64, u64)) -> Result<Self, Error> {
+        let writer = match sink {
+            Some(sink) => {
+                let mut writer = BufWriter::new(sink.open()?);
+                writer.write_all(b"line,byte_offset,column,reason,record\n")?;
+                Some(writer)
+            }
+            None => None,
+        };
+        Ok(Self {
+            policy,
+            writer,
+            position,
+            count: 0,
+        })
+    }
+
+    /// Whether rejected rows need their positions and contents collected.
+    pub fn detailed(&self) -> bool {
+        self.policy != OnError::Skip || self.writer.is_some()
+    }
+
+    /// Whether rows dropped for other reasons need them collected too.
+    pub fn audited(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Handles the malformed rows of the next chunk in input order.
+    pub fn handle(&mut self, report: &ChunkReport) -> Result<(), Error> {
+        let mut position = self.position;
+        self.handle_input(report, &mut position, None)?;
+        self.position = position;
+        Ok(())
+    }
+
+    /// Handles the malformed rows of a chunk of another input, such as the
+    /// right side of a join, which starts at `position` and is named by
+    /// `input` in the reasons.
+    pub fn handle_input(
+        &mut self,
+        report: &ChunkReport,
+        position: &mut (u64, u64),
+        input: Option<&str>,
+    ) -> Result<(), Error> {
+        self.count += report.rejected;
+        for reject in &report.rejects {
+            let line = position.1 + reject.line + 1;
+            let byte_offset = position.0 + reject.offset;
+            let reason = match input {
+                Some(input) => format!("{}: {}", input, reject.reason),
+                None => reject.reason.to_string(),
+            };
+
+            if let Some(writer) = &mut self.writer {
+                let csv = CsvFormat::default();
+                let mut out = Vec::new();
+                csv.write_value(&mut out, 0, line.to_string().as_bytes());
+                csv.write_value(&mut out, 1, byte_offset.to_string().as_bytes());
+                csv.write_value(&mut out, 2, reject.column.map(|c| c.to_string()).unwrap_or_default().as_bytes());
+                csv.write_value(&mut out, 3, reason.as_bytes());
+                csv.write_value(&mut out, 4, &reject.record);
+                csv.end_record(&mut out);
+                writer.write_all(&out)?;
+            }
+
+            // Only malformed rows are errors
+            let Reason::Malformed(kind) = reject.reason else {
+                continue;
+            };
+            let error = ParseError {
+                line,
+                byte_offset,
+                column: reject.column,
+                kind,
+            };
+            match self.policy {
+                OnError::Skip => {}
+                OnError::Log => match input {
+                    Some(input) => eprintln!("\rSkipped malformed row of the {} at {}", input, error),
+                    None => eprintln!("\rSkipped malformed row at {}", error),
+                },
+                OnError::Fail => {
+                    self.finish()?;
+                    return Err(Error::Parse(error));
+                }
+            }
+        }
+
+        position.0 += report.len;
+        position.1 += report.lines;
+        Ok(())
+    }
+
+    /// Flushes the rejects file, returning the number of rejected rows.
+    pub fn finish(&mut self) -> Result<usize, Error> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        Ok(self.count)
+    }
+}
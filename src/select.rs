@@ -0,0 +1,185 @@
+use regex::bytes::Regex;
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::header::Header;
+
+/// The output columns: selected input fields followed by the capture groups
+/// of any `--extract` patterns.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub fields: Vec<usize>,
+    pub extracts: Vec<Extract>,
+    pub empty: EmptyPolicy,
+    pub short_rows: ShortRows,
+}
+
+/// What to do with empty output values.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum EmptyPolicy {
+    /// Write the empty value, keeping every column in place
+    #[default]
+    Keep,
+    /// Drop the whole row
+    DropRow,
+    /// Write this value instead
+    Fill(Vec<u8>),
+    /// Omit the value and its delimiter (compact output, columns shift)
+    Skip,
+}
+
+impl FromStr for EmptyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(EmptyPolicy::Keep),
+            "drop-row" => Ok(EmptyPolicy::DropRow),
+            "skip" => Ok(EmptyPolicy::Skip),
+            _ => match s.strip_prefix("fill=") {
+                Some(value) => Ok(EmptyPolicy::Fill(value.as_bytes().to_vec())),
+                None => Err(format!(
+                    "unknown empty policy '{}' (expected keep, drop-row, fill=VALUE or skip)",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// What to do with rows that have fewer fields than the selection needs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShortRows {
+    /// Drop the row
+    #[default]
+    Drop,
+    /// Treat the missing fields as empty values
+    Pad,
+}
+
+impl FromStr for ShortRows {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(ShortRows::Drop),
+            "pad" => Ok(ShortRows::Pad),
+            _ => Err(format!("unknown short row policy '{}' (expected drop or pad)", s)),
+        }
+    }
+}
+
+/// Regex capture-group extraction from one input column. Each capture group
+/// becomes an output column; a pattern without groups yields the whole match.
+#[derive(Debug)]
+pub struct Extract {
+    column: usize,
+    regex: Regex,
+}
+
+impl Extract {
+    pub fn new(column: usize, pattern: &str) -> Result<Self, Error> {
+        Ok(Self {
+            column,
+            regex: Regex::new(pattern)?,
+        })
+    }
+
+    fn groups(&self) -> std::ops::RangeInclusive<usize> {
+        match self.regex.captures_len() {
+            1 => 0..=0,
+            n => 1..=n - 1,
+        }
+    }
+
+    /// Names of the output columns, taken from named groups or derived from
+    /// the source column name and group number.
+    fn names(&self, header: Option<&Header>) -> Vec<Vec<u8>> {
+        let source = column_name(header, self.column);
+        let names: Vec<_> = self.regex.capture_names().collect();
+        self.groups()
+            .map(|group| match names[group] {
+                Some(name) => name.as_bytes().to_vec(),
+                None => [&source[..], b"_", group.to_string().as_bytes()].concat(),
+            })
+            .collect()
+    }
+}
+
+fn column_name(header: Option<&Header>, index: usize) -> Vec<u8> {
+    match header.and_then(|h| h.name(index)) {
+        Some(name) => name.to_vec(),
+        None => format!("col{}", index).into_bytes(),
+    }
+}
+
+impl Selection {
+    /// Selects input columns `0..width` unchanged.
+    pub fn all(width: usize) -> Self {
+        Self {
+            fields: (0..width).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Highest input column the selection reads.
+    pub fn max_column(&self) -> Option<usize> {
+        self.fields
+            .iter()
+            .copied()
+            .chain(self.extracts.iter().map(|e| e.column))
+            .max()
+    }
+
+    /// Names of the output columns, from the header if there is one and
+    /// `col<N>` for input column N otherwise.
+    pub fn column_names(&self, header: Option<&Header>) -> Vec<Vec<u8>> {
+        let mut names: Vec<Vec<u8>> = self.fields.iter().map(|&i| column_name(header, i)).collect();
+        for extract in &self.extracts {
+            names.extend(extract.names(header));
+        }
+        names
+    }
+
+    /// Projects one row like `project`, applying the empty policy. Returns
+    /// false if the row is dropped; values already emitted are then void.
+    pub fn project_row<F: AsRef<[u8]>>(&self, fields: &[F], mut emit: impl FnMut(usize, &[u8])) -> bool {
+        let mut written = 0;
+        let mut drop_row = false;
+        self.project(fields, |column, value| {
+            let value = match (&self.empty, value.is_empty()) {
+                (_, false) | (EmptyPolicy::Keep, true) => value,
+                (EmptyPolicy::Fill(fill), true) => fill,
+                (EmptyPolicy::DropRow, true) => {
+                    drop_row = true;
+                    return;
+                }
+                (EmptyPolicy::Skip, true) => return,
+            };
+            emit(column, value);
+            written += 1;
+        });
+        !drop_row && (written > 0 || self.empty != EmptyPolicy::Skip)
+    }
+
+    /// Calls `emit` with the output column index and value of each output
+    /// value of one row in order. Captures that did not match produce empty
+    /// values.
+    pub fn project<F: AsRef<[u8]>>(&self, fields: &[F], mut emit: impl FnMut(usize, &[u8])) {
+        let mut column = 0;
+        for &index in &self.fields {
+            emit(column, fields.get(index).map_or(&[][..], |f| f.as_ref()));
+            column += 1;
+        }
+
+        for extract in &self.extracts {
+            let field = fields.get(extract.column).map_or(&[][..], |f| f.as_ref());
+            let captures = extract.regex.captures(field);
+            for group in extract.groups() {
+                let value = captures.as_ref().and_then(|c| c.get(group));
+                emit(column, value.map_or(&[][..], |m| m.as_bytes()));
+                column += 1;
+            }
+        }
+    }
+}
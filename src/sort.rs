@@ -0,0 +1,537 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::Error;
+use crate::expr::parse_number;
+use crate::header::Column;
+use crate::spill::{SpillDir, SpillReader};
+
+/// Number of runs merged at once; more runs are merged in several passes
+const FAN_IN: usize = 64;
+/// Approximate bookkeeping cost of a buffered row besides its bytes
+const ROW_OVERHEAD: usize = 32;
+
+/// How the values of a sort key are compared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compare {
+    /// Byte by byte
+    #[default]
+    Lexicographic,
+    /// As numbers; values that are not numbers sort after all numbers, or
+    /// before them when descending
+    Numeric,
+    /// Runs of digits as numbers and everything else byte by byte, so
+    /// `file2` sorts before `file10`
+    Natural,
+}
+
+/// A column to sort on, with its direction and comparison.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SortKey {
+    pub column: Column,
+    pub descending: bool,
+    pub compare: Compare,
+}
+
+impl SortKey {
+    /// Sorts ascending and lexicographically on `column`.
+    pub fn new(column: impl Into<Column>) -> Self {
+        Self {
+            column: column.into(),
+            descending: false,
+            compare: Compare::default(),
+        }
+    }
+
+    pub fn descending(mut self) -> Self {
+        self.descending = true;
+        self
+    }
+
+    pub fn compare(mut self, compare: Compare) -> Self {
+        self.compare = compare;
+        self
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    /// Parses `COL[:asc|desc][:lex|numeric|natural]`, with the modifiers in
+    /// any order.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<&str> = s.split(':').collect();
+        let mut key = SortKey::new("");
+        while parts.len() > 1 {
+            match *parts.last().unwrap() {
+                "asc" => key.descending = false,
+                "desc" => key.descending = true,
+                "lex" => key.compare = Compare::Lexicographic,
+                "numeric" | "n" => key.compare = Compare::Numeric,
+                "natural" => key.compare = Compare::Natural,
+                _ => break,
+            }
+            parts.pop();
+        }
+        let column = parts.join(":");
+        if column.is_empty() {
+            return Err(format!("expected COL[:asc|desc][:lex|numeric|natural] but got '{}'", s));
+        }
+        key.column = Column::from(column.as_str());
+        Ok(key)
+    }
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.column)?;
+        if self.descending {
+            write!(f, ":desc")?;
+        }
+        match self.compare {
+            Compare::Lexicographic => Ok(()),
+            Compare::Numeric => write!(f, ":numeric"),
+            Compare::Natural => write!(f, ":natural"),
+        }
+    }
+}
+
+/// Sort keys resolved against the output columns. Rows are compared
+/// through an encoding of their key values whose byte order is the sort
+/// order, so sorting, spilling and merging only compare bytes.
+pub struct SortOrder {
+    /// Whether each output column is captured for the key
+    pub columns: Vec<bool>,
+    /// Position among the captured values, direction and comparison of
+    /// each key
+    keys: Vec<(usize, bool, Compare)>,
+}
+
+impl SortOrder {
+    /// `keys` holds the output column index of each key.
+    pub fn new(width: usize, keys: &[(usize, &SortKey)]) -> Self {
+        let mut columns = vec![false; width];
+        for &(index, _) in keys {
+            columns[index] = true;
+        }
+        let keys = keys
+            .iter()
+            .map(|&(index, key)| {
+                let position = columns[..index].iter().filter(|&&captured| captured).count();
+                (position, key.descending, key.compare)
+            })
+            .collect();
+        Self { columns, keys }
+    }
+
+    /// Encodes the captured values, each prefixed with its length, as a
+    /// sort key.
+    pub fn encode(&self, captured: &[u8], out: &mut Vec<u8>) {
+        let mut values = Vec::new();
+        let mut rest = captured;
+        while rest.len() >= 4 {
+            let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            values.push(&rest[4..4 + len]);
+            rest = &rest[4 + len..];
+        }
+
+        for &(position, descending, compare) in &self.keys {
+            let value = values.get(position).copied().unwrap_or_default();
+            let start = out.len();
+            match compare {
+                Compare::Lexicographic => encode_bytes(value, out),
+                Compare::Numeric => match parse_number(value) {
+                    Some(number) => {
+                        out.push(0);
+                        // Flipping the sign bit, or every bit of a negative
+                        // number, orders floats as unsigned integers
+                        let bits = (number + 0.0).to_bits();
+                        let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+                        out.extend_from_slice(&bits.to_be_bytes());
+                    }
+                    None => {
+                        out.push(1);
+                        encode_bytes(value, out);
+                    }
+                },
+                Compare::Natural => encode_natural(value, out),
+            }
+            // Every encoding is prefix-free, so inverting it reverses the order
+            if descending {
+                for byte in &mut out[start..] {
+                    *byte = !*byte;
+                }
+            }
+        }
+    }
+}
+
+/// Escapes zero bytes and terminates the value, so a value sorts before
+/// any longer value it is a prefix of.
+fn encode_bytes(value: &[u8], out: &mut Vec<u8>) {
+    for &byte in value {
+        out.push(byte);
+        if byte == 0 {
+            out.push(0xFF);
+        }
+    }
+    out.extend_from_slice(&[0, 0]);
+}
+
+/// Encodes runs of digits as a `0` marker, the number of significant
+/// digits and the digits, so they compare as numbers against each other
+/// and as digits against other bytes.
+fn encode_natural(value: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < value.len() {
+        if !value[i].is_ascii_digit() {
+            out.push(value[i]);
+            if value[i] == 0 {
+                out.push(0xFF);
+            }
+            i += 1;
+            continue;
+        }
+
+        let end = value[i..].iter().position(|b| !b.is_ascii_digit()).map_or(value.len(), |n| i + n);
+        let digits = &value[i..end];
+        let significant = &digits[digits.iter().position(|&b| b != b'0').unwrap_or(digits.len())..];
+        out.push(b'0');
+        out.extend_from_slice(&(significant.len() as u32).to_be_bytes());
+        out.extend_from_slice(significant);
+        i = end;
+    }
+    out.extend_from_slice(&[0, 0]);
+}
+
+/// The rows of one chunk with their encoded keys, sorted on the worker
+/// that processed the chunk.
+pub struct SortedChunk {
+    text: Vec<u8>,
+    keys: Vec<u8>,
+    /// Key and row ranges in `keys` and `text`, in sorted order
+    rows: Vec<(usize, usize, usize, usize)>,
+}
+
+impl SortedChunk {
+    /// Sorts serialized rows, given the end offsets of each row in `text`
+    /// and of its captured values in `captured`. Equal keys keep their order.
+    pub fn new(text: Vec<u8>, captured: &[u8], ends: &[(usize, usize)], order: &SortOrder) -> Self {
+        let mut keys = Vec::new();
+        let mut key_ends = Vec::with_capacity(ends.len());
+        let mut start = 0;
+        for &(row_end, captured_end) in ends {
+            order.encode(&captured[start..captured_end], &mut keys);
+            key_ends.push((row_end, keys.len()));
+            start = captured_end;
+        }
+        Self::with_keys(text, keys, &key_ends)
+    }
+
+    /// Sorts serialized rows on keys that are already encoded, given the
+    /// end offsets of each row in `text` and of its key in `keys`. Equal
+    /// keys keep their order.
+    pub fn with_keys(text: Vec<u8>, keys: Vec<u8>, ends: &[(usize, usize)]) -> Self {
+        let mut rows = Vec::with_capacity(ends.len());
+        let mut start = (0, 0);
+        for &(row_end, key_end) in ends {
+            rows.push((start.1, key_end, start.0, row_end));
+            start = (row_end, key_end);
+        }
+        rows.sort_by(|a, b| keys[a.0..a.1].cmp(&keys[b.0..b.1]));
+        Self { text, keys, rows }
+    }
+
+    fn get(&self, i: usize) -> (&[u8], &[u8]) {
+        let (key_start, key_end, row_start, row_end) = self.rows[i];
+        (&self.keys[key_start..key_end], &self.text[row_start..row_end])
+    }
+
+    fn size(&self) -> usize {
+        self.text.len() + self.keys.len() + self.rows.len() * ROW_OVERHEAD
+    }
+}
+
+/// Collects sorted chunks in the writer stage, where they arrive in input
+/// order. Chunks are held in memory up to the budget, then merged into a
+/// sorted run on disk; at the end the runs are merged into the output.
+/// Ties are broken by input order throughout, so the sort is stable.
+pub struct Sorter<'a> {
+    budget: usize,
+    used: usize,
+    batch: Vec<SortedChunk>,
+    temp_dir: &'a Path,
+    spill: Option<SpillDir>,
+    runs: Vec<SpillReader>,
+}
+
+impl<'a> Sorter<'a> {
+    pub fn new(budget: usize, temp_dir: &'a Path) -> Self {
+        Self {
+            budget,
+            used: 0,
+            batch: Vec::new(),
+            temp_dir,
+            spill: None,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Adds the next chunk in input order.
+    pub fn push(&mut self, chunk: SortedChunk) -> Result<(), Error> {
+        self.used += chunk.size();
+        self.batch.push(chunk);
+        if self.used > self.budget {
+            self.spill_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Merges the buffered chunks into a run on disk.
+    fn spill_batch(&mut self) -> Result<(), Error> {
+        if self.spill.is_none() {
+            self.spill = Some(SpillDir::new(self.temp_dir)?);
+        }
+        let dir = self.spill.as_ref().unwrap();
+        let mut run = dir.create()?;
+        merge_chunks(&self.batch, &mut |key, row| Ok(run.write(&[key, row])?))?;
+        self.runs.push(run.finish()?);
+        self.batch.clear();
+        self.used = 0;
+        Ok(())
+    }
+
+    /// Writes all rows in sorted order.
+    pub fn finish(mut self, emit: &mut impl FnMut(&[u8]) -> Result<(), Error>) -> Result<(), Error> {
+        if self.runs.is_empty() {
+            return merge_chunks(&self.batch, &mut |_, row| emit(row));
+        }
+        if !self.batch.is_empty() {
+            self.spill_batch()?;
+        }
+
+        // Merge consecutive runs, so earlier rows stay first among equals
+        let dir = self.spill.take().unwrap();
+        let mut runs = std::mem::take(&mut self.runs);
+        while runs.len() > FAN_IN {
+            let mut merged = Vec::new();
+            let mut group = Vec::new();
+            for run in runs {
+                group.push(run);
+                if group.len() == FAN_IN {
+                    let mut out = dir.create()?;
+                    merge_runs(std::mem::take(&mut group), &mut |key, row| Ok(out.write(&[key, row])?))?;
+                    merged.push(out.finish()?);
+                }
+            }
+            if !group.is_empty() {
+                let mut out = dir.create()?;
+                merge_runs(group, &mut |key, row| Ok(out.write(&[key, row])?))?;
+                merged.push(out.finish()?);
+            }
+            runs = merged;
+        }
+        merge_runs(runs, &mut |_, row| emit(row))
+    }
+}
+
+/// Merges sorted chunks held in memory.
+fn merge_chunks(
+    chunks: &[SortedChunk],
+    emit: &mut impl FnMut(&[u8], &[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut next = vec![0; chunks.len()];
+    let mut heap = BinaryHeap::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        if !chunk.rows.is_empty() {
+            heap.push(Reverse((chunk.get(0).0, i)));
+        }
+    }
+
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let (key, row) = chunks[i].get(next[i]);
+        emit(key, row)?;
+        next[i] += 1;
+        if next[i] < chunks[i].rows.len() {
+            heap.push(Reverse((chunks[i].get(next[i]).0, i)));
+        }
+    }
+    Ok(())
+}
+
+/// Merges runs of `(key, row)` records on disk.
+fn merge_runs(
+    mut runs: Vec<SpillReader>,
+    emit: &mut impl FnMut(&[u8], &[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut heads = Vec::with_capacity(runs.len());
+    let mut heap = BinaryHeap::new();
+    for (i, run) in runs.iter_mut().enumerate() {
+        let mut fields = [Vec::new(), Vec::new()];
+        if run.read(&mut fields)? {
+            heap.push(Reverse((fields[0].clone(), i)));
+        }
+        heads.push(fields);
+    }
+
+    while let Some(Reverse((_, i))) = heap.pop() {
+        let [key, row] = &heads[i];
+        emit(key, row)?;
+        if runs[i].read(&mut heads[i])? {
+            heap.push(Reverse((heads[i][0].clone(), i)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sort order on the first output column of rows one column wide.
+    fn order(descending: bool, compare: Compare) -> SortOrder {
+        let key = SortKey { descending, compare, ..SortKey::new(0) };
+        SortOrder::new(1, &[(0, &key)])
+    }
+
+    fn capture(values: &[&[u8]]) -> Vec<u8> {
+        let mut captured = Vec::new();
+        for value in values {
+            captured.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            captured.extend_from_slice(value);
+        }
+        captured
+    }
+
+    fn encode(order: &SortOrder, values: &[&[u8]]) -> Vec<u8> {
+        let mut key = Vec::new();
+        order.encode(&capture(values), &mut key);
+        key
+    }
+
+    /// Sorts `values` through their encoded keys.
+    fn sorted<'a>(order: &SortOrder, values: &[&'a str]) -> Vec<&'a str> {
+        let mut values = values.to_vec();
+        values.sort_by_cached_key(|value| encode(order, &[value.as_bytes()]));
+        values
+    }
+
+    #[test]
+    fn encodes_lexicographic_keys() {
+        let order = order(false, Compare::Lexicographic);
+        assert_eq!(sorted(&order, &["b", "ab", "", "a", "a\0", "B"]), ["", "B", "a", "a\0", "ab", "b"]);
+        let order = self::order(true, Compare::Lexicographic);
+        assert_eq!(sorted(&order, &["b", "ab", "", "a"]), ["b", "ab", "a", ""]);
+    }
+
+    #[test]
+    fn encodes_numeric_keys() {
+        let order = order(false, Compare::Numeric);
+        let values = ["10", "x", "-2.5", "9", "0", "-0", "1e3", "-100", "", " 7 "];
+        assert_eq!(sorted(&order, &values), ["-100", "-2.5", "0", "-0", " 7 ", "9", "10", "1e3", "", "x"]);
+        assert_eq!(encode(&order, &[b"0"]), encode(&order, &[b"-0"]));
+
+        // Descending puts the values that are not numbers first
+        let order = self::order(true, Compare::Numeric);
+        assert_eq!(sorted(&order, &["1", "x", "-1", "20"]), ["x", "20", "1", "-1"]);
+    }
+
+    #[test]
+    fn encodes_natural_keys() {
+        let order = order(false, Compare::Natural);
+        let values = ["file10", "file2", "file02b", "file", "file2a", "File3", "a100b", "a20b"];
+        assert_eq!(sorted(&order, &values), ["File3", "a20b", "a100b", "file", "file2", "file2a", "file02b", "file10"]);
+    }
+
+    #[test]
+    fn later_keys_break_ties() {
+        let keys = [
+            SortKey::new(1),
+            SortKey::new(0).descending().compare(Compare::Numeric),
+        ];
+        let order = SortOrder::new(3, &[(1, &keys[0]), (0, &keys[1])]);
+        assert_eq!(order.columns, [true, true, false]);
+        // Values are captured in column order: column 0, then column 1
+        let mut rows = [["1", "b"], ["3", "a"], ["2", "b"], ["10", "a"]];
+        rows.sort_by_cached_key(|row| encode(&order, &[row[0].as_bytes(), row[1].as_bytes()]));
+        assert_eq!(rows, [["10", "a"], ["3", "a"], ["2", "b"], ["1", "b"]]);
+    }
+
+    /// Rows of `key,index`
+    type Rows = Vec<(&'static str, usize)>;
+
+    /// A chunk of `rows`, sorted on the key.
+    fn chunk(rows: &[(&str, usize)], order: &SortOrder) -> SortedChunk {
+        let (mut text, mut captured, mut ends) = (Vec::new(), Vec::new(), Vec::new());
+        for (key, index) in rows {
+            text.extend_from_slice(format!("{},{}\n", key, index).as_bytes());
+            captured.extend_from_slice(&capture(&[key.as_bytes()]));
+            ends.push((text.len(), captured.len()));
+        }
+        SortedChunk::new(text, &captured, &ends, order)
+    }
+
+    fn sort_chunks(chunks: &[Rows], budget: usize) -> Vec<u8> {
+        let order = order(false, Compare::Numeric);
+        let temp_dir = std::env::temp_dir();
+        let mut sorter = Sorter::new(budget, &temp_dir);
+        for rows in chunks {
+            sorter.push(chunk(rows, &order)).unwrap();
+        }
+        let mut out = Vec::new();
+        sorter
+            .finish(&mut |row| {
+                out.extend_from_slice(row);
+                Ok(())
+            })
+            .unwrap();
+        out
+    }
+
+    /// Rows with few distinct keys, so most rows tie, split into chunks.
+    fn chunks(count: usize) -> (Vec<Rows>, Vec<u8>) {
+        const KEYS: [&str; 5] = ["3", "1", "x", "2", "10"];
+        let rows: Vec<_> = (0..count).map(|i| (KEYS[(i * 3 + i / 7) % KEYS.len()], i)).collect();
+
+        // A stable sort of all rows at once is the reference
+        let order = order(false, Compare::Numeric);
+        let mut expected = rows.clone();
+        expected.sort_by_cached_key(|(key, _)| encode(&order, &[key.as_bytes()]));
+        let expected = expected.iter().flat_map(|(key, i)| format!("{},{}\n", key, i).into_bytes()).collect();
+        (rows.chunks(3).map(<[_]>::to_vec).collect(), expected)
+    }
+
+    #[test]
+    fn sorts_stably_in_memory() {
+        let (chunks, expected) = chunks(100);
+        assert_eq!(sort_chunks(&chunks, usize::MAX), expected);
+    }
+
+    #[test]
+    fn sorts_stably_when_spilling() {
+        let (chunks, expected) = chunks(100);
+        // A run for every chunk, and a run for every few chunks
+        assert_eq!(sort_chunks(&chunks, 0), expected);
+        assert_eq!(sort_chunks(&chunks, 200), expected);
+    }
+
+    #[test]
+    fn merges_more_runs_than_the_fan_in() {
+        let (chunks, expected) = chunks(3 * (FAN_IN * 2 + 5));
+        assert!(chunks.len() > FAN_IN * 2);
+        assert_eq!(sort_chunks(&chunks, 0), expected);
+    }
+
+    #[test]
+    fn parses_and_displays_sort_keys() {
+        let key: SortKey = "price:desc:numeric".parse().unwrap();
+        assert_eq!(key, SortKey::new("price").descending().compare(Compare::Numeric));
+        assert_eq!(key.to_string(), "price:desc:numeric");
+        assert_eq!("a:b:natural".parse::<SortKey>().unwrap(), SortKey::new("a:b").compare(Compare::Natural));
+        assert_eq!("name".parse::<SortKey>().unwrap().to_string(), "name");
+        assert!(":desc".parse::<SortKey>().is_err());
+    }
+}
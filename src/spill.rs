@@ -0,0 +1,166 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::Error;
+
+/// Numbers the spill directories of this process
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+/// Number of hash partitions when the size of the data is unknown
+const DEFAULT_PARTITIONS: usize = 256;
+
+/// Private directory for the spill files of one run, removed with its
+/// contents when dropped.
+pub struct SpillDir {
+    path: PathBuf,
+    next: AtomicUsize,
+}
+
+impl SpillDir {
+    /// Creates a new directory under `parent`.
+    pub fn new(parent: &Path) -> io::Result<Self> {
+        let name = format!("pulsecsv-{}-{}", std::process::id(), NEXT_DIR.fetch_add(1, Ordering::Relaxed));
+        let path = parent.join(name);
+        fs::create_dir_all(&path)?;
+        Ok(Self {
+            path,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Creates an empty spill file in the directory.
+    pub fn create(&self) -> io::Result<SpillWriter> {
+        let path = self.path.join(format!("{}.spill", self.next.fetch_add(1, Ordering::Relaxed)));
+        let file = File::options().read(true).write(true).create_new(true).open(path)?;
+        Ok(SpillWriter {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Appends records of length-prefixed fields to a spill file.
+pub struct SpillWriter {
+    writer: BufWriter<File>,
+}
+
+impl SpillWriter {
+    pub fn write(&mut self, fields: &[&[u8]]) -> io::Result<()> {
+        for field in fields {
+            self.writer.write_all(&(field.len() as u32).to_le_bytes())?;
+            self.writer.write_all(field)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the file and reads it back from the start.
+    pub fn finish(self) -> io::Result<SpillReader> {
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.rewind()?;
+        Ok(SpillReader {
+            reader: BufReader::new(file),
+        })
+    }
+}
+
+/// Reads back the records of a spill file.
+pub struct SpillReader {
+    reader: BufReader<File>,
+}
+
+impl SpillReader {
+    /// Reads the next record into `fields`, which must have as many entries
+    /// as the records were written with. Returns false at the end of the file.
+    pub fn read(&mut self, fields: &mut [Vec<u8>]) -> io::Result<bool> {
+        for (i, field) in fields.iter_mut().enumerate() {
+            let mut len = [0; 4];
+            match self.reader.read_exact(&mut len) {
+                Err(e) if i == 0 && e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                result => result?,
+            }
+            field.clear();
+            field.resize(u32::from_le_bytes(len) as usize, 0);
+            self.reader.read_exact(field)?;
+        }
+        Ok(true)
+    }
+}
+
+/// Number of hash partitions for spilling data of `bytes` bytes, so each
+/// partition is expected to fit in `budget`.
+pub fn partition_count(bytes: Option<u64>, budget: usize) -> usize {
+    match bytes {
+        Some(bytes) => (bytes / budget.max(1) as u64 * 2).clamp(16, 512) as usize,
+        None => DEFAULT_PARTITIONS,
+    }
+}
+
+/// Hash used to partition keys, the same for every run.
+pub fn hash_key(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key);
+    hasher.finish()
+}
+
+/// Appends `value` prefixed with its length.
+pub fn push_value(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+/// Reads back values written by `push_value`.
+pub struct Values<'a>(pub &'a [u8]);
+
+impl<'a> Iterator for Values<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.0.len() < 4 {
+            return None;
+        }
+        let len = u32::from_le_bytes(self.0[..4].try_into().unwrap()) as usize;
+        let value = &self.0[4..4 + len];
+        self.0 = &self.0[4 + len..];
+        Some(value)
+    }
+}
+
+/// Reads the position stored in the first 8 bytes of `field`.
+pub fn position(field: &[u8]) -> u64 {
+    u64::from_le_bytes(field[..8].try_into().unwrap())
+}
+
+/// Merges runs of `(position, row)` records, each sorted by position, and
+/// emits the rows in position order. Rows with the same position come
+/// from one run and keep their order.
+pub fn merge_by_position(
+    mut runs: Vec<SpillReader>,
+    emit: &mut impl FnMut(&[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut heads = Vec::with_capacity(runs.len());
+    let mut heap = BinaryHeap::new();
+    for (i, run) in runs.iter_mut().enumerate() {
+        let mut fields = [Vec::new(), Vec::new()];
+        if run.read(&mut fields)? {
+            heap.push(Reverse((position(&fields[0]), i)));
+        }
+        heads.push(fields);
+    }
+
+    while let Some(Reverse((_, i))) = heap.pop() {
+        emit(&heads[i][1])?;
+        if runs[i].read(&mut heads[i])? {
+            heap.push(Reverse((position(&heads[i][0]), i)));
+        }
+    }
+    Ok(())
+}
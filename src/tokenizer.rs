@@ -0,0 +1,411 @@
+use memchr::{memchr, memchr2};
+use std::borrow::Cow;
+
+use crate::error::ParseErrorKind;
+
+/// Quote-aware CSV tokenizer following RFC 4180.
+///
+/// A quote only opens a quoted field when it is the first byte of the field.
+/// Inside a quoted field delimiters and newlines are literal, and a quote is
+/// escaped either by doubling it (the default) or by a distinct escape byte.
+#[derive(Clone, Copy, Debug)]
+pub struct Tokenizer {
+    delimiter: u8,
+    quote: Option<u8>,
+    escape: Option<u8>,
+}
+
+impl Tokenizer {
+    pub fn new(delimiter: u8, quote: Option<u8>, escape: Option<u8>) -> Self {
+        // An escape equal to the quote is the plain doubled-quote rule
+        let escape = escape.filter(|&e| Some(e) != quote);
+        Self {
+            delimiter,
+            quote,
+            escape,
+        }
+    }
+
+    /// Returns an iterator over the records in `data`, which must start on a
+    /// record boundary. Records are yielded without their line terminator.
+    pub fn records<'t, 'a>(&'t self, data: &'a [u8]) -> Records<'t, 'a> {
+        Records {
+            tokenizer: self,
+            data,
+            pos: 0,
+        }
+    }
+
+    /// Returns an iterator that splits `data` into chunks of roughly
+    /// `chunk_size` bytes, each ending on a record boundary.
+    pub fn chunks<'a>(&self, data: &'a [u8], chunk_size: usize) -> Chunks<'a> {
+        Chunks {
+            tokenizer: *self,
+            data,
+            pos: 0,
+            chunk_size: chunk_size.max(1),
+        }
+    }
+
+    /// Returns the position just past the first record terminator at or after
+    /// `target`, scanning forward from `start` which must be a record boundary.
+    /// Returns `data.len()` if no such terminator exists.
+    pub fn find_boundary(&self, data: &[u8], start: usize, target: usize) -> usize {
+        let quote = match self.quote {
+            Some(quote) => quote,
+            None => return next_line(data, target),
+        };
+
+        // Without any quote between the boundary and the target the quoting
+        // state is known, so the scan can resume directly at the target
+        let mut pos = if target > start && memchr(quote, &data[start..target]).is_none() {
+            target
+        } else {
+            start
+        };
+
+        while pos < data.len() {
+            let next = match memchr2(quote, b'\n', &data[pos..]) {
+                Some(i) => pos + i,
+                None => return data.len(),
+            };
+            if data[next] == b'\n' {
+                if next >= target {
+                    return next + 1;
+                }
+                pos = next + 1;
+            } else if self.is_field_start(data, next) {
+                pos = self.skip_quoted(data, next + 1);
+            } else {
+                pos = next + 1;
+            }
+        }
+
+        data.len()
+    }
+
+    /// Returns the position just past the record starting at `start`,
+    /// including its line terminator.
+    pub fn record_end(&self, data: &[u8], start: usize) -> usize {
+        self.find_boundary(data, start, start)
+    }
+
+    /// Splits a single record into its fields, appending them to `fields`.
+    /// Fields are borrowed from the record unless unescaping was required.
+    ///
+    /// Malformed quoting is split leniently, and the first problem is
+    /// returned with the index of the field it was found in.
+    pub fn split_fields<'a>(&self, record: &'a [u8], fields: &mut Vec<Cow<'a, [u8]>>) -> Option<(usize, ParseErrorKind)> {
+        let mut malformed = None;
+        let mut pos = 0;
+        loop {
+            let (field, end) = match self.quote {
+                Some(quote) if record.get(pos) == Some(&quote) => {
+                    let (field, end, problem) = self.quoted_field(record, pos + 1);
+                    if let (Some(kind), None) = (problem, &malformed) {
+                        malformed = Some((fields.len(), kind));
+                    }
+                    (field, end)
+                }
+                _ => {
+                    let end = memchr(self.delimiter, &record[pos..]).map_or(record.len(), |i| pos + i);
+                    (Cow::Borrowed(&record[pos..end]), end)
+                }
+            };
+            fields.push(field);
+
+            if end >= record.len() {
+                break;
+            }
+            pos = end + 1;
+        }
+        malformed
+    }
+
+    fn is_field_start(&self, data: &[u8], pos: usize) -> bool {
+        pos == 0 || data[pos - 1] == self.delimiter || data[pos - 1] == b'\n'
+    }
+
+    /// Skips the body of a quoted field starting just after its opening quote,
+    /// returning the position just past the closing quote.
+    fn skip_quoted(&self, data: &[u8], mut pos: usize) -> usize {
+        while pos < data.len() {
+            let next = match self.next_special(data, pos) {
+                Some(next) => next,
+                None => return data.len(),
+            };
+            if self.is_escape(data, next) {
+                pos = next + 2;
+            } else {
+                return next + 1;
+            }
+        }
+        data.len()
+    }
+
+    /// Parses a quoted field whose body starts at `pos`, returning the
+    /// unescaped value, the position of the delimiter ending the field and
+    /// what was wrong with its quoting, if anything.
+    fn quoted_field<'a>(&self, record: &'a [u8], mut pos: usize) -> (Cow<'a, [u8]>, usize, Option<ParseErrorKind>) {
+        let body_start = pos;
+        let mut owned: Option<Vec<u8>> = None;
+
+        loop {
+            let next = match self.next_special(record, pos) {
+                Some(next) => next,
+                None => {
+                    // Unterminated quote: the rest of the record is the value
+                    let value = match owned {
+                        Some(mut buf) => {
+                            buf.extend_from_slice(&record[pos.min(record.len())..]);
+                            Cow::Owned(buf)
+                        }
+                        None => Cow::Borrowed(&record[body_start..]),
+                    };
+                    return (value, record.len(), Some(ParseErrorKind::UnterminatedQuote));
+                }
+            };
+
+            if self.is_escape(record, next) {
+                let buf = owned.get_or_insert_with(Vec::new);
+                buf.extend_from_slice(&record[pos..next]);
+                if let Some(&escaped) = record.get(next + 1) {
+                    buf.push(escaped);
+                }
+                pos = next + 2;
+                continue;
+            }
+
+            // Closing quote; anything up to the delimiter is kept literally
+            let tail_end = memchr(self.delimiter, &record[next + 1..]).map_or(record.len(), |i| next + 1 + i);
+            let problem = (tail_end > next + 1).then_some(ParseErrorKind::TextAfterQuote);
+            let value = match owned {
+                None if tail_end == next + 1 => Cow::Borrowed(&record[body_start..next]),
+                owned => {
+                    let mut buf = owned.unwrap_or_default();
+                    buf.extend_from_slice(&record[pos..next]);
+                    buf.extend_from_slice(&record[next + 1..tail_end]);
+                    Cow::Owned(buf)
+                }
+            };
+            return (value, tail_end, problem);
+        }
+    }
+
+    /// Whether the quote or escape byte at `pos` escapes the byte after it.
+    fn is_escape(&self, data: &[u8], pos: usize) -> bool {
+        match self.escape {
+            Some(escape) => data[pos] == escape,
+            None => self.quote.is_some() && data.get(pos + 1) == self.quote.as_ref(),
+        }
+    }
+
+    /// Finds the next quote or escape byte at or after `pos`.
+    fn next_special(&self, data: &[u8], pos: usize) -> Option<usize> {
+        if pos >= data.len() {
+            return None;
+        }
+        let quote = self.quote.unwrap_or(b'"');
+        let found = match self.escape {
+            Some(escape) => memchr2(quote, escape, &data[pos..]),
+            None => memchr(quote, &data[pos..]),
+        };
+        found.map(|i| pos + i)
+    }
+}
+
+/// Number of line feeds in `data`.
+pub fn count_lines(data: &[u8]) -> u64 {
+    memchr::memchr_iter(b'\n', data).count() as u64
+}
+
+fn next_line(data: &[u8], pos: usize) -> usize {
+    if pos >= data.len() {
+        return data.len();
+    }
+    memchr(b'\n', &data[pos..]).map_or(data.len(), |i| pos + i + 1)
+}
+
+/// Iterator over the records of a chunk, see [`Tokenizer::records`].
+pub struct Records<'t, 'a> {
+    tokenizer: &'t Tokenizer,
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'t, 'a> Iterator for Records<'t, 'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+
+        let start = self.pos;
+        let end = self.tokenizer.record_end(self.data, start);
+        self.pos = end;
+
+        let mut record = &self.data[start..end];
+        if let Some(rest) = record.strip_suffix(b"\n") {
+            record = rest;
+        }
+        if let Some(rest) = record.strip_suffix(b"\r") {
+            record = rest;
+        }
+        Some(record)
+    }
+}
+
+/// Iterator over record-aligned chunks, see [`Tokenizer::chunks`].
+pub struct Chunks<'a> {
+    tokenizer: Tokenizer,
+    data: &'a [u8],
+    pos: usize,
+    chunk_size: usize,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+
+        let start = self.pos;
+        let target = (start + self.chunk_size).min(self.data.len());
+        self.pos = self.tokenizer.find_boundary(self.data, start, target);
+        Some(&self.data[start..self.pos])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"id,note,city\n1,\"multi\nline, with comma\",Paris\n2,\"say \"\"hi\"\"\nthen \"\"bye\"\"\",Lyon\n3,plain,Nice\n";
+
+    fn split(tokenizer: &Tokenizer, record: &[u8]) -> (Vec<Vec<u8>>, Option<(usize, ParseErrorKind)>) {
+        let mut fields = Vec::new();
+        let problem = tokenizer.split_fields(record, &mut fields);
+        (fields.into_iter().map(Cow::into_owned).collect(), problem)
+    }
+
+    fn fields(tokenizer: &Tokenizer, data: &[u8]) -> Vec<Vec<Vec<u8>>> {
+        tokenizer.records(data).map(|record| split(tokenizer, record).0).collect()
+    }
+
+    #[test]
+    fn splits_plain_and_quoted_fields() {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), None);
+        let (fields, problem) = split(&tokenizer, b"a,\"b,c\",,\"d\"\"e\"");
+        assert_eq!(fields, [&b"a"[..], b"b,c", b"", b"d\"e"]);
+        assert_eq!(problem, None);
+
+        let mut fields = Vec::new();
+        tokenizer.split_fields(b"\"plain\",\"esc\"\"aped\"", &mut fields);
+        assert!(matches!(fields[0], Cow::Borrowed(_)));
+        assert!(matches!(fields[1], Cow::Owned(_)));
+    }
+
+    #[test]
+    fn reports_the_first_malformed_field() {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), None);
+        let (fields, problem) = split(&tokenizer, b"a,\"b\"x,\"c");
+        assert_eq!(fields, [&b"a"[..], b"bx", b"c"]);
+        assert_eq!(problem, Some((1, ParseErrorKind::TextAfterQuote)));
+
+        let (fields, problem) = split(&tokenizer, b"a,\"open, still");
+        assert_eq!(fields, [&b"a"[..], b"open, still"]);
+        assert_eq!(problem, Some((1, ParseErrorKind::UnterminatedQuote)));
+    }
+
+    #[test]
+    fn a_quote_inside_a_field_is_literal() {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), None);
+        assert_eq!(split(&tokenizer, b"5\" disk,x"), (vec![b"5\" disk".to_vec(), b"x".to_vec()], None));
+    }
+
+    #[test]
+    fn splits_with_an_escape_byte() {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), Some(b'\\'));
+        let (fields, problem) = split(&tokenizer, b"\"a\\\"b\\\\c\",d");
+        assert_eq!(fields, [&b"a\"b\\c"[..], b"d"]);
+        assert_eq!(problem, None);
+    }
+
+    #[test]
+    fn splits_on_every_delimiter_without_quotes() {
+        let tokenizer = Tokenizer::new(b',', None, None);
+        let (fields, _) = split(&tokenizer, b"\"a,b\"");
+        assert_eq!(fields, [&b"\"a"[..], b"b\""]);
+    }
+
+    #[test]
+    fn records_keep_quoted_newlines_and_strip_terminators() {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), None);
+        let records: Vec<_> = tokenizer.records(b"a,\"x\r\ny\"\r\nb,c\r\n\nd").collect();
+        assert_eq!(records, [&b"a,\"x\r\ny\""[..], b"b,c", b"", b"d"]);
+    }
+
+    #[test]
+    fn find_boundary_skips_quoted_newlines() {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), None);
+        let data = b"1,\"a\nb\"\n2,c\n";
+        // Targets inside the quoted field move to the end of its record
+        for target in 0..8 {
+            assert_eq!(tokenizer.find_boundary(data, 0, target), 8, "target {}", target);
+        }
+        assert_eq!(tokenizer.find_boundary(data, 0, 8), 12);
+        assert_eq!(tokenizer.find_boundary(data, 8, 9), 12);
+        assert_eq!(tokenizer.find_boundary(data, 0, 12), 12);
+        assert_eq!(tokenizer.record_end(data, 8), 12);
+    }
+
+    #[test]
+    fn find_boundary_skips_escaped_quotes() {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), None);
+        // The doubled quotes do not close the field, so the newline after
+        // them is still inside it
+        let data = b"\"x\"\"\ny\"\"\"\nz\n";
+        assert_eq!(tokenizer.find_boundary(data, 0, 1), 10);
+        assert_eq!(tokenizer.find_boundary(data, 0, 5), 10);
+
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), Some(b'\\'));
+        let data = b"\"x\\\"\ny\"\nz\n";
+        assert_eq!(tokenizer.find_boundary(data, 0, 1), 8);
+    }
+
+    #[test]
+    fn chunks_end_on_record_boundaries_at_every_size() {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), None);
+        let expected = fields(&tokenizer, DATA);
+        assert_eq!(expected.len(), 4);
+        assert_eq!(expected[1][1], b"multi\nline, with comma");
+        assert_eq!(expected[2][1], b"say \"hi\"\nthen \"bye\"");
+
+        for chunk_size in 1..=DATA.len() + 1 {
+            let chunks: Vec<_> = tokenizer.chunks(DATA, chunk_size).collect();
+            assert_eq!(chunks.concat(), DATA, "chunk size {}", chunk_size);
+            let records: Vec<_> = chunks.iter().flat_map(|chunk| fields(&tokenizer, chunk)).collect();
+            assert_eq!(records, expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn chunks_handle_a_last_record_without_terminator() {
+        let tokenizer = Tokenizer::new(b',', Some(b'"'), None);
+        let data = b"a,\"b\nc\"\nd,e";
+        for chunk_size in 1..=data.len() {
+            let chunks: Vec<_> = tokenizer.chunks(data, chunk_size).collect();
+            assert_eq!(chunks.concat(), data);
+            assert!(chunks.iter().all(|chunk| chunk.ends_with(b"\n") || chunk.ends_with(b"d,e")));
+        }
+    }
+
+    #[test]
+    fn counts_lines() {
+        assert_eq!(count_lines(b""), 0);
+        assert_eq!(count_lines(b"a\nb\r\nc"), 2);
+    }
+}
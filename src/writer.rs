@@ -0,0 +1,576 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// When output fields are wrapped in quotes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuoteStyle {
+    /// Never quote, even if the field contains the delimiter
+    Never,
+    /// Quote fields containing the delimiter, a quote or a line break
+    Necessary,
+    /// Quote every field
+    Always,
+    /// Quote every field that is not a number
+    NonNumeric,
+}
+
+impl FromStr for QuoteStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(QuoteStyle::Never),
+            "necessary" | "as-needed" => Ok(QuoteStyle::Necessary),
+            "always" => Ok(QuoteStyle::Always),
+            "non-numeric" => Ok(QuoteStyle::NonNumeric),
+            _ => Err(format!(
+                "unknown quote style '{}' (expected never, as-needed, always or non-numeric)",
+                s
+            )),
+        }
+    }
+}
+
+/// Record terminator written after each output row.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+}
+
+impl FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lf" | "unix" => Ok(LineEnding::Lf),
+            "crlf" | "windows" => Ok(LineEnding::CrLf),
+            _ => Err(format!("unknown line ending '{}' (expected lf or crlf)", s)),
+        }
+    }
+}
+
+/// How rows are serialized as delimited text.
+#[derive(Clone, Copy, Debug)]
+pub struct CsvFormat {
+    pub delimiter: u8,
+    pub quote: u8,
+    pub quote_style: QuoteStyle,
+    pub line_ending: LineEnding,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+            quote_style: QuoteStyle::Necessary,
+            line_ending: LineEnding::Lf,
+        }
+    }
+}
+
+impl CsvFormat {
+    /// Appends the value of output column `index`, preceded by the delimiter
+    /// unless it is the first column. Quotes inside quoted values are doubled.
+    pub fn write_value(&self, out: &mut Vec<u8>, index: usize, value: &[u8]) {
+        if index > 0 {
+            out.push(self.delimiter);
+        }
+
+        if !self.needs_quotes(value) {
+            out.extend_from_slice(value);
+            return;
+        }
+
+        out.push(self.quote);
+        for part in value.split_inclusive(|&b| b == self.quote) {
+            out.extend_from_slice(part);
+            if part.last() == Some(&self.quote) {
+                out.push(self.quote);
+            }
+        }
+        out.push(self.quote);
+    }
+
+    /// Appends an explicitly quoted empty value, unless quoting is disabled.
+    pub fn write_empty(&self, out: &mut Vec<u8>) {
+        if self.quote_style != QuoteStyle::Never {
+            out.extend_from_slice(&[self.quote, self.quote]);
+        }
+    }
+
+    pub fn end_record(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.line_ending.as_bytes());
+    }
+
+    fn needs_quotes(&self, value: &[u8]) -> bool {
+        match self.quote_style {
+            QuoteStyle::Never => false,
+            QuoteStyle::Always => true,
+            QuoteStyle::NonNumeric if !is_number(value) => true,
+            QuoteStyle::Necessary | QuoteStyle::NonNumeric => value
+                .iter()
+                .any(|&b| b == self.delimiter || b == self.quote || b == b'\n' || b == b'\r'),
+        }
+    }
+}
+
+/// Whether `value` is a plain decimal number such as `-7`, `+3.5`, `.5` or
+/// `1e-3`. Unlike `expr::parse_number`, surrounding whitespace makes a value
+/// text, so it keeps its quotes.
+fn is_number(value: &[u8]) -> bool {
+    let digits = |s: &[u8]| s.iter().take_while(|b| b.is_ascii_digit()).count();
+
+    let rest = value.strip_prefix(b"-").or_else(|| value.strip_prefix(b"+")).unwrap_or(value);
+    let int = digits(rest);
+    let mut rest = &rest[int..];
+    let mut mantissa = int;
+    if let Some(frac) = rest.strip_prefix(b".") {
+        let n = digits(frac);
+        mantissa += n;
+        rest = &frac[n..];
+    }
+    if mantissa == 0 {
+        return false;
+    }
+
+    if let Some(exp) = rest.strip_prefix(b"e").or_else(|| rest.strip_prefix(b"E")) {
+        let exp = exp.strip_prefix(b"+").or_else(|| exp.strip_prefix(b"-")).unwrap_or(exp);
+        let n = digits(exp);
+        if n == 0 {
+            return false;
+        }
+        rest = &exp[n..];
+    }
+
+    rest.is_empty()
+}
+
+/// Serialization used for output rows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    /// One JSON object per line (NDJSON)
+    JsonLines,
+    /// A single JSON array of objects
+    Json,
+    /// Apache Parquet, built by `columnar::BatchBuilder` rather than
+    /// serialized row by row
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "ndjson" => Ok(Format::JsonLines),
+            "json" => Ok(Format::Json),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(format!("unknown format '{}' (expected csv, jsonl, json or parquet)", s)),
+        }
+    }
+}
+
+/// Output serialization settings.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputFormat {
+    pub format: Format,
+    pub csv: CsvFormat,
+    /// Write JSON numbers, booleans and nulls instead of only strings
+    pub infer_types: bool,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        Self {
+            format: Format::Csv,
+            csv: CsvFormat::default(),
+            infer_types: false,
+        }
+    }
+}
+
+/// Serializes the rows of one run, with the output column names resolved.
+pub struct RowWriter {
+    format: OutputFormat,
+    /// Column names, written as the CSV header line if present
+    header: Option<Vec<Vec<u8>>>,
+    /// JSON object key prefixes (`"name":`) for each output column
+    keys: Vec<Vec<u8>>,
+}
+
+impl RowWriter {
+    /// Creates a writer for output columns called `names`. The names are
+    /// written as a CSV header only if the input had a header. A name that
+    /// repeats an earlier one gets a `_2`, `_3`, ... suffix as a JSON key,
+    /// so every object has distinct keys.
+    pub fn new(format: OutputFormat, names: Vec<Vec<u8>>, has_header: bool) -> Self {
+        let keys = unique_names(&names)
+            .iter()
+            .map(|name| {
+                let mut key = Vec::new();
+                write_json_string(&mut key, name);
+                key.push(b':');
+                key
+            })
+            .collect();
+        Self {
+            format,
+            header: has_header.then_some(names),
+            keys,
+        }
+    }
+
+    /// Number of output columns.
+    pub fn columns(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Bytes written before the first row.
+    pub fn begin(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self.format.format {
+            Format::Csv => {
+                if let Some(names) = &self.header {
+                    for (i, name) in names.iter().enumerate() {
+                        self.format.csv.write_value(&mut out, i, name);
+                    }
+                    self.format.csv.end_record(&mut out);
+                }
+            }
+            Format::JsonLines | Format::Parquet => {}
+            Format::Json => out.extend_from_slice(b"[\n"),
+        }
+        out
+    }
+
+    /// Bytes written after the last row.
+    pub fn end(&self) -> &'static [u8] {
+        match self.format.format {
+            Format::Json => b"\n]\n",
+            Format::Csv | Format::JsonLines | Format::Parquet => b"",
+        }
+    }
+
+    /// Separator between two rows, which the writer stage also has to insert
+    /// between chunks that were serialized independently.
+    pub fn row_separator(&self) -> &'static [u8] {
+        match self.format.format {
+            Format::Json => b",\n",
+            Format::Csv | Format::JsonLines | Format::Parquet => b"",
+        }
+    }
+
+    /// Starts a row; `first` is whether it is the first row of its chunk.
+    pub fn start_row(&self, out: &mut Vec<u8>, first: bool) {
+        match self.format.format {
+            Format::Csv | Format::Parquet => {}
+            Format::JsonLines => out.push(b'{'),
+            Format::Json => {
+                if !first {
+                    out.extend_from_slice(self.row_separator());
+                }
+                out.push(b'{');
+            }
+        }
+    }
+
+    /// Appends the value of output column `column`, which is the
+    /// `position`-th value written for this row.
+    pub fn write_value(&self, out: &mut Vec<u8>, column: usize, position: usize, value: &[u8]) {
+        match self.format.format {
+            Format::Csv | Format::Parquet => self.format.csv.write_value(out, position, value),
+            Format::JsonLines | Format::Json => {
+                if position > 0 {
+                    out.push(b',');
+                }
+                out.extend_from_slice(&self.keys[column]);
+                match self.format.infer_types {
+                    true => write_json_inferred(out, value),
+                    false => write_json_string(out, value),
+                }
+            }
+        }
+    }
+
+    /// Ends a row that started at `row_start` and has `written` values.
+    pub fn end_row(&self, out: &mut Vec<u8>, row_start: usize, written: usize) {
+        match self.format.format {
+            Format::Csv | Format::Parquet => {
+                // A lone empty value would otherwise be an empty line, which
+                // most readers skip
+                if out.len() == row_start && written == 1 {
+                    self.format.csv.write_empty(out);
+                }
+                self.format.csv.end_record(out);
+            }
+            Format::JsonLines => out.extend_from_slice(b"}\n"),
+            Format::Json => out.push(b'}'),
+        }
+    }
+}
+
+/// Makes repeated names distinct by suffixing `_2`, `_3`, ..., skipping
+/// suffixes that are already names of their own.
+fn unique_names(names: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut taken: HashSet<Vec<u8>> = names.iter().cloned().collect();
+    let mut first = HashSet::new();
+    names
+        .iter()
+        .map(|name| {
+            if first.insert(name) {
+                return name.clone();
+            }
+            let renamed = (2..)
+                .map(|n| [&name[..], format!("_{}", n).as_bytes()].concat())
+                .find(|renamed| !taken.contains(renamed))
+                .unwrap();
+            taken.insert(renamed.clone());
+            renamed
+        })
+        .collect()
+}
+
+/// Writes `value` as a JSON number, boolean or null if it looks like one,
+/// and as a string otherwise. Empty values become null.
+fn write_json_inferred(out: &mut Vec<u8>, value: &[u8]) {
+    match value {
+        b"" | b"null" => out.extend_from_slice(b"null"),
+        b"true" | b"false" => out.extend_from_slice(value),
+        _ if is_json_number(value) => out.extend_from_slice(value),
+        _ => write_json_string(out, value),
+    }
+}
+
+/// Whether `value` follows the JSON number grammar exactly.
+fn is_json_number(value: &[u8]) -> bool {
+    let digits = |s: &[u8]| s.iter().take_while(|b| b.is_ascii_digit()).count();
+
+    let mut rest = value.strip_prefix(b"-").unwrap_or(value);
+    let int = digits(rest);
+    if int == 0 || (int > 1 && rest[0] == b'0') {
+        return false;
+    }
+    rest = &rest[int..];
+
+    if let Some(frac) = rest.strip_prefix(b".") {
+        let n = digits(frac);
+        if n == 0 {
+            return false;
+        }
+        rest = &frac[n..];
+    }
+
+    if let Some(exp) = rest.strip_prefix(b"e").or_else(|| rest.strip_prefix(b"E")) {
+        let exp = exp.strip_prefix(b"+").or_else(|| exp.strip_prefix(b"-")).unwrap_or(exp);
+        let n = digits(exp);
+        if n == 0 {
+            return false;
+        }
+        rest = &exp[n..];
+    }
+
+    rest.is_empty()
+}
+
+/// Writes `value` as a quoted JSON string. Valid UTF-8 is kept as is, while
+/// bytes that are not valid UTF-8 are written as `\u00XX` escapes of their
+/// Latin-1 code point, so arbitrary input never produces invalid JSON.
+pub fn write_json_string(out: &mut Vec<u8>, value: &[u8]) {
+    out.push(b'"');
+    let mut rest = value;
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(s) => (s.as_bytes(), &[][..]),
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                let len = e.error_len().unwrap_or(after.len());
+                (valid, &after[..len])
+            }
+        };
+
+        for &b in valid {
+            match b {
+                b'"' => out.extend_from_slice(b"\\\""),
+                b'\\' => out.extend_from_slice(b"\\\\"),
+                b'\n' => out.extend_from_slice(b"\\n"),
+                b'\r' => out.extend_from_slice(b"\\r"),
+                b'\t' => out.extend_from_slice(b"\\t"),
+                0x00..=0x1f => write_unicode_escape(out, b),
+                _ => out.push(b),
+            }
+        }
+        for &b in invalid {
+            write_unicode_escape(out, b);
+        }
+
+        rest = &rest[valid.len() + invalid.len()..];
+    }
+    out.push(b'"');
+}
+
+fn write_unicode_escape(out: &mut Vec<u8>, b: u8) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    out.extend_from_slice(b"\\u00");
+    out.push(HEX[(b >> 4) as usize]);
+    out.push(HEX[(b & 0xf) as usize]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `values` as one CSV record.
+    fn csv_record(format: CsvFormat, values: &[&[u8]]) -> String {
+        let mut out = Vec::new();
+        for (i, value) in values.iter().enumerate() {
+            format.write_value(&mut out, i, value);
+        }
+        format.end_record(&mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    fn quoting(quote_style: QuoteStyle) -> CsvFormat {
+        CsvFormat {
+            quote_style,
+            ..CsvFormat::default()
+        }
+    }
+
+    #[test]
+    fn quotes_only_when_necessary() {
+        let format = quoting(QuoteStyle::Necessary);
+        assert_eq!(
+            csv_record(format, &[b"plain", b"a,b", b"say \"hi\"", b"two\nlines", b"cr\r"]),
+            "plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",\"cr\r\"\n"
+        );
+        assert_eq!(csv_record(format, &[b"", b" 7 "]), ", 7 \n");
+    }
+
+    #[test]
+    fn quotes_always_or_never() {
+        assert_eq!(csv_record(quoting(QuoteStyle::Always), &[b"1", b"", b"a\"b"]), "\"1\",\"\",\"a\"\"b\"\n");
+        assert_eq!(csv_record(quoting(QuoteStyle::Never), &[b"a,b", b"c\"d"]), "a,b,c\"d\n");
+    }
+
+    #[test]
+    fn quotes_everything_but_plain_numbers() {
+        let format = quoting(QuoteStyle::NonNumeric);
+        let numbers: [&[u8]; 8] = [b"7", b"-7", b"+3.5", b".5", b"5.", b"1e-3", b"2.5E+10", b"007"];
+        for number in numbers {
+            assert_eq!(csv_record(format, &[number]), format!("{}\n", String::from_utf8_lossy(number)));
+        }
+        let text: [&[u8]; 10] = [b" 7 ", b"7 ", b"", b"-", b".", b"1e", b"1.2.3", b"inf", b"NaN", b"0x10"];
+        for value in text {
+            assert_eq!(csv_record(format, &[value]), format!("\"{}\"\n", String::from_utf8_lossy(value)));
+        }
+    }
+
+    #[test]
+    fn uses_the_configured_characters() {
+        let format = CsvFormat {
+            delimiter: b';',
+            quote: b'\'',
+            quote_style: QuoteStyle::Necessary,
+            line_ending: LineEnding::CrLf,
+        };
+        assert_eq!(csv_record(format, &[b"a,b", b"c;d", b"it's", b"\"x\""]), "a,b;'c;d';'it''s';\"x\"\r\n");
+    }
+
+    #[test]
+    fn keeps_a_lone_empty_value_visible() {
+        for (quote_style, expected) in [(QuoteStyle::Necessary, "\"\"\n"), (QuoteStyle::Never, "\n")] {
+            let format = OutputFormat {
+                csv: quoting(quote_style),
+                ..OutputFormat::default()
+            };
+            let rows = RowWriter::new(format, vec![b"a".to_vec()], false);
+            let mut out = Vec::new();
+            rows.start_row(&mut out, true);
+            rows.write_value(&mut out, 0, 0, b"");
+            rows.end_row(&mut out, 0, 1);
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+        }
+    }
+
+    /// Writes `rows` with `writer`, including the begin and end bytes.
+    fn write_rows(writer: &RowWriter, rows: &[&[&[u8]]]) -> String {
+        let mut out = writer.begin();
+        for (i, row) in rows.iter().enumerate() {
+            writer.start_row(&mut out, i == 0);
+            let start = out.len();
+            for (column, value) in row.iter().enumerate() {
+                writer.write_value(&mut out, column, column, value);
+            }
+            writer.end_row(&mut out, start, row.len());
+        }
+        out.extend_from_slice(writer.end());
+        String::from_utf8(out).unwrap()
+    }
+
+    fn json_writer(format: Format, infer_types: bool, names: &[&str]) -> RowWriter {
+        let format = OutputFormat {
+            format,
+            infer_types,
+            ..OutputFormat::default()
+        };
+        RowWriter::new(format, names.iter().map(|name| name.as_bytes().to_vec()).collect(), true)
+    }
+
+    #[test]
+    fn writes_json_lines_and_arrays() {
+        let rows: [&[&[u8]]; 2] = [&[b"1", b"a"], &[b"2", b"b"]];
+        let lines = json_writer(Format::JsonLines, false, &["id", "name"]);
+        assert_eq!(write_rows(&lines, &rows), "{\"id\":\"1\",\"name\":\"a\"}\n{\"id\":\"2\",\"name\":\"b\"}\n");
+        let array = json_writer(Format::Json, false, &["id", "name"]);
+        assert_eq!(write_rows(&array, &rows), "[\n{\"id\":\"1\",\"name\":\"a\"},\n{\"id\":\"2\",\"name\":\"b\"}\n]\n");
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        let writer = json_writer(Format::JsonLines, false, &["a \"b\""]);
+        let out = write_rows(&writer, &[&[b"q\" b\\ n\n r\r t\t \x01 \x7f \xc3\xa9 \xe9 \xff\xfe"]]);
+        assert_eq!(
+            out,
+            "{\"a \\\"b\\\"\":\"q\\\" b\\\\ n\\n r\\r t\\t \\u0001 \x7f \u{e9} \\u00e9 \\u00ff\\u00fe\"}\n"
+        );
+    }
+
+    #[test]
+    fn infers_json_types() {
+        let names = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+        let values: [&[u8]; 10] = [b"-12", b"0.5e-3", b"true", b"false", b"null", b"", b"007", b"1.", b" 1", b"True"];
+        let typed = json_writer(Format::JsonLines, true, &names);
+        assert_eq!(
+            write_rows(&typed, &[&values]),
+            "{\"a\":-12,\"b\":0.5e-3,\"c\":true,\"d\":false,\"e\":null,\"f\":null,\
+             \"g\":\"007\",\"h\":\"1.\",\"i\":\" 1\",\"j\":\"True\"}\n"
+        );
+        let strings = json_writer(Format::JsonLines, false, &names[..2]);
+        assert_eq!(write_rows(&strings, &[&[b"", b"null"]]), "{\"a\":\"\",\"b\":\"null\"}\n");
+    }
+
+    #[test]
+    fn makes_repeated_json_keys_distinct() {
+        let writer = json_writer(Format::JsonLines, false, &["a", "a", "a_2", "b", "a"]);
+        assert_eq!(
+            write_rows(&writer, &[&[b"1", b"2", b"3", b"4", b"5"]]),
+            "{\"a\":\"1\",\"a_3\":\"2\",\"a_2\":\"3\",\"b\":\"4\",\"a_4\":\"5\"}\n"
+        );
+        // The CSV header keeps the names as they are
+        let csv = RowWriter::new(OutputFormat::default(), vec![b"a".to_vec(), b"a".to_vec()], true);
+        assert_eq!(csv.begin(), b"a,a\n");
+    }
+}